@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::error::Error;
+
+use crate::line_diff::*;
+use crate::merkle::*;
+use crate::vc::*;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BlameLine {
+    pub line: Box<[u8]>,
+    pub commit_hash: VcHash,
+    pub author: String,
+    pub timestamp: SystemTime,
+    // path and 0-based line number of the line in the commit it is attributed to
+    pub path: PathBuf,
+    pub line_num: usize
+}
+
+#[derive(Debug, Clone)]
+pub struct BlameOptions {
+    pub follow_renames: bool,
+    // commits whose changes are seen through, e.g. formatting-only commits
+    pub ignore_commits: HashSet<VcHash>
+}
+
+impl Default for BlameOptions {
+    fn default() -> Self {
+        Self {
+            follow_renames: true,
+            ignore_commits: HashSet::new()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BlameError {
+    PathNotFound(PathBuf),
+    NotAFile(PathBuf)
+}
+
+impl Display for BlameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlameError::PathNotFound(p) => write!(f, "path not found in commit: {}", p.display()),
+            BlameError::NotAFile(p) => write!(f, "path is not a file: {}", p.display())
+        }
+    }
+}
+
+impl Error for BlameError {}
+
+pub fn blame<P>(commit: &Commit, path: P) -> Result<Vec<BlameLine>, BlameError>
+where P: AsRef<Path>
+{
+    blame_with_options(commit, path, &BlameOptions::default())
+}
+
+// Attributes every line of `path` in `commit` to the commit which last changed it,
+// walking parent commits and carrying line origins backwards through line diffs.
+pub fn blame_with_options<P>(commit: &Commit, path: P, options: &BlameOptions) -> Result<Vec<BlameLine>, BlameError>
+where P: AsRef<Path>
+{
+    let path = path.as_ref();
+    let blob = match commit.tree.get_path(path) {
+        Some(FsObject::Blob(b)) => b,
+        Some(FsObject::Tree(_)) => return Err(BlameError::NotAFile(path.to_path_buf())),
+        None => return Err(BlameError::PathNotFound(path.to_path_buf()))
+    };
+    let final_lines = split_lines_inclusive(blob.get_data());
+    let mut result: Vec<Option<BlameLine>> = vec![None; final_lines.len()];
+
+    // (index into the final file, index into the file as of `current`)
+    let mut pending: Vec<(usize, usize)> = (0..final_lines.len()).map(|i| (i, i)).collect();
+    let mut current = commit;
+    let mut current_path = path.to_path_buf();
+    let mut current_blob = blob;
+
+    while !pending.is_empty() {
+        let parent_file = current.parent.and_then(|parent| {
+            find_parent_file(current, parent, &current_path, current_blob, options.follow_renames)
+                .map(|(p, b)| (parent, p, b))
+        });
+        let Some((parent, parent_path, parent_blob)) = parent_file else {
+            attribute(&mut result, &final_lines, current, &current_path, &pending);
+            break;
+        };
+
+        if parent_blob.get_hash() != current_blob.get_hash() {
+            let parent_lines = split_lines_inclusive(parent_blob.get_data());
+            let current_lines = split_lines_inclusive(current_blob.get_data());
+            let ops = diff_lines(&parent_lines, &current_lines);
+
+            let mut origins: HashMap<usize, usize> = ops.iter().filter_map(|op| match op {
+                DiffOp::Equal { old_index, new_index } => Some((*new_index, *old_index)),
+                _ => None
+            }).collect();
+            if options.ignore_commits.contains(&current.get_hash()) {
+                origins.extend(positional_origins(&ops));
+            }
+
+            let mut carried = Vec::new();
+            let mut blamed = Vec::new();
+            for (final_index, index) in pending {
+                match origins.get(&index) {
+                    Some(parent_index) => carried.push((final_index, *parent_index)),
+                    None => blamed.push((final_index, index))
+                }
+            }
+            attribute(&mut result, &final_lines, current, &current_path, &blamed);
+            pending = carried;
+        }
+
+        current = parent;
+        current_path = parent_path;
+        current_blob = parent_blob;
+    }
+
+    Ok(result.into_iter().map(|l| l.expect("every line is attributed")).collect())
+}
+
+fn attribute(result: &mut [Option<BlameLine>], final_lines: &[&[u8]], commit: &Commit, path: &Path, lines: &[(usize, usize)]) {
+    for (final_index, index) in lines {
+        result[*final_index] = Some(BlameLine {
+            line: final_lines[*final_index].into(),
+            commit_hash: commit.get_hash(),
+            author: commit.author.clone(),
+            timestamp: commit.timestamp,
+            path: path.to_path_buf(),
+            line_num: *index
+        });
+    }
+}
+
+// Pairs up the lines of each changed block by position, so that a rewritten line
+// is traced to the line it replaced rather than to the commit which rewrote it
+fn positional_origins(ops: &[DiffOp]) -> Vec<(usize, usize)> {
+    let mut origins = Vec::new();
+    let mut deleted = Vec::new();
+    let mut inserted = Vec::new();
+    for op in ops.iter().chain(std::iter::once(&DiffOp::Equal { old_index: 0, new_index: 0 })) {
+        match op {
+            DiffOp::Delete { old_index } => deleted.push(*old_index),
+            DiffOp::Insert { new_index } => inserted.push(*new_index),
+            DiffOp::Equal { .. } => {
+                origins.extend(inserted.drain(..).zip(deleted.drain(..)));
+            }
+        }
+    }
+    origins
+}
+
+// Locates the version of the file in the parent commit, following renames if asked to
+fn find_parent_file<'p>(current: &Commit, parent: &'p Commit, path: &Path, blob: &Blob, follow_renames: bool) -> Option<(PathBuf, &'p Blob)> {
+    match parent.tree.get_path(path) {
+        Some(FsObject::Blob(b)) => return Some((path.to_path_buf(), b)),
+        Some(FsObject::Tree(_)) => return None,
+        None => {}
+    }
+    if !follow_renames {
+        return None
+    }
+
+    // only files which no longer exist under the same path can be rename sources
+    let candidates: Vec<(PathBuf, &Blob)> = parent.tree.get_blobs().into_iter()
+        .filter(|(p, _)| current.tree.get_path(p).is_none())
+        .collect();
+
+    if let Some((p, b)) = candidates.iter().find(|(_, b)| b.get_hash() == blob.get_hash()) {
+        return Some((p.clone(), *b))
+    }
+
+    candidates.into_iter()
+        .map(|(p, b)| (line_similarity(b.get_data(), blob.get_data()), p, b))
+        .filter(|(similarity, _, _)| *similarity >= RENAME_SIMILARITY_THRESHOLD)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, p, b)| (p, b))
+}
+
+#[test]
+fn test_blame_single_commit() {
+    let commit = Commit::new(tree_of(&[("a.txt", "one\ntwo\n")]), None, "alice".to_string(), "init".to_string());
+    let lines = blame(&commit, "a.txt").unwrap();
+    assert_eq!(lines.len(), 2);
+    assert!(lines.iter().all(|l| l.commit_hash == commit.hash && l.author == "alice"));
+    assert_eq!(lines[1].line_num, 1);
+}
+
+#[test]
+fn test_blame_walks_history() {
+    let c1 = Commit::new(tree_of(&[("a.txt", "one\ntwo\nthree\n")]), None, "alice".to_string(), "init".to_string());
+    let c2 = Commit::new(tree_of(&[("a.txt", "one\nTWO\nthree\n")]), Some(&c1), "bob".to_string(), "shout".to_string());
+    let c3 = Commit::new(tree_of(&[("a.txt", "zero\none\nTWO\nthree\n")]), Some(&c2), "carol".to_string(), "prepend".to_string());
+
+    let lines = blame(&c3, "a.txt").unwrap();
+    let authors: Vec<&str> = lines.iter().map(|l| l.author.as_str()).collect();
+    assert_eq!(authors, vec!["carol", "alice", "bob", "alice"]);
+    assert_eq!(lines[1].line_num, 0);
+    assert_eq!(&*lines[2].line, b"TWO\n");
+}
+
+#[test]
+fn test_blame_follows_renames() {
+    let c1 = Commit::new(tree_of(&[("old.txt", "one\ntwo\n")]), None, "alice".to_string(), "init".to_string());
+    let c2 = Commit::new(tree_of(&[("new.txt", "one\ntwo\nthree\n")]), Some(&c1), "bob".to_string(), "rename".to_string());
+
+    let lines = blame(&c2, "new.txt").unwrap();
+    assert_eq!(lines[0].author, "alice");
+    assert_eq!(lines[0].path, PathBuf::from("old.txt"));
+    assert_eq!(lines[2].author, "bob");
+
+    let options = BlameOptions { follow_renames: false, ..Default::default() };
+    let lines = blame_with_options(&c2, "new.txt", &options).unwrap();
+    assert!(lines.iter().all(|l| l.author == "bob"));
+}
+
+#[test]
+fn test_blame_ignore_commits() {
+    let c1 = Commit::new(tree_of(&[("a.rs", "fn a(){}\nfn b(){}\n")]), None, "alice".to_string(), "init".to_string());
+    let c2 = Commit::new(tree_of(&[("a.rs", "fn a() {}\nfn b() {}\n")]), Some(&c1), "bob".to_string(), "fmt".to_string());
+
+    let lines = blame(&c2, "a.rs").unwrap();
+    assert!(lines.iter().all(|l| l.author == "bob"));
+
+    let options = BlameOptions { ignore_commits: HashSet::from([c2.hash]), ..Default::default() };
+    let lines = blame_with_options(&c2, "a.rs", &options).unwrap();
+    assert!(lines.iter().all(|l| l.author == "alice"));
+    assert_eq!(&*lines[0].line, b"fn a() {}\n");
+}
+
+#[test]
+fn test_blame_missing_path() {
+    let commit = Commit::new(tree_of(&[("a.txt", "one\n")]), None, "alice".to_string(), "init".to_string());
+    assert!(matches!(blame(&commit, "b.txt"), Err(BlameError::PathNotFound(_))));
+}
@@ -1,10 +1,113 @@
-use std::{fs, io};
-use std::io::{Read, Error};
-use digest::{Digest, DynDigest, OutputSizeUser, generic_array::GenericArray};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write, Error};
+use digest::{Digest, DynDigest, FixedOutput, FixedOutputReset, HashMarker, Output, OutputSizeUser, Reset, Update};
+use digest::generic_array::GenericArray;
+use digest::consts::{U8, U16, U32};
 use sha2::Sha256;
-use hex;
 
-pub type DigestByteArray<D: Digest> = GenericArray<u8, <D as OutputSizeUser>::OutputSize>;
+pub type DigestByteArray<D> = GenericArray<u8, <D as OutputSizeUser>::OutputSize>;
+
+// Size of the buffer files are read through while they are hashed
+pub const HASH_BUFFER_LEN: usize = 64 * 1024;
+
+// Names `select_hasher` accepts
+pub const HASHER_NAMES: [&str; 14] = [
+    "md5", "sha1", "sha224", "sha256", "sha384", "sha512",
+    "sha3-256", "sha3-512", "blake2b", "blake2s", "blake3", "blake3-parallel",
+    "xxh3", "xxh3-128"
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnknownDigestError(pub String);
+
+impl Display for UnknownDigestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unsupported digest: {} (expected one of {})", self.0, HASHER_NAMES.join(", "))
+    }
+}
+
+impl std::error::Error for UnknownDigestError {}
+
+// BLAKE3 which splits large inputs across threads, hashing them as separate subtrees of its hash tree.
+// Hashes to the same as `blake3::Hasher`; only worth it for inputs of a megabyte or more.
+#[derive(Debug, Clone, Default)]
+pub struct Blake3Parallel(blake3::Hasher);
+
+impl HashMarker for Blake3Parallel {}
+
+impl Update for Blake3Parallel {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update_rayon(data);
+    }
+}
+
+impl OutputSizeUser for Blake3Parallel {
+    type OutputSize = U32;
+}
+
+impl FixedOutput for Blake3Parallel {
+    fn finalize_into(self, out: &mut Output<Self>) {
+        FixedOutput::finalize_into(self.0, out);
+    }
+}
+
+impl FixedOutputReset for Blake3Parallel {
+    fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+        FixedOutputReset::finalize_into_reset(&mut self.0, out);
+    }
+}
+
+impl Reset for Blake3Parallel {
+    fn reset(&mut self) {
+        Reset::reset(&mut self.0);
+    }
+}
+
+// xxh3 behind the digest traits, so it can be used wherever a digest can. It is NOT cryptographic:
+// collisions are easy to make on purpose, so it is only fit for keys of caches of trusted data.
+// Outputs are big-endian.
+macro_rules! xxh3_digest {
+    ($name:ident, $size:ty, $digest:ident) => {
+        #[derive(Clone, Default)]
+        pub struct $name(xxhash_rust::xxh3::Xxh3);
+
+        impl HashMarker for $name {}
+
+        impl Update for $name {
+            fn update(&mut self, data: &[u8]) {
+                self.0.update(data);
+            }
+        }
+
+        impl OutputSizeUser for $name {
+            type OutputSize = $size;
+        }
+
+        impl FixedOutput for $name {
+            fn finalize_into(self, out: &mut Output<Self>) {
+                out.copy_from_slice(&self.0.$digest().to_be_bytes());
+            }
+        }
+
+        impl FixedOutputReset for $name {
+            fn finalize_into_reset(&mut self, out: &mut Output<Self>) {
+                out.copy_from_slice(&self.0.$digest().to_be_bytes());
+                self.0.reset();
+            }
+        }
+
+        impl Reset for $name {
+            fn reset(&mut self) {
+                self.0.reset();
+            }
+        }
+    };
+}
+
+xxh3_digest!(Xxh3, U8, digest);
+xxh3_digest!(Xxh3_128, U16, digest128);
 
 pub fn hash_sha256(data: &[u8]) -> [u8; 32] {
     let mut hasher = Sha256::new();
@@ -14,7 +117,7 @@ pub fn hash_sha256(data: &[u8]) -> [u8; 32] {
 
 pub fn combine_hashes<D: Digest>(hashes: &[DigestByteArray<D>]) -> DigestByteArray<D> 
 {
-    let concatted: Vec<u8> = hashes.iter().flat_map(|h| h.into_iter()).map(|b| b.clone()).collect();
+    let concatted: Vec<u8> = hashes.iter().flat_map(|h| h.into_iter()).copied().collect();
     hash::<D>(&concatted)
 }
 
@@ -24,31 +127,96 @@ pub fn hash<D: Digest>(data: &[u8]) -> DigestByteArray<D> {
     hasher.finalize()
 }
 
+// Feeds everything written to it into a digest, so that anything which writes can be hashed without
+// collecting what it writes first
+#[derive(Debug, Clone, Default)]
+pub struct HashWriter<D: Digest> {
+    hasher: D,
+    written: u64
+}
+
+impl<D: Digest> HashWriter<D> {
+    pub fn new() -> Self {
+        Self { hasher: D::new(), written: 0 }
+    }
+
+    pub fn get_written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn finalize(self) -> DigestByteArray<D> {
+        self.hasher.finalize()
+    }
+}
+
+impl<D: Digest> Write for HashWriter<D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Digest::update(&mut self.hasher, buf);
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// `HashWriter` for a digest picked at runtime. The hasher is left holding what was written.
+pub struct DynHashWriter<'a> {
+    hasher: &'a mut dyn DynDigest
+}
+
+impl<'a> DynHashWriter<'a> {
+    pub fn new(hasher: &'a mut dyn DynDigest) -> Self {
+        Self { hasher }
+    }
+}
+
+impl Write for DynHashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Hashes everything the reader yields, a buffer at a time
+pub fn hash_reader<D: Digest, R: Read>(reader: R) -> Result<DigestByteArray<D>, Error> {
+    let mut writer = HashWriter::<D>::new();
+    std::io::copy(&mut BufReader::with_capacity(HASH_BUFFER_LEN, reader), &mut writer)?;
+    Ok(writer.finalize())
+}
+
 pub fn hash_file<D: Digest>(path: &str) -> Result<DigestByteArray<D>, Error>
 {
-    let mut f = fs::File::open(path)?;
+    hash_reader::<D, _>(fs::File::open(path)?)
+}
 
-    let mut hasher = D::new();
-    let mut buf: Vec<u8> = Vec::new();
-    let _filesize = f.read_to_end(&mut buf)?;
-    hasher.update(&buf);
-    Ok(hasher.finalize())
+// Hashes a file through a memory map rather than reads. The file mustn't be changed while it is hashed.
+#[cfg(feature = "mmap")]
+pub fn hash_file_mmap<D: Digest>(path: &str) -> Result<DigestByteArray<D>, Error> {
+    let f = fs::File::open(path)?;
+    // empty files can't be mapped on every platform
+    if f.metadata()?.len() == 0 {
+        return Ok(hash::<D>(&[]))
+    }
+    // SAFETY: the map is only read while it is alive, and the caller promises not to change the file meanwhile
+    let map = unsafe { memmap2::Mmap::map(&f)? };
+    Ok(hash::<D>(&map))
 }
 
+// Hashes each line of a file, without its line ending
 pub fn hash_file_lines<D: Digest>(path: &str) -> Result<Vec<DigestByteArray<D>>, Error>
 where <D as OutputSizeUser>::OutputSize: generic_array::ArrayLength
 {
-    let mut f = fs::File::open(path)?;
-
-    let mut buf = String::new();
-    let _filesize = f.read_to_string(&mut buf)?;
-
-    Ok(
-        buf
+    let reader = BufReader::with_capacity(HASH_BUFFER_LEN, fs::File::open(path)?);
+    reader
         .lines()
-        .map(|s| hash::<D>(s.as_bytes()))
+        .map(|line| line.map(|s| hash::<D>(s.as_bytes())))
         .collect()
-    )
 }
 
 pub fn hash_dyn(hasher: &mut dyn DynDigest, data: &[u8]) -> Box<[u8]> {
@@ -56,37 +224,104 @@ pub fn hash_dyn(hasher: &mut dyn DynDigest, data: &[u8]) -> Box<[u8]> {
     hasher.finalize_reset()
 }
 
-pub fn hash_file_dyn(hasher: &mut dyn DynDigest, path: &str) -> Result<Box<[u8]>, Error> {
-    let mut f = fs::File::open(path)?;
-    let mut buf: Vec<u8> = Vec::new();
-    let _filesize = f.read_to_end(&mut buf)?;
-    hasher.update(&buf);
-    // let _n = io::copy(&mut f, &mut hasher)?;
+pub fn hash_reader_dyn<R: Read>(hasher: &mut dyn DynDigest, reader: R) -> Result<Box<[u8]>, Error> {
+    std::io::copy(&mut BufReader::with_capacity(HASH_BUFFER_LEN, reader), &mut DynHashWriter::new(hasher))?;
     Ok(hasher.finalize_reset())
 }
 
-pub fn hash_file_lines_dyn(hasher: &mut dyn DynDigest, path: &str) -> Result<Vec<Box<[u8]>>, Error> {
-    let mut f = fs::File::open(path)?;
-    let mut buf = String::new();
-    let _filesize = f.read_to_string(&mut buf)?;
+pub fn hash_file_dyn(hasher: &mut dyn DynDigest, path: &str) -> Result<Box<[u8]>, Error> {
+    hash_reader_dyn(hasher, fs::File::open(path)?)
+}
 
-    Ok(
-        buf
+pub fn hash_file_lines_dyn(hasher: &mut dyn DynDigest, path: &str) -> Result<Vec<Box<[u8]>>, Error> {
+    let reader = BufReader::with_capacity(HASH_BUFFER_LEN, fs::File::open(path)?);
+    reader
         .lines()
-        .map(|s| hash_dyn(hasher, s.as_bytes()))
-        .collect::<Vec<Box<[u8]>>>()
-    )
+        .map(|line| line.map(|s| hash_dyn(hasher, s.as_bytes())))
+        .collect()
 }
 
-pub fn select_hasher(s: &str) -> Box<dyn DynDigest> {
-    match s {
+pub fn select_hasher(s: &str) -> Result<Box<dyn DynDigest>, UnknownDigestError> {
+    Ok(match s {
         "md5" => Box::new(md5::Md5::default()),
         "sha1" => Box::new(sha1::Sha1::default()),
         "sha224" => Box::new(sha2::Sha224::default()),
         "sha256" => Box::new(sha2::Sha256::default()),
         "sha384" => Box::new(sha2::Sha384::default()),
         "sha512" => Box::new(sha2::Sha512::default()),
-        _ => unimplemented!("unsupported digest: {}", s),
+        "sha3-256" => Box::new(sha3::Sha3_256::default()),
+        "sha3-512" => Box::new(sha3::Sha3_512::default()),
+        "blake2b" => Box::new(blake2::Blake2b512::default()),
+        "blake2s" => Box::new(blake2::Blake2s256::default()),
+        "blake3" => Box::new(blake3::Hasher::default()),
+        "blake3-parallel" => Box::new(Blake3Parallel::default()),
+        "xxh3" => Box::new(Xxh3::default()),
+        "xxh3-128" => Box::new(Xxh3_128::default()),
+        _ => return Err(UnknownDigestError(s.to_string()))
+    })
+}
+
+// Digests of the same data by algorithm name
+pub type MultiHash = BTreeMap<String, Box<[u8]>>;
+
+// Feeds one pass over the data to several digests picked by name, for when different consumers
+// want different hashes of the same thing. Writing to it updates every digest.
+pub struct MultiHasher {
+    hashers: Vec<(String, Box<dyn DynDigest>)>
+}
+
+impl MultiHasher {
+    // Repeated names are hashed once
+    pub fn new<S>(names: &[S]) -> Result<Self, UnknownDigestError>
+    where S: AsRef<str>
+    {
+        let mut hashers: Vec<(String, Box<dyn DynDigest>)> = Vec::new();
+        for name in names.iter().map(|n| n.as_ref()) {
+            if !hashers.iter().any(|(n, _)| n == name) {
+                hashers.push((name.to_string(), select_hasher(name)?));
+            }
+        }
+        Ok(Self { hashers })
+    }
+
+    pub fn get_names(&self) -> Vec<&str> {
+        self.hashers.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(data);
+        }
+    }
+
+    // Returns the digests of everything fed in so far, leaving the hasher ready for new data
+    pub fn finalize_reset(&mut self) -> MultiHash {
+        self.hashers.iter_mut().map(|(name, hasher)| (name.clone(), hasher.finalize_reset())).collect()
+    }
+
+    pub fn hash(&mut self, data: &[u8]) -> MultiHash {
+        self.update(data);
+        self.finalize_reset()
+    }
+
+    pub fn hash_reader<R: Read>(&mut self, reader: R) -> Result<MultiHash, Error> {
+        std::io::copy(&mut BufReader::with_capacity(HASH_BUFFER_LEN, reader), self)?;
+        Ok(self.finalize_reset())
+    }
+
+    pub fn hash_file(&mut self, path: &str) -> Result<MultiHash, Error> {
+        self.hash_reader(fs::File::open(path)?)
+    }
+}
+
+impl Write for MultiHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
 
@@ -98,8 +333,12 @@ pub fn hex_string_to_hash_vec(s: &str) -> Result<Vec<u8>, hex::FromHexError> {
     hex::decode(s)
 }
 
+// Fails if the string isn't the hex of a hash of `D`'s length
 pub fn hex_string_to_hash<D: Digest>(s: &str) -> Result<DigestByteArray<D>, hex::FromHexError> {
     let bytes = hex_string_to_hash_vec(s)?;
+    if bytes.len() != <D as Digest>::output_size() {
+        return Err(hex::FromHexError::InvalidStringLength)
+    }
     let mut arr = DigestByteArray::<D>::default();
     arr.copy_from_slice(&bytes);
     Ok(arr)
@@ -124,6 +363,7 @@ fn test_hex_string_to_hash() {
     let hex_string = hash_to_hex_string(&hash);
     let hash2 = hex_string_to_hash::<Sha256>(&hex_string).unwrap();
     assert_eq!(hash, hash2);
+    assert!(hex_string_to_hash::<sha1::Sha1>(&hex_string).is_err());
 }
 
 #[test]
@@ -134,8 +374,8 @@ fn hash_example() {
 
 #[test]
 fn hash_dyn_example() {
-    let mut hasher1 = select_hasher("md5");
-    let mut hasher2 = select_hasher("sha512");
+    let mut hasher1 = select_hasher("md5").unwrap();
+    let mut hasher2 = select_hasher("sha512").unwrap();
 
     // the `&mut *hasher` is to DerefMut the value out of the Box
     // this is equivalent to `DerefMut::deref_mut(&mut hasher)`
@@ -145,9 +385,9 @@ fn hash_dyn_example() {
     let hash1_2 = hash_dyn(&mut *hasher1, b"bar");
     let hash2_1 = hash_dyn(&mut *hasher2, b"foo");
 
-    println!("{}", hash_to_hex_string(& *hash1_1));
-    println!("{}", hash_to_hex_string(& *hash1_2));
-    println!("{}", hash_to_hex_string(& *hash2_1));
+    println!("{}", hash_to_hex_string(&hash1_1));
+    println!("{}", hash_to_hex_string(&hash1_2));
+    println!("{}", hash_to_hex_string(&hash2_1));
 }
 
 #[test]
@@ -166,4 +406,113 @@ fn combine_hashes_example() {
         println!("{}", hash_to_hex_string(&h));
     }
     println!("combined: {}", hash_to_hex_string(&combined));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_select_hasher() {
+    for name in HASHER_NAMES {
+        let mut hasher = select_hasher(name).unwrap();
+        assert_eq!(hash_dyn(&mut *hasher, b"foo"), hash_dyn(&mut *hasher, b"foo"), "{}", name);
+    }
+    let err = select_hasher("sha4").err().unwrap();
+    assert_eq!(err, UnknownDigestError("sha4".to_string()));
+    assert!(err.to_string().starts_with("unsupported digest: sha4"));
+}
+
+#[test]
+fn test_generic_and_dyn_hashes_agree() {
+    fn check<D: Digest>(name: &str, expected_hex: &str) {
+        let generic = hash::<D>(b"abc");
+        assert_eq!(hash_to_hex_string(&generic), expected_hex, "{}", name);
+        assert_eq!(&hash_dyn(&mut *select_hasher(name).unwrap(), b"abc")[..], &generic[..], "{}", name);
+    }
+    check::<sha3::Sha3_256>("sha3-256", "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532");
+    check::<blake2::Blake2s256>("blake2s", "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982");
+    check::<blake3::Hasher>("blake3", "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+    check::<Blake3Parallel>("blake3-parallel", "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+    check::<Xxh3>("xxh3", &format!("{:016x}", xxhash_rust::xxh3::xxh3_64(b"abc")));
+    check::<Xxh3_128>("xxh3-128", &format!("{:032x}", xxhash_rust::xxh3::xxh3_128(b"abc")));
+}
+
+#[test]
+fn test_blake3_parallel_matches_serial() {
+    // large enough to be split across threads
+    let data: Vec<u8> = (0..4 << 20).map(|i: u32| (i % 251) as u8).collect();
+    assert_eq!(hash::<Blake3Parallel>(&data), hash::<blake3::Hasher>(&data));
+    assert_eq!(&hash::<Blake3Parallel>(&data)[..], blake3::hash(&data).as_bytes());
+}
+
+#[cfg(test)]
+fn large_file() -> (tempfile::NamedTempFile, Vec<u8>) {
+    // spans several buffers and ends part way through one
+    let data: Vec<u8> = (0..3 * HASH_BUFFER_LEN + 17).map(|i| (i % 251) as u8).collect();
+    let mut f = tempfile::NamedTempFile::new().unwrap();
+    f.write_all(&data).unwrap();
+    (f, data)
+}
+
+#[test]
+fn test_hash_file_streams() {
+    let (f, data) = large_file();
+    let path = f.path().to_str().unwrap();
+    assert_eq!(hash_file::<Sha256>(path).unwrap(), hash::<Sha256>(&data));
+    let mut hasher = select_hasher("blake3").unwrap();
+    assert_eq!(&hash_file_dyn(&mut *hasher, path).unwrap()[..], blake3::hash(&data).as_bytes());
+    // the hasher is reset for the next file
+    assert_eq!(&hash_file_dyn(&mut *hasher, path).unwrap()[..], blake3::hash(&data).as_bytes());
+}
+
+#[test]
+fn test_hash_writer() {
+    let (_, data) = large_file();
+    let mut writer = HashWriter::<Sha256>::new();
+    for chunk in data.chunks(1000) {
+        writer.write_all(chunk).unwrap();
+    }
+    assert_eq!(writer.get_written(), data.len() as u64);
+    assert_eq!(writer.finalize(), hash::<Sha256>(&data));
+
+    let mut hasher = select_hasher("sha256").unwrap();
+    DynHashWriter::new(&mut *hasher).write_all(&data).unwrap();
+    assert_eq!(&hasher.finalize_reset()[..], &hash::<Sha256>(&data)[..]);
+}
+
+#[test]
+fn test_hash_file_lines_streams() {
+    let mut f = tempfile::NamedTempFile::new().unwrap();
+    f.write_all(b"first\r\nsecond\n\nlast").unwrap();
+    let path = f.path().to_str().unwrap();
+    let expected: Vec<_> = ["first", "second", "", "last"].iter().map(|l| hash::<Sha256>(l.as_bytes())).collect();
+    assert_eq!(hash_file_lines::<Sha256>(path).unwrap(), expected);
+    let hashes = hash_file_lines_dyn(&mut *select_hasher("sha256").unwrap(), path).unwrap();
+    assert_eq!(hashes.iter().map(|h| &h[..]).collect::<Vec<_>>(), expected.iter().map(|h| &h[..]).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_multi_hasher() {
+    let mut hasher = MultiHasher::new(&["md5", "sha1", "sha256", "sha1"]).unwrap();
+    assert_eq!(hasher.get_names(), vec!["md5", "sha1", "sha256"]);
+    let check = |hashes: &MultiHash, data: &[u8]| {
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(&hashes["md5"][..], &hash::<md5::Md5>(data)[..]);
+        assert_eq!(&hashes["sha1"][..], &hash::<sha1::Sha1>(data)[..]);
+        assert_eq!(&hashes["sha256"][..], &hash::<Sha256>(data)[..]);
+    };
+    check(&hasher.hash(b"hello"), b"hello");
+
+    let (f, data) = large_file();
+    check(&hasher.hash_file(f.path().to_str().unwrap()).unwrap(), &data);
+    // nothing is carried over from the file
+    check(&hasher.hash(b""), b"");
+
+    assert_eq!(MultiHasher::new(&["sha256", "crc32"]).err(), Some(UnknownDigestError("crc32".to_string())));
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_hash_file_mmap() {
+    let (f, data) = large_file();
+    assert_eq!(hash_file_mmap::<Sha256>(f.path().to_str().unwrap()).unwrap(), hash::<Sha256>(&data));
+    let empty = tempfile::NamedTempFile::new().unwrap();
+    assert_eq!(hash_file_mmap::<Sha256>(empty.path().to_str().unwrap()).unwrap(), hash::<Sha256>(b""));
+}
@@ -0,0 +1,415 @@
+use std::fmt::Display;
+use std::io::{self, BufRead, Write};
+
+use crate::line_diff::*;
+use crate::vc::*;
+
+pub const DEFAULT_CONTEXT_LINES: usize = 3;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum HunkLine {
+    Context(Box<[u8]>),
+    Removed(Box<[u8]>),
+    Added(Box<[u8]>)
+}
+
+impl HunkLine {
+    pub fn get_data(&self) -> &[u8] {
+        match self {
+            HunkLine::Context(l) | HunkLine::Removed(l) | HunkLine::Added(l) => l
+        }
+    }
+
+    pub fn is_change(&self) -> bool {
+        !matches!(self, HunkLine::Context(_))
+    }
+
+    fn in_old(&self) -> bool {
+        !matches!(self, HunkLine::Added(_))
+    }
+
+    fn in_new(&self) -> bool {
+        !matches!(self, HunkLine::Removed(_))
+    }
+}
+
+// A contiguous group of changes together with its surrounding context lines.
+// `old_start` and `new_start` are 0-based line indices.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub new_start: usize,
+    pub lines: Vec<HunkLine>
+}
+
+impl Hunk {
+    pub fn old_len(&self) -> usize {
+        self.lines.iter().filter(|l| l.in_old()).count()
+    }
+
+    pub fn new_len(&self) -> usize {
+        self.lines.iter().filter(|l| l.in_new()).count()
+    }
+
+    // Ranges of `lines` holding consecutive changed lines
+    fn change_runs(&self) -> Vec<(usize, usize)> {
+        let mut runs = Vec::new();
+        let mut i = 0;
+        while i < self.lines.len() {
+            if self.lines[i].is_change() {
+                let start = i;
+                while i < self.lines.len() && self.lines[i].is_change() {
+                    i += 1;
+                }
+                runs.push((start, i));
+            } else {
+                i += 1;
+            }
+        }
+        runs
+    }
+
+    pub fn can_split(&self) -> bool {
+        self.change_runs().len() > 1
+    }
+
+    // Splits the hunk at every run of context lines between two changes.
+    // Each resulting hunk keeps the whole context run on either side of its changes,
+    // so neighbouring split hunks share those context lines.
+    pub fn split(&self) -> Vec<Hunk> {
+        let runs = self.change_runs();
+        if runs.len() < 2 {
+            return vec![self.clone()];
+        }
+
+        let mut hunks = Vec::new();
+        for i in 0..runs.len() {
+            let from = if i == 0 { 0 } else { runs[i - 1].1 };
+            let to = if i + 1 == runs.len() { self.lines.len() } else { runs[i + 1].0 };
+            let skipped = &self.lines[..from];
+            let old_start = self.old_start + skipped.iter().filter(|l| l.in_old()).count();
+            let new_start = self.new_start + skipped.iter().filter(|l| l.in_new()).count();
+            hunks.push(Hunk {
+                old_start,
+                new_start,
+                lines: self.lines[from..to].to_vec()
+            });
+        }
+        hunks
+    }
+}
+
+impl Display for Hunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // unified diff headers are 1-based, and an empty range points at the line before it
+        let old_len = self.old_len();
+        let new_len = self.new_len();
+        let old_start = if old_len == 0 { self.old_start } else { self.old_start + 1 };
+        let new_start = if new_len == 0 { self.new_start } else { self.new_start + 1 };
+        writeln!(f, "@@ -{},{} +{},{} @@", old_start, old_len, new_start, new_len)?;
+        for line in &self.lines {
+            let prefix = match line {
+                HunkLine::Context(_) => ' ',
+                HunkLine::Removed(_) => '-',
+                HunkLine::Added(_) => '+'
+            };
+            let text = String::from_utf8_lossy(line.get_data());
+            write!(f, "{}{}", prefix, text)?;
+            if !text.ends_with('\n') {
+                writeln!(f, "\n\\ No newline at end of file")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The hunks that turn an old version of a file into a new one
+#[derive(Debug, Clone)]
+pub struct FileDiff {
+    pub old_lines: Vec<Box<[u8]>>,
+    pub hunks: Vec<Hunk>
+}
+
+impl FileDiff {
+    pub fn new(old: &Blob, new: &Blob) -> Self {
+        Self::with_context(old, new, DEFAULT_CONTEXT_LINES)
+    }
+
+    pub fn with_context(old: &Blob, new: &Blob, context: usize) -> Self {
+        let old_lines = split_lines_inclusive(old.get_data());
+        let new_lines = split_lines_inclusive(new.get_data());
+        let ops = diff_lines(&old_lines, &new_lines);
+
+        // positions in the old and new files before each op
+        let mut positions = Vec::with_capacity(ops.len() + 1);
+        let (mut old_pos, mut new_pos) = (0, 0);
+        for op in &ops {
+            positions.push((old_pos, new_pos));
+            match op {
+                DiffOp::Equal { .. } => { old_pos += 1; new_pos += 1; },
+                DiffOp::Delete { .. } => old_pos += 1,
+                DiffOp::Insert { .. } => new_pos += 1
+            }
+        }
+
+        let changes: Vec<usize> = ops.iter().enumerate()
+            .filter(|(_, op)| !matches!(op, DiffOp::Equal { .. }))
+            .map(|(i, _)| i)
+            .collect();
+
+        // group changes which are close enough for their context to touch
+        let mut groups: Vec<(usize, usize)> = Vec::new();
+        for i in changes {
+            match groups.last_mut() {
+                Some((_, last)) if i - *last <= 2 * context + 1 => *last = i,
+                _ => groups.push((i, i))
+            }
+        }
+
+        let hunks = groups.into_iter().map(|(first, last)| {
+            let from = first.saturating_sub(context);
+            let to = (last + context + 1).min(ops.len());
+            let lines = ops[from..to].iter().map(|op| match op {
+                DiffOp::Equal { old_index, .. } => HunkLine::Context(old_lines[*old_index].into()),
+                DiffOp::Delete { old_index } => HunkLine::Removed(old_lines[*old_index].into()),
+                DiffOp::Insert { new_index } => HunkLine::Added(new_lines[*new_index].into())
+            }).collect();
+            let (old_start, new_start) = positions[from];
+            Hunk { old_start, new_start, lines }
+        }).collect();
+
+        Self {
+            old_lines: old_lines.into_iter().map(|l| l.into()).collect(),
+            hunks
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hunks.is_empty()
+    }
+
+    // Replaces the hunk at `index` with its split parts, returning whether it could be split
+    pub fn split_hunk(&mut self, index: usize) -> bool {
+        let hunk = &self.hunks[index];
+        if !hunk.can_split() {
+            return false
+        }
+        let parts = hunk.split();
+        self.hunks.splice(index..index + 1, parts);
+        true
+    }
+
+    // Builds the old file with only the accepted hunks applied
+    pub fn apply(&self, accepted: &[bool]) -> Blob {
+        assert_eq!(accepted.len(), self.hunks.len(), "one selection per hunk is required");
+
+        let mut data: Vec<u8> = Vec::new();
+        // index of the next old line which has not been emitted yet
+        let mut cursor = 0;
+        for (hunk, accept) in self.hunks.iter().zip(accepted) {
+            while cursor < hunk.old_start {
+                data.extend_from_slice(&self.old_lines[cursor]);
+                cursor += 1;
+            }
+            let mut old_index = hunk.old_start;
+            for line in &hunk.lines {
+                match line {
+                    HunkLine::Context(l) => {
+                        // split hunks share context, which may have been emitted already
+                        if old_index >= cursor {
+                            data.extend_from_slice(l);
+                            cursor = old_index + 1;
+                        }
+                        old_index += 1;
+                    },
+                    HunkLine::Removed(l) => {
+                        if !accept {
+                            data.extend_from_slice(l);
+                        }
+                        cursor = old_index + 1;
+                        old_index += 1;
+                    },
+                    HunkLine::Added(l) => {
+                        if *accept {
+                            data.extend_from_slice(l);
+                        }
+                    }
+                }
+            }
+        }
+        for line in &self.old_lines[cursor.min(self.old_lines.len())..] {
+            data.extend_from_slice(line);
+        }
+        Blob::new_owned(data.into_boxed_slice())
+    }
+
+    // The blob to stage: the old file plus the selected hunks
+    pub fn stage(&self, selected: &[bool]) -> Blob {
+        self.apply(selected)
+    }
+
+    // The blob to keep in the working copy: the new file without the selected hunks
+    pub fn discard(&self, selected: &[bool]) -> Blob {
+        let kept = selected.iter().map(|s| !s).collect::<Vec<bool>>();
+        self.apply(&kept)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PatchMode {
+    Stage,
+    Discard
+}
+
+impl PatchMode {
+    fn verb(&self) -> &'static str {
+        match self {
+            PatchMode::Stage => "Stage",
+            PatchMode::Discard => "Discard"
+        }
+    }
+}
+
+const PROMPT_HELP: &str = "\
+y - select this hunk
+n - do not select this hunk
+a - select this hunk and all later hunks
+d - do not select this hunk or any later hunks
+s - split the current hunk into smaller hunks
+q - quit; do not select this hunk or any later hunks
+? - print help
+";
+
+// Line-based interactive loop asking about every hunk of `diff`, as used by `add -p`.
+// Hunks may be split while prompting, so the returned selection lines up with `diff.hunks`
+// after the loop has finished. End of input is treated like `q`.
+pub fn prompt_hunks<R, W>(diff: &mut FileDiff, mode: PatchMode, mut input: R, mut output: W) -> io::Result<Vec<bool>>
+where R: BufRead, W: Write
+{
+    let mut selected: Vec<bool> = Vec::new();
+    let mut index = 0;
+    while index < diff.hunks.len() {
+        let hunk = &diff.hunks[index];
+        let options = if hunk.can_split() { "y,n,a,d,s,q,?" } else { "y,n,a,d,q,?" };
+        write!(output, "{}", hunk)?;
+        write!(output, "({}/{}) {} this hunk [{}]? ", index + 1, diff.hunks.len(), mode.verb(), options)?;
+        output.flush()?;
+
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 {
+            break;
+        }
+        match answer.trim() {
+            "y" => { selected.push(true); index += 1; },
+            "n" => { selected.push(false); index += 1; },
+            "a" => {
+                selected.resize(diff.hunks.len(), true);
+                index = diff.hunks.len();
+            },
+            "d" | "q" => break,
+            "s" => {
+                let before = diff.hunks.len();
+                if diff.split_hunk(index) {
+                    writeln!(output, "Split into {} hunks.", diff.hunks.len() - before + 1)?;
+                } else {
+                    writeln!(output, "Sorry, cannot split this hunk")?;
+                }
+            },
+            _ => write!(output, "{}", PROMPT_HELP)?
+        }
+    }
+    selected.resize(diff.hunks.len(), false);
+    Ok(selected)
+}
+
+#[cfg(test)]
+fn numbered_lines(nums: &[u32]) -> Blob {
+    let s: String = nums.iter().map(|n| format!("line {}\n", n)).collect();
+    Blob::new(s.as_bytes())
+}
+
+#[test]
+fn test_file_diff_hunks() {
+    let old = numbered_lines(&(1..=20).collect::<Vec<u32>>());
+    let mut new_nums: Vec<u32> = (1..=20).collect();
+    new_nums[1] = 100;
+    new_nums[17] = 200;
+    let new = numbered_lines(&new_nums);
+
+    let diff = FileDiff::new(&old, &new);
+    assert_eq!(diff.hunks.len(), 2);
+    assert_eq!(diff.hunks[0].old_start, 0);
+    assert_eq!(diff.hunks[0].old_len(), 5);
+    assert_eq!(diff.hunks[1].old_start, 14);
+    assert!(diff.hunks[0].to_string().starts_with("@@ -1,5 +1,5 @@\n"));
+}
+
+#[test]
+fn test_stage_and_discard() {
+    let old = numbered_lines(&(1..=20).collect::<Vec<u32>>());
+    let mut new_nums: Vec<u32> = (1..=20).collect();
+    new_nums[1] = 100;
+    new_nums[17] = 200;
+    let new = numbered_lines(&new_nums);
+    let diff = FileDiff::new(&old, &new);
+
+    assert_eq!(diff.stage(&[false, false]).get_data(), old.get_data());
+    assert_eq!(diff.stage(&[true, true]).get_data(), new.get_data());
+
+    let mut staged_nums: Vec<u32> = (1..=20).collect();
+    staged_nums[1] = 100;
+    assert_eq!(diff.stage(&[true, false]).get_data(), numbered_lines(&staged_nums).get_data());
+
+    let mut kept_nums: Vec<u32> = (1..=20).collect();
+    kept_nums[17] = 200;
+    assert_eq!(diff.discard(&[true, false]).get_data(), numbered_lines(&kept_nums).get_data());
+}
+
+#[test]
+fn test_split_hunk_and_apply() {
+    let old = numbered_lines(&[1, 2, 3, 4, 5, 6]);
+    let new = numbered_lines(&[1, 20, 3, 4, 50, 6]);
+    let mut diff = FileDiff::new(&old, &new);
+    assert_eq!(diff.hunks.len(), 1);
+    assert!(diff.split_hunk(0));
+    assert_eq!(diff.hunks.len(), 2);
+    assert!(!diff.split_hunk(0));
+
+    assert_eq!(diff.apply(&[true, true]).get_data(), new.get_data());
+    assert_eq!(diff.apply(&[false, false]).get_data(), old.get_data());
+    assert_eq!(diff.apply(&[false, true]).get_data(), numbered_lines(&[1, 2, 3, 4, 50, 6]).get_data());
+}
+
+#[test]
+fn test_apply_without_trailing_newline() {
+    let old = Blob::new(b"a\nb");
+    let new = Blob::new(b"a\nc");
+    let diff = FileDiff::new(&old, &new);
+    assert_eq!(diff.apply(&[true]).get_data(), b"a\nc");
+    assert!(diff.hunks[0].to_string().contains("\\ No newline at end of file"));
+}
+
+#[test]
+fn test_prompt_hunks() {
+    let old = numbered_lines(&[1, 2, 3, 4, 5, 6]);
+    let new = numbered_lines(&[1, 20, 3, 4, 50, 6]);
+    let mut diff = FileDiff::new(&old, &new);
+
+    let input = b"?\ns\nn\ny\n";
+    let mut output: Vec<u8> = Vec::new();
+    let selected = prompt_hunks(&mut diff, PatchMode::Stage, &input[..], &mut output).unwrap();
+    assert_eq!(selected, vec![false, true]);
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("Stage this hunk [y,n,a,d,s,q,?]? "));
+    assert!(output.contains("Split into 2 hunks."));
+    assert_eq!(diff.stage(&selected).get_data(), numbered_lines(&[1, 2, 3, 4, 50, 6]).get_data());
+}
+
+#[test]
+fn test_prompt_hunks_end_of_input() {
+    let old = numbered_lines(&[1, 2, 3]);
+    let new = numbered_lines(&[1, 5, 3]);
+    let mut diff = FileDiff::new(&old, &new);
+    let selected = prompt_hunks(&mut diff, PatchMode::Discard, &b""[..], io::sink()).unwrap();
+    assert_eq!(selected, vec![false]);
+}
@@ -0,0 +1,23 @@
+pub mod hashing;
+pub mod merkle;
+pub mod diff;
+pub mod vc;
+pub mod vc_serialize;
+pub mod vc_object;
+pub mod vc_binary;
+pub mod vc_config;
+pub mod vc_pack;
+pub mod vc_store;
+pub mod vc_db;
+pub mod vc_refs;
+pub mod vc_gc;
+pub mod vc_fsck;
+pub mod vc_lock;
+pub mod util;
+pub mod line_diff;
+pub mod hunk;
+pub mod blame;
+pub mod merge;
+pub mod merge_driver;
+pub mod rerere;
+pub mod manifest;
@@ -0,0 +1,223 @@
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DiffOp {
+    Equal { old_index: usize, new_index: usize },
+    Delete { old_index: usize },
+    Insert { new_index: usize }
+}
+
+// Splits data into lines, keeping the line terminators so that the lines can be joined back losslessly
+pub fn split_lines_inclusive(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|b| *b == b'\n').collect()
+}
+
+// Computes a shortest edit script from `old` to `new` using the linear-space Myers algorithm.
+// Common prefixes and suffixes are trimmed before searching.
+pub fn diff_lines<T: PartialEq>(old: &[T], new: &[T]) -> Vec<DiffOp> {
+    let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut ops = Vec::with_capacity(old.len().max(new.len()));
+    for i in 0..prefix {
+        ops.push(DiffOp::Equal { old_index: i, new_index: i });
+    }
+    myers(&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix], prefix, prefix, &mut ops);
+    for i in 0..suffix {
+        ops.push(DiffOp::Equal { old_index: old.len() - suffix + i, new_index: new.len() - suffix + i });
+    }
+    ops
+}
+
+// Linear-space variant: splits the box at a snake in the middle of an optimal path and recurses on both halves
+fn myers<T: PartialEq>(old: &[T], new: &[T], old_offset: usize, new_offset: usize, ops: &mut Vec<DiffOp>) {
+    if old.is_empty() {
+        ops.extend((0..new.len()).map(|j| DiffOp::Insert { new_index: new_offset + j }));
+        return
+    }
+    if new.is_empty() {
+        ops.extend((0..old.len()).map(|i| DiffOp::Delete { old_index: old_offset + i }));
+        return
+    }
+
+    let snake = middle_snake(old, new);
+    let (mut x, mut y) = snake.start;
+    let (end_x, end_y) = snake.end;
+    myers(&old[..x], &new[..y], old_offset, new_offset, ops);
+
+    // a forward snake starts with its edit, a backward one ends with it
+    if snake.forward && end_x - x != end_y - y {
+        if end_x - x > end_y - y {
+            ops.push(DiffOp::Delete { old_index: old_offset + x });
+            x += 1;
+        } else {
+            ops.push(DiffOp::Insert { new_index: new_offset + y });
+            y += 1;
+        }
+    }
+    while x < end_x && y < end_y {
+        ops.push(DiffOp::Equal { old_index: old_offset + x, new_index: new_offset + y });
+        x += 1;
+        y += 1;
+    }
+    if x < end_x {
+        ops.push(DiffOp::Delete { old_index: old_offset + x });
+    } else if y < end_y {
+        ops.push(DiffOp::Insert { new_index: new_offset + y });
+    }
+
+    myers(&old[end_x..], &new[end_y..], old_offset + end_x, new_offset + end_y, ops);
+}
+
+struct Snake {
+    start: (usize, usize),
+    end: (usize, usize),
+    forward: bool
+}
+
+// Searches from both corners at once until the paths overlap, keeping only the furthest
+// reaching x (forwards) and y (backwards) per diagonal
+fn middle_snake<T: PartialEq>(old: &[T], new: &[T]) -> Snake {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = (n + m + 1) / 2;
+    let delta = n - m;
+    let offset = max + 1;
+    let mut forward = vec![0isize; 2 * offset as usize + 1];
+    let mut backward = vec![0isize; 2 * offset as usize + 1];
+    backward[(offset + 1) as usize] = m;
+
+    for d in 0..=max {
+        for k in (-d..=d).rev().step_by(2) {
+            let idx = (k + offset) as usize;
+            let (prev_x, mut x) = if k == -d || (k != d && forward[idx - 1] < forward[idx + 1]) {
+                (forward[idx + 1], forward[idx + 1])
+            } else {
+                (forward[idx - 1], forward[idx - 1] + 1)
+            };
+            let mut y = x - k;
+            let prev_y = if d == 0 || x != prev_x { y } else { y - 1 };
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[idx] = x;
+            let c = k - delta;
+            if delta % 2 != 0 && -d < c && c < d && y >= backward[(c + offset) as usize] {
+                return Snake { start: (prev_x as usize, prev_y as usize), end: (x as usize, y as usize), forward: true }
+            }
+        }
+        for c in (-d..=d).rev().step_by(2) {
+            let idx = (c + offset) as usize;
+            let (prev_y, mut y) = if c == -d || (c != d && backward[idx - 1] > backward[idx + 1]) {
+                (backward[idx + 1], backward[idx + 1])
+            } else {
+                (backward[idx - 1], backward[idx - 1] - 1)
+            };
+            let k = c + delta;
+            let mut x = y + k;
+            let prev_x = if d == 0 || y != prev_y { x } else { x + 1 };
+            while x > 0 && y > 0 && old[x as usize - 1] == new[y as usize - 1] {
+                x -= 1;
+                y -= 1;
+            }
+            backward[idx] = y;
+            if delta % 2 == 0 && -d <= k && k <= d && x <= forward[(k + offset) as usize] {
+                return Snake { start: (x as usize, y as usize), end: (prev_x as usize, prev_y as usize), forward: false }
+            }
+        }
+    }
+    unreachable!("the forward and backward searches always meet")
+}
+
+// Minimum share of common lines for a deleted file to count as the source of a renamed one
+pub const RENAME_SIMILARITY_THRESHOLD: f64 = 0.5;
+
+// Share of lines two files have in common, from 0.0 (nothing) to 1.0 (identical)
+pub fn line_similarity(a: &[u8], b: &[u8]) -> f64 {
+    let a_lines = split_lines_inclusive(a);
+    let b_lines = split_lines_inclusive(b);
+    if a_lines.is_empty() && b_lines.is_empty() {
+        return 1.0
+    }
+    let common = diff_lines(&a_lines, &b_lines).iter()
+        .filter(|op| matches!(op, DiffOp::Equal { .. }))
+        .count();
+    2.0 * common as f64 / (a_lines.len() + b_lines.len()) as f64
+}
+
+#[cfg(test)]
+fn apply_ops<T: Clone>(old: &[T], new: &[T], ops: &[DiffOp]) -> Vec<T> {
+    ops.iter().filter_map(|op| match op {
+        DiffOp::Equal { old_index, .. } => Some(old[*old_index].clone()),
+        DiffOp::Delete { .. } => None,
+        DiffOp::Insert { new_index } => Some(new[*new_index].clone())
+    }).collect()
+}
+
+#[test]
+fn test_diff_lines_identical() {
+    let a = vec!["a", "b", "c"];
+    let ops = diff_lines(&a, &a);
+    assert!(ops.iter().all(|op| matches!(op, DiffOp::Equal { .. })));
+    assert_eq!(ops.len(), 3);
+}
+
+#[test]
+fn test_diff_lines_reconstructs_new() {
+    let a = vec!["a", "b", "c", "a", "b", "b", "a"];
+    let b = vec!["c", "b", "a", "b", "a", "c"];
+    let ops = diff_lines(&a, &b);
+    assert_eq!(apply_ops(&a, &b, &ops), b);
+    // the Myers example has an edit distance of 5
+    let edits = ops.iter().filter(|op| !matches!(op, DiffOp::Equal { .. })).count();
+    assert_eq!(edits, 5);
+}
+
+#[test]
+fn test_diff_lines_empty_sides() {
+    let a: Vec<&str> = vec![];
+    let b = vec!["x", "y"];
+    assert_eq!(diff_lines(&a, &b), vec![DiffOp::Insert { new_index: 0 }, DiffOp::Insert { new_index: 1 }]);
+    assert_eq!(diff_lines(&b, &a), vec![DiffOp::Delete { old_index: 0 }, DiffOp::Delete { old_index: 1 }]);
+}
+
+#[test]
+fn test_diff_lines_is_shortest() {
+    // compare edit counts against a quadratic LCS table on pseudo-random small inputs
+    let mut seed = 0x2545f491u32;
+    let mut next = move |bound: u32| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed % bound
+    };
+    for _ in 0..2000 {
+        let a: Vec<u32> = (0..next(12)).map(|_| next(3)).collect();
+        let b: Vec<u32> = (0..next(12)).map(|_| next(3)).collect();
+        let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+            }
+        }
+        let ops = diff_lines(&a, &b);
+        assert_eq!(apply_ops(&a, &b, &ops), b);
+        let edits = ops.iter().filter(|op| !matches!(op, DiffOp::Equal { .. })).count();
+        assert_eq!(edits, a.len() + b.len() - 2 * lcs[0][0], "{:?} -> {:?}", a, b);
+    }
+}
+
+#[test]
+fn test_line_similarity() {
+    assert_eq!(line_similarity(b"a\nb\n", b"a\nb\n"), 1.0);
+    assert_eq!(line_similarity(b"a\nb\n", b"c\nd\n"), 0.0);
+    assert_eq!(line_similarity(b"a\nb\n", b"a\nc\n"), 0.5);
+}
+
+#[test]
+fn test_split_lines_inclusive() {
+    let lines = split_lines_inclusive(b"one\ntwo\nthree");
+    assert_eq!(lines, vec![&b"one\n"[..], &b"two\n"[..], &b"three"[..]]);
+}
@@ -1,25 +1,20 @@
-use std::io;
+#[cfg(test)]
 use sha2::{Digest, Sha256};
-
-mod hashing;
-mod merkle;
-mod diff;
-mod vc;
-mod vc_serialize;
-mod util;
-
-use hashing::*;
+#[cfg(test)]
+use diff_rs::hashing::*;
+use std::io::Write;
+use std::path::Path;
 
 #[test]
 fn test_hash_file() {
-    let path = "/home/jacekline/dev/projects/dif/test.txt";
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../test.txt");
     let hash = hash_file::<Sha256>(path).expect("Failed to hash file");
     println!("{}", hash_to_hex_string(&hash));
 }
 
 #[test]
 fn test_hash_file_lines() {
-    let path = "/home/jacekline/dev/projects/dif/test.txt";
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../test.txt");
     let line_hashes = hash_file_lines::<Sha256>(path).expect("Failed to hash file");
 
     for hash in line_hashes {
@@ -35,6 +30,74 @@ fn test() {
     println!("{:?}", hash_to_hex_string(&hash));
 }
 
+const USAGE: &str = "usage:
+  diff-rs checksum [-a ALGORITHM] [--tag] PATH...
+  diff-rs checksum -c MANIFEST [-a ALGORITHM]";
+
+// `checksum` writes a manifest of the files under PATH to `out`, as `sha256sum` does,
+// or with `-c` checks the files a manifest names, writing a status line for each.
+// Relative paths, those of the manifest included, are taken from `base_dir`.
+fn checksum(args: &[String], base_dir: &Path, out: &mut dyn Write) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut algorithm = "sha256".to_string();
+    let mut format = diff_rs::manifest::ManifestFormat::Gnu;
+    let mut check = None;
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-a" | "--algorithm" => algorithm = args.next().ok_or(USAGE)?.clone(),
+            "--tag" => format = diff_rs::manifest::ManifestFormat::Bsd,
+            "-c" | "--check" => check = Some(args.next().ok_or(USAGE)?.clone()),
+            _ => paths.push(arg.clone())
+        }
+    }
+
+    if let Some(manifest_path) = check {
+        let mut ok = true;
+        for (entry, status) in diff_rs::manifest::verify_manifest(manifest_path, &algorithm, base_dir)? {
+            writeln!(out, "{}: {}", entry.path, status)?;
+            ok &= status == diff_rs::manifest::EntryStatus::Ok;
+        }
+        return Ok(ok)
+    }
+    if paths.is_empty() {
+        return Err(USAGE.into())
+    }
+    write!(out, "{}", diff_rs::manifest::format_manifest(&diff_rs::manifest::hash_files(&algorithm, &paths, base_dir)?, format))?;
+    Ok(true)
+}
+
+#[test]
+fn test_checksum_round_trip() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    std::fs::create_dir_all(tempdir.path().join("dist/sub")).unwrap();
+    std::fs::write(tempdir.path().join("dist/a.txt"), b"a").unwrap();
+    std::fs::write(tempdir.path().join("dist/sub/b.txt"), b"b").unwrap();
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+
+    // `checksum dist > dist/SHA256SUMS && checksum -c dist/SHA256SUMS`, run from the directory above
+    let mut manifest = Vec::new();
+    let hashed = checksum(&args(&["dist"]), tempdir.path(), &mut manifest).unwrap();
+    std::fs::write(tempdir.path().join("dist/SHA256SUMS"), &manifest).unwrap();
+    let mut statuses = Vec::new();
+    let checked = checksum(&args(&["-c", "dist/SHA256SUMS"]), tempdir.path(), &mut statuses).unwrap();
+
+    assert!(hashed && checked);
+    assert_eq!(String::from_utf8(statuses).unwrap(), "dist/a.txt: OK\ndist/sub/b.txt: OK\n");
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("checksum") => checksum(&args[1..], Path::new(""), &mut std::io::stdout()),
+        _ => Err(USAGE.into())
+    };
+    match result {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2)
+        }
+    }
 }
@@ -0,0 +1,309 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::hashing::*;
+
+// Checksum manifests as written by `sha256sum` and friends, one file per line:
+//   GNU style: `<hex>  <path>`, or `<hex> *<path>` for files hashed in binary mode, which is the same on Unix.
+//     Lines of paths holding a backslash or newline start with a backslash, and those are escaped in the path.
+//   BSD tag style (`--tag`): `<TAG> (<path>) = <hex>`, which also names the algorithm.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ManifestFormat {
+    #[default]
+    Gnu,
+    Bsd
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ManifestEntry {
+    pub algorithm: String,
+    pub path: String,
+    pub hexdigest: String
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EntryStatus {
+    Ok,
+    Failed,
+    Missing
+}
+
+impl Display for EntryStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntryStatus::Ok => write!(f, "OK"),
+            EntryStatus::Failed => write!(f, "FAILED"),
+            EntryStatus::Missing => write!(f, "MISSING")
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io(std::io::Error),
+    UnknownDigest(UnknownDigestError),
+    // `line` counts from 1
+    InvalidLine { line: usize, content: String }
+}
+
+impl Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManifestError::Io(e) => write!(f, "io error: {}", e),
+            ManifestError::UnknownDigest(e) => write!(f, "{}", e),
+            ManifestError::InvalidLine { line, content } => write!(f, "invalid manifest line {}: {}", line, content)
+        }
+    }
+}
+
+impl Error for ManifestError {}
+
+impl From<std::io::Error> for ManifestError {
+    fn from(e: std::io::Error) -> Self {
+        ManifestError::Io(e)
+    }
+}
+
+impl From<UnknownDigestError> for ManifestError {
+    fn from(e: UnknownDigestError) -> Self {
+        ManifestError::UnknownDigest(e)
+    }
+}
+
+// The tag BSD style lines name an algorithm by, as the BSD and coreutils tools write it
+pub fn bsd_tag(algorithm: &str) -> String {
+    match algorithm {
+        "blake2b" => "BLAKE2b".to_string(),
+        "blake2s" => "BLAKE2s".to_string(),
+        _ => algorithm.to_uppercase()
+    }
+}
+
+fn algorithm_of_tag(tag: &str) -> Option<&'static str> {
+    HASHER_NAMES.iter().copied().find(|name| bsd_tag(name) == tag)
+}
+
+// The files to hash for the given paths: files as they are, and directories walked for the files below them.
+// Files found in directories are sorted so that manifests come out the same every time.
+pub fn collect_files<P>(paths: &[P]) -> Result<Vec<PathBuf>, std::io::Error>
+where P: AsRef<Path>
+{
+    let mut files = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        if path.is_dir() {
+            let mut found = Vec::new();
+            walk_dir(path, &mut found)?;
+            found.sort();
+            files.extend(found);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+fn walk_dir(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), std::io::Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            walk_dir(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+// Hashes the files of `paths`, walking directories, with the algorithm named as `select_hasher` takes it.
+// Relative paths are taken from `base_dir` and entries name them as they were given.
+pub fn hash_files<P, B>(algorithm: &str, paths: &[P], base_dir: B) -> Result<Vec<ManifestEntry>, ManifestError>
+where P: AsRef<Path>, B: AsRef<Path>
+{
+    let base_dir = base_dir.as_ref();
+    let mut hasher = select_hasher(algorithm)?;
+    let mut entries = Vec::new();
+    let paths: Vec<PathBuf> = paths.iter().map(|path| base_dir.join(path)).collect();
+    for path in collect_files(&paths)? {
+        let digest = hash_reader_dyn(&mut *hasher, fs::File::open(&path)?)?;
+        entries.push(ManifestEntry {
+            algorithm: algorithm.to_string(),
+            path: path.strip_prefix(base_dir).unwrap_or(&path).to_string_lossy().to_string(),
+            hexdigest: hash_to_hex_string(&digest)
+        });
+    }
+    Ok(entries)
+}
+
+pub fn format_entry(entry: &ManifestEntry, format: ManifestFormat) -> String {
+    match format {
+        ManifestFormat::Gnu if entry.path.contains(['\\', '\n']) =>
+            format!("\\{}  {}", entry.hexdigest, entry.path.replace('\\', "\\\\").replace('\n', "\\n")),
+        ManifestFormat::Gnu => format!("{}  {}", entry.hexdigest, entry.path),
+        ManifestFormat::Bsd => format!("{} ({}) = {}", bsd_tag(&entry.algorithm), entry.path, entry.hexdigest)
+    }
+}
+
+pub fn format_manifest(entries: &[ManifestEntry], format: ManifestFormat) -> String {
+    entries.iter().map(|e| format_entry(e, format) + "\n").collect()
+}
+
+// Parses a manifest of either style, or a mix of both. GNU style lines don't say what they were
+// hashed with, so they are taken to be of `algorithm`. Blank lines are skipped.
+pub fn parse_manifest(manifest: &str, algorithm: &str) -> Result<Vec<ManifestEntry>, ManifestError> {
+    let mut entries = Vec::new();
+    for (i, line) in manifest.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue
+        }
+        let entry = parse_bsd_line(line)
+            .or_else(|| parse_gnu_line(line, algorithm))
+            .ok_or_else(|| ManifestError::InvalidLine { line: i + 1, content: line.to_string() })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn parse_bsd_line(line: &str) -> Option<ManifestEntry> {
+    let (tag, rest) = line.split_once(" (")?;
+    let (path, hexdigest) = rest.rsplit_once(") = ")?;
+    Some(ManifestEntry {
+        algorithm: algorithm_of_tag(tag)?.to_string(),
+        path: path.to_string(),
+        hexdigest: parse_hex(hexdigest)?
+    })
+}
+
+fn parse_gnu_line(line: &str, algorithm: &str) -> Option<ManifestEntry> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line)
+    };
+    let (hexdigest, path) = line.split_once(' ')?;
+    // a space for text mode, an asterisk for binary mode
+    let path = path.strip_prefix(' ').or_else(|| path.strip_prefix('*'))?;
+    Some(ManifestEntry {
+        algorithm: algorithm.to_string(),
+        path: if escaped { unescape(path)? } else { path.to_string() },
+        hexdigest: parse_hex(hexdigest)?
+    })
+}
+
+fn parse_hex(s: &str) -> Option<String> {
+    (!s.is_empty() && s.len().is_multiple_of(2) && s.bytes().all(|b| b.is_ascii_hexdigit())).then(|| s.to_ascii_lowercase())
+}
+
+fn unescape(path: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                'n' => '\n',
+                _ => return None
+            },
+            c => c
+        });
+    }
+    Some(unescaped)
+}
+
+// Checks every entry of a manifest against the file it names, relative to `base_dir`.
+// Files which can't be found are MISSING, and those which can't be opened or read FAILED,
+// rather than errors, as `sha256sum --check` reports them.
+pub fn verify_entries<P>(entries: &[ManifestEntry], base_dir: P) -> Result<Vec<(ManifestEntry, EntryStatus)>, ManifestError>
+where P: AsRef<Path>
+{
+    let mut results = Vec::new();
+    for entry in entries {
+        let mut hasher = select_hasher(&entry.algorithm)?;
+        let digest = fs::File::open(base_dir.as_ref().join(&entry.path)).and_then(|f| hash_reader_dyn(&mut *hasher, f));
+        let status = match digest {
+            Ok(digest) if hash_to_hex_string(&digest) == entry.hexdigest => EntryStatus::Ok,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => EntryStatus::Missing,
+            _ => EntryStatus::Failed
+        };
+        results.push((entry.clone(), status));
+    }
+    Ok(results)
+}
+
+// Verifies a manifest file. It and its paths are relative to `base_dir`, as `sha256sum --check` run there
+// takes them, so a manifest of `hash_files` can be checked with the same base it was written from.
+pub fn verify_manifest<P, B>(manifest_path: P, algorithm: &str, base_dir: B) -> Result<Vec<(ManifestEntry, EntryStatus)>, ManifestError>
+where P: AsRef<Path>, B: AsRef<Path>
+{
+    let entries = parse_manifest(&fs::read_to_string(base_dir.as_ref().join(manifest_path))?, algorithm)?;
+    verify_entries(&entries, base_dir)
+}
+
+#[test]
+fn test_manifest_formats_round_trip() {
+    let entry = |algorithm: &str, path: &str| ManifestEntry {
+        algorithm: algorithm.to_string(),
+        path: path.to_string(),
+        hexdigest: hash_to_hex_string(&hash_dyn(&mut *select_hasher(algorithm).unwrap(), b""))
+    };
+    let entries = vec![entry("sha256", "a b.txt"), entry("sha256", "dir/odd\\name\n")];
+    let gnu = format_manifest(&entries, ManifestFormat::Gnu);
+    assert_eq!(gnu.lines().next().unwrap(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  a b.txt");
+    assert_eq!(gnu.lines().nth(1).unwrap(), "\\e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  dir/odd\\\\name\\n");
+    assert_eq!(parse_manifest(&gnu, "sha256").unwrap(), entries);
+
+    let entries = vec![entry("md5", "a.txt"), entry("blake2b", "(b) = c.txt")];
+    let bsd = format_manifest(&entries, ManifestFormat::Bsd);
+    assert_eq!(bsd.lines().next().unwrap(), "MD5 (a.txt) = d41d8cd98f00b204e9800998ecf8427e");
+    assert!(bsd.lines().nth(1).unwrap().starts_with("BLAKE2b ((b) = c.txt) = "));
+    // the algorithm of BSD lines comes from their tag
+    assert_eq!(parse_manifest(&bsd, "sha256").unwrap(), entries);
+
+    // binary mode marker and upper case digests, as other tools may write them
+    let parsed = parse_manifest("D41D8CD98F00B204E9800998ECF8427E *a.txt\n\n", "md5").unwrap();
+    assert_eq!(parsed, vec![entry("md5", "a.txt")]);
+    assert!(matches!(parse_manifest("ok\nnot a manifest line", "md5"), Err(ManifestError::InvalidLine { line: 1, .. })));
+    assert!(matches!(parse_manifest("", "sha4"), Ok(entries) if entries.is_empty()));
+}
+
+#[test]
+fn test_hash_and_verify_tree() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let root = tempdir.path();
+    fs::create_dir_all(root.join("src/nested")).unwrap();
+    fs::write(root.join("README"), b"readme").unwrap();
+    fs::write(root.join("src/main.rs"), b"fn main() {}").unwrap();
+    fs::write(root.join("src/nested/lib.rs"), b"").unwrap();
+
+    let entries = hash_files("sha256", &[root.join("src"), root.join("README")], "").unwrap();
+    let paths: Vec<_> = entries.iter().map(|e| Path::new(&e.path).strip_prefix(root).unwrap().to_path_buf()).collect();
+    assert_eq!(paths, vec![PathBuf::from("src/main.rs"), PathBuf::from("src/nested/lib.rs"), PathBuf::from("README")]);
+    assert_eq!(entries[2].hexdigest, hash_to_hex_string(&hash::<sha2::Sha256>(b"readme")));
+    assert!(matches!(hash_files("sha4", &[root], ""), Err(ManifestError::UnknownDigest(_))));
+
+    // written with the paths as they were hashed, then the tree changes under it
+    let manifest_path = root.join("SHA256SUMS");
+    fs::write(&manifest_path, format_manifest(&entries, ManifestFormat::Gnu)).unwrap();
+    fs::write(root.join("src/main.rs"), b"fn main() { changed() }").unwrap();
+    fs::remove_file(root.join("README")).unwrap();
+
+    let statuses: Vec<_> = verify_manifest(&manifest_path, "sha256", "").unwrap().into_iter().map(|(e, s)| (PathBuf::from(e.path), s)).collect();
+    assert_eq!(statuses, vec![
+        (root.join("src/main.rs"), EntryStatus::Failed),
+        (root.join("src/nested/lib.rs"), EntryStatus::Ok),
+        (root.join("README"), EntryStatus::Missing)
+    ]);
+
+    // paths are relative to the base directory, not to the manifest
+    let manifest_path = root.join("src/SHA256SUMS");
+    let beside = [ManifestEntry { path: "nested/lib.rs".to_string(), ..entries[1].clone() }];
+    fs::write(&manifest_path, format_manifest(&beside, ManifestFormat::Gnu)).unwrap();
+    assert_eq!(verify_manifest(&manifest_path, "sha256", "").unwrap()[0].1, EntryStatus::Missing);
+    assert_eq!(verify_manifest("SHA256SUMS", "sha256", root.join("src")).unwrap()[0].1, EntryStatus::Ok);
+
+    // an entry naming something that opens but can't be read fails without stopping the others
+    let unreadable = [ManifestEntry { path: "nested".to_string(), ..entries[1].clone() }, beside[0].clone()];
+    let statuses: Vec<_> = verify_entries(&unreadable, root.join("src")).unwrap().into_iter().map(|(_, s)| s).collect();
+    assert_eq!(statuses, vec![EntryStatus::Failed, EntryStatus::Ok]);
+}
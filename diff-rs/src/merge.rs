@@ -0,0 +1,823 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::error::Error;
+
+use crate::line_diff::*;
+use crate::merge_driver::*;
+use crate::merkle::*;
+use crate::vc::*;
+
+pub const CONFLICT_MARKER_OURS: &str = "<<<<<<< ours\n";
+pub const CONFLICT_MARKER_SEPARATOR: &str = "=======\n";
+pub const CONFLICT_MARKER_THEIRS: &str = ">>>>>>> theirs\n";
+const CONFLICT_MARKER_BASE: &str = "|||||||";
+
+pub type Line = Box<[u8]>;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MergeChunk {
+    Resolved(Vec<Line>),
+    Conflict {
+        base: Vec<Line>,
+        ours: Vec<Line>,
+        theirs: Vec<Line>
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FileMerge {
+    pub chunks: Vec<MergeChunk>
+}
+
+impl FileMerge {
+    pub fn is_clean(&self) -> bool {
+        self.conflict_count() == 0
+    }
+
+    pub fn conflict_count(&self) -> usize {
+        self.chunks.iter().filter(|c| matches!(c, MergeChunk::Conflict { .. })).count()
+    }
+
+    // Renders the merge, surrounding every conflict with conflict markers
+    pub fn to_blob(&self) -> Blob {
+        let mut data: Vec<u8> = Vec::new();
+        for chunk in &self.chunks {
+            match chunk {
+                MergeChunk::Resolved(lines) => push_lines(&mut data, lines),
+                MergeChunk::Conflict { ours, theirs, .. } => {
+                    data.extend_from_slice(CONFLICT_MARKER_OURS.as_bytes());
+                    push_lines(&mut data, ours);
+                    end_line(&mut data);
+                    data.extend_from_slice(CONFLICT_MARKER_SEPARATOR.as_bytes());
+                    push_lines(&mut data, theirs);
+                    end_line(&mut data);
+                    data.extend_from_slice(CONFLICT_MARKER_THEIRS.as_bytes());
+                }
+            }
+        }
+        Blob::new_owned(data.into_boxed_slice())
+    }
+
+    // Replaces every conflict for which `resolve` returns the resolved lines
+    pub fn resolve_conflicts<F>(&self, mut resolve: F) -> FileMerge
+    where F: FnMut(&[Line], &[Line], &[Line]) -> Option<Vec<Line>>
+    {
+        let chunks = self.chunks.iter().map(|chunk| match chunk {
+            MergeChunk::Conflict { base, ours, theirs } => match resolve(base, ours, theirs) {
+                Some(lines) => MergeChunk::Resolved(lines),
+                None => chunk.clone()
+            },
+            resolved => resolved.clone()
+        }).collect();
+        FileMerge { chunks }
+    }
+}
+
+fn is_marker(line: &[u8], marker: &str) -> bool {
+    let marker = &marker.as_bytes()[..7];
+    line.starts_with(marker) && matches!(line.get(7), None | Some(b' ' | b'\n' | b'\r'))
+}
+
+// Reads back the conflicts of a file holding conflict markers, e.g. one written by `FileMerge::to_blob`.
+// Base sections in the diff3 style are accepted; an unterminated conflict is kept as plain text.
+pub fn parse_conflicts(blob: &Blob) -> FileMerge {
+    let mut chunks: Vec<MergeChunk> = Vec::new();
+    let mut resolved: Vec<Line> = Vec::new();
+    // (raw lines of the conflict, sections seen so far: ours, base, theirs)
+    let mut conflict: Option<(Vec<Line>, [Vec<Line>; 3], usize)> = None;
+
+    for line in split_lines_inclusive(blob.get_data()) {
+        match &mut conflict {
+            None if is_marker(line, CONFLICT_MARKER_OURS) => {
+                conflict = Some((vec![line.into()], Default::default(), 0));
+            },
+            None => resolved.push(line.into()),
+            Some((raw, sections, section)) => {
+                raw.push(line.into());
+                if *section == 0 && is_marker(line, CONFLICT_MARKER_BASE) {
+                    *section = 1;
+                } else if *section < 2 && is_marker(line, CONFLICT_MARKER_SEPARATOR) {
+                    *section = 2;
+                } else if *section == 2 && is_marker(line, CONFLICT_MARKER_THEIRS) {
+                    let [ours, base, theirs] = std::mem::take(sections);
+                    if !resolved.is_empty() {
+                        chunks.push(MergeChunk::Resolved(std::mem::take(&mut resolved)));
+                    }
+                    chunks.push(MergeChunk::Conflict { base, ours, theirs });
+                    conflict = None;
+                } else {
+                    sections[*section].push(line.into());
+                }
+            }
+        }
+    }
+    if let Some((raw, _, _)) = conflict {
+        resolved.extend(raw);
+    }
+    if !resolved.is_empty() {
+        chunks.push(MergeChunk::Resolved(resolved));
+    }
+    FileMerge { chunks }
+}
+
+fn push_lines(data: &mut Vec<u8>, lines: &[Line]) {
+    for line in lines {
+        data.extend_from_slice(line);
+    }
+}
+
+// markers have to start on a line of their own, even after a last line without a newline
+fn end_line(data: &mut Vec<u8>) {
+    if data.last().is_some_and(|b| *b != b'\n') {
+        data.push(b'\n');
+    }
+}
+
+fn to_lines(lines: &[&[u8]]) -> Vec<Line> {
+    lines.iter().map(|l| (*l).into()).collect()
+}
+
+// For every line of `base`, the index of the matching line in `other`
+fn match_lines(base: &[&[u8]], other: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+    for op in diff_lines(base, other) {
+        if let DiffOp::Equal { old_index, new_index } = op {
+            matches[old_index] = Some(new_index);
+        }
+    }
+    matches
+}
+
+// Three-way line merge of two versions of a file against their common ancestor (diff3)
+pub fn merge3(base: &Blob, ours: &Blob, theirs: &Blob) -> FileMerge {
+    let base_lines = split_lines_inclusive(base.get_data());
+    let ours_lines = split_lines_inclusive(ours.get_data());
+    let theirs_lines = split_lines_inclusive(theirs.get_data());
+    let ours_matches = match_lines(&base_lines, &ours_lines);
+    let theirs_matches = match_lines(&base_lines, &theirs_lines);
+
+    let mut chunks: Vec<MergeChunk> = Vec::new();
+    let push_resolved = |chunks: &mut Vec<MergeChunk>, lines: &[&[u8]]| {
+        if lines.is_empty() {
+            return
+        }
+        match chunks.last_mut() {
+            Some(MergeChunk::Resolved(prev)) => prev.extend(to_lines(lines)),
+            _ => chunks.push(MergeChunk::Resolved(to_lines(lines)))
+        }
+    };
+
+    let (mut o, mut a, mut b) = (0, 0, 0);
+    loop {
+        // lines unchanged on both sides
+        let mut stable = 0;
+        while o + stable < base_lines.len()
+            && ours_matches[o + stable] == Some(a + stable)
+            && theirs_matches[o + stable] == Some(b + stable)
+        {
+            stable += 1;
+        }
+        if stable > 0 {
+            push_resolved(&mut chunks, &base_lines[o..o + stable]);
+            o += stable;
+            a += stable;
+            b += stable;
+            continue;
+        }
+
+        // the next base line both sides still have marks the end of the unstable region
+        let (next_o, next_a, next_b) = (o..base_lines.len())
+            .find_map(|i| Some((i, ours_matches[i]?, theirs_matches[i]?)))
+            .unwrap_or((base_lines.len(), ours_lines.len(), theirs_lines.len()));
+        if (next_o, next_a, next_b) == (o, a, b) {
+            break;
+        }
+
+        let base_chunk = &base_lines[o..next_o];
+        let ours_chunk = &ours_lines[a..next_a];
+        let theirs_chunk = &theirs_lines[b..next_b];
+        if ours_chunk == base_chunk {
+            push_resolved(&mut chunks, theirs_chunk);
+        } else if theirs_chunk == base_chunk || ours_chunk == theirs_chunk {
+            push_resolved(&mut chunks, ours_chunk);
+        } else {
+            chunks.push(MergeChunk::Conflict {
+                base: to_lines(base_chunk),
+                ours: to_lines(ours_chunk),
+                theirs: to_lines(theirs_chunk)
+            });
+        }
+        (o, a, b) = (next_o, next_a, next_b);
+    }
+
+    FileMerge { chunks }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MergeSide {
+    Ours,
+    Theirs
+}
+
+#[derive(Debug, Clone)]
+pub enum TreeConflict {
+    // both sides changed the file in overlapping places; `merged` holds conflict markers
+    Content {
+        path: PathBuf,
+        base: Blob,
+        ours: Blob,
+        theirs: Blob,
+        merged: Blob
+    },
+    AddAdd {
+        path: PathBuf,
+        ours: Blob,
+        theirs: Blob,
+        merged: Blob
+    },
+    ModifyDelete {
+        path: PathBuf,
+        modified: Blob,
+        deleted_by: MergeSide
+    },
+    RenameRename {
+        base_path: PathBuf,
+        ours_path: PathBuf,
+        theirs_path: PathBuf
+    },
+    RenameDelete {
+        base_path: PathBuf,
+        renamed_path: PathBuf,
+        renamed_by: MergeSide
+    },
+    // a file on one side is in the way of a directory on the other
+    DirectoryFile {
+        path: PathBuf
+    },
+    // both sides added the file, one of them as executable
+    Mode {
+        path: PathBuf,
+        ours: FileMode,
+        theirs: FileMode
+    }
+}
+
+impl TreeConflict {
+    pub fn get_path(&self) -> &Path {
+        match self {
+            TreeConflict::Content { path, .. } => path,
+            TreeConflict::AddAdd { path, .. } => path,
+            TreeConflict::ModifyDelete { path, .. } => path,
+            TreeConflict::RenameRename { base_path, .. } => base_path,
+            TreeConflict::RenameDelete { base_path, .. } => base_path,
+            TreeConflict::DirectoryFile { path } => path,
+            TreeConflict::Mode { path, .. } => path
+        }
+    }
+}
+
+impl Display for TreeConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TreeConflict::Content { path, .. } => write!(f, "content conflict in {}", path.display()),
+            TreeConflict::AddAdd { path, .. } => write!(f, "add/add conflict in {}", path.display()),
+            TreeConflict::ModifyDelete { path, deleted_by, .. } => {
+                write!(f, "modify/delete conflict in {}, deleted in {:?}", path.display(), deleted_by)
+            },
+            TreeConflict::RenameRename { base_path, ours_path, theirs_path } => {
+                write!(f, "rename/rename conflict: {} renamed to {} in Ours and to {} in Theirs",
+                    base_path.display(), ours_path.display(), theirs_path.display())
+            },
+            TreeConflict::RenameDelete { base_path, renamed_path, renamed_by } => {
+                write!(f, "rename/delete conflict: {} renamed to {} in {:?} and deleted in the other",
+                    base_path.display(), renamed_path.display(), renamed_by)
+            },
+            TreeConflict::DirectoryFile { path } => write!(f, "directory/file conflict in {}", path.display()),
+            TreeConflict::Mode { path, ours, theirs } => {
+                write!(f, "mode conflict in {}: {:o} in Ours and {:o} in Theirs", path.display(), ours.as_octal(), theirs.as_octal())
+            }
+        }
+    }
+}
+
+impl Error for TreeConflict {}
+
+#[derive(Debug, Clone)]
+struct BlobChange {
+    base: Option<Blob>,
+    new: Option<Blob>
+}
+
+#[derive(Debug, Default)]
+struct TreeChanges {
+    // paths both sides agree on, taken as they are without looking inside
+    agreed: Vec<(PathBuf, Option<FsObject>)>,
+    ours: BTreeMap<PathBuf, BlobChange>,
+    theirs: BTreeMap<PathBuf, BlobChange>
+}
+
+// What a tree lists an object as, so that a change of mode alone is a change
+fn hash_of(obj: Option<&FsObject>) -> Option<(FileMode, VcHash)> {
+    obj.map(|o| (o.get_mode(), o.get_hash()))
+}
+
+// The executable bit of the merged file, taken from whichever side changed it.
+// None if the file was added on both sides with different modes.
+fn merge_mode(base: Option<&Blob>, ours: &Blob, theirs: &Blob) -> Option<bool> {
+    match base {
+        _ if ours.executable == theirs.executable => Some(ours.executable),
+        Some(base) if base.executable == ours.executable => Some(theirs.executable),
+        Some(_) => Some(ours.executable),
+        None => None
+    }
+}
+
+fn blobs_of(path: &Path, obj: Option<&FsObject>) -> Vec<(PathBuf, Blob)> {
+    match obj {
+        None => Vec::new(),
+        Some(FsObject::Blob(b)) => vec![(path.to_path_buf(), b.clone())],
+        Some(FsObject::Tree(t)) => t.get_blobs().into_iter().map(|(p, b)| (path.join(p), b.clone())).collect()
+    }
+}
+
+fn names_of<'n>(listings: &[&'n HashMap<Name, FsObject>]) -> BTreeSet<&'n Name> {
+    listings.iter().flat_map(|l| l.keys()).collect()
+}
+
+// Walks the three trees together, skipping every subtree whose hash is identical on both sides
+// and recording blob level changes everywhere else
+fn collect_changes(path: &Path, base: Option<&FsObject>, ours: Option<&FsObject>, theirs: Option<&FsObject>, changes: &mut TreeChanges) {
+    // paths deleted on both sides are still recorded per side, as one of them may be a rename
+    if ours.is_some() && hash_of(ours) == hash_of(theirs) {
+        if hash_of(base) != hash_of(ours) {
+            changes.agreed.push((path.to_path_buf(), ours.cloned()));
+        }
+        return
+    }
+    match (base, ours, theirs) {
+        (None | Some(FsObject::Tree(_)), Some(FsObject::Tree(o)), Some(FsObject::Tree(t))) => {
+            let empty = HashMap::new();
+            let base_listings = match base {
+                Some(FsObject::Tree(b)) => &b.listings,
+                _ => &empty
+            };
+            collect_listing_changes(path, base_listings, &o.listings, &t.listings, changes);
+        },
+        _ => {
+            diff_objects(path, base, ours, &mut changes.ours);
+            diff_objects(path, base, theirs, &mut changes.theirs);
+        }
+    }
+}
+
+fn collect_listing_changes(path: &Path, base: &HashMap<Name, FsObject>, ours: &HashMap<Name, FsObject>, theirs: &HashMap<Name, FsObject>, changes: &mut TreeChanges) {
+    for name in names_of(&[base, ours, theirs]) {
+        collect_changes(&path.join(name), base.get(name), ours.get(name), theirs.get(name), changes);
+    }
+}
+
+fn diff_objects(path: &Path, old: Option<&FsObject>, new: Option<&FsObject>, out: &mut BTreeMap<PathBuf, BlobChange>) {
+    if hash_of(old) == hash_of(new) {
+        return
+    }
+    match (old, new) {
+        (Some(FsObject::Tree(a)), Some(FsObject::Tree(b))) => {
+            for name in names_of(&[&a.listings, &b.listings]) {
+                diff_objects(&path.join(name), a.listings.get(name), b.listings.get(name), out);
+            }
+        },
+        (Some(FsObject::Blob(a)), Some(FsObject::Blob(b))) => {
+            out.insert(path.to_path_buf(), BlobChange { base: Some(a.clone()), new: Some(b.clone()) });
+        },
+        _ => {
+            for (p, b) in blobs_of(path, old) {
+                out.insert(p, BlobChange { base: Some(b), new: None });
+            }
+            for (p, b) in blobs_of(path, new) {
+                out.entry(p).or_insert(BlobChange { base: None, new: None }).new = Some(b);
+            }
+        }
+    }
+}
+
+// Pairs deleted files with added ones that have the same or similar content
+fn detect_renames(changes: &BTreeMap<PathBuf, BlobChange>) -> BTreeMap<PathBuf, PathBuf> {
+    let added: Vec<(&PathBuf, &Blob)> = changes.iter()
+        .filter_map(|(p, c)| match c {
+            BlobChange { base: None, new: Some(b) } => Some((p, b)),
+            _ => None
+        })
+        .collect();
+
+    let mut renames = BTreeMap::new();
+    let mut used: HashSet<&PathBuf> = HashSet::new();
+    for (from, change) in changes {
+        let BlobChange { base: Some(old), new: None } = change else {
+            continue
+        };
+        let exact = added.iter().find(|(to, b)| !used.contains(to) && b.hash == old.hash);
+        let similar = || added.iter()
+            .filter(|(to, _)| !used.contains(to))
+            .map(|(to, b)| (line_similarity(old.get_data(), b.get_data()), to))
+            .filter(|(similarity, _)| *similarity >= RENAME_SIMILARITY_THRESHOLD)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, to)| *to);
+        if let Some(to) = exact.map(|(to, _)| *to).or_else(similar) {
+            used.insert(to);
+            renames.insert(from.clone(), to.clone());
+        }
+    }
+    renames
+}
+
+#[derive(Debug)]
+struct MergeState<'o> {
+    options: &'o MergeOptions,
+    // blob level results, relative to the base tree
+    results: Vec<(PathBuf, Option<Blob>)>,
+    conflicts: Vec<TreeConflict>,
+    handled: HashSet<PathBuf>
+}
+
+impl<'o> MergeState<'o> {
+    fn new(options: &'o MergeOptions) -> Self {
+        Self {
+            options,
+            results: Vec::new(),
+            conflicts: Vec::new(),
+            handled: HashSet::new()
+        }
+    }
+
+    fn merge_file(&mut self, path: &Path, base: Option<&Blob>, ours: &Blob, theirs: &Blob) {
+        let executable = merge_mode(base, ours, theirs).unwrap_or_else(|| {
+            self.conflicts.push(TreeConflict::Mode {
+                path: path.to_path_buf(),
+                ours: ours.get_mode(),
+                theirs: theirs.get_mode()
+            });
+            ours.executable
+        });
+        if ours.hash == theirs.hash {
+            self.results.push((path.to_path_buf(), Some(ours.clone().with_executable(executable))));
+            return
+        }
+        let merged = self.options.merge_file(path, base, ours, theirs);
+        if merged.clean {
+            self.results.push((path.to_path_buf(), Some(merged.blob.with_executable(executable))));
+            return
+        }
+        match base {
+            Some(base) => self.conflicts.push(TreeConflict::Content {
+                path: path.to_path_buf(),
+                base: base.clone(),
+                ours: ours.clone(),
+                theirs: theirs.clone(),
+                merged: merged.blob
+            }),
+            None => self.conflicts.push(TreeConflict::AddAdd {
+                path: path.to_path_buf(),
+                ours: ours.clone(),
+                theirs: theirs.clone(),
+                merged: merged.blob
+            })
+        }
+    }
+
+    // A file modified on one side and deleted on the other is only resolved by the ours and theirs strategies
+    fn modify_delete(&mut self, path: &Path, modified: &Blob, deleted_by: MergeSide) {
+        match (self.options.strategy, deleted_by) {
+            (MergeStrategy::Ours, MergeSide::Ours) | (MergeStrategy::Theirs, MergeSide::Theirs) => {
+                self.results.push((path.to_path_buf(), None));
+            },
+            (MergeStrategy::Ours, MergeSide::Theirs) | (MergeStrategy::Theirs, MergeSide::Ours) => {
+                self.results.push((path.to_path_buf(), Some(modified.clone())));
+            },
+            _ => self.conflicts.push(TreeConflict::ModifyDelete {
+                path: path.to_path_buf(),
+                modified: modified.clone(),
+                deleted_by
+            })
+        }
+    }
+
+    // Merges a file renamed from `from` to `to` by `side` with whatever the other side did to it
+    fn merge_rename(&mut self, side: MergeSide, from: &Path, to: &Path, this: &BTreeMap<PathBuf, BlobChange>, other: &BTreeMap<PathBuf, BlobChange>) {
+        self.handled.insert(from.to_path_buf());
+        self.handled.insert(to.to_path_buf());
+        let base = this[from].base.as_ref().expect("rename source exists in base");
+        let renamed = this[to].new.as_ref().expect("rename target exists");
+
+        let other_version = match other.get(from) {
+            None => Some(base),
+            Some(BlobChange { new: Some(b), .. }) => Some(b),
+            Some(BlobChange { new: None, .. }) => None
+        };
+        let Some(other_version) = other_version else {
+            self.conflicts.push(TreeConflict::RenameDelete {
+                base_path: from.to_path_buf(),
+                renamed_path: to.to_path_buf(),
+                renamed_by: side
+            });
+            return
+        };
+        self.results.push((from.to_path_buf(), None));
+
+        // the other side may have added a file of its own where this side renamed to
+        if let Some(BlobChange { new: Some(added), .. }) = other.get(to) {
+            match side {
+                MergeSide::Ours => self.merge_file(to, None, renamed, added),
+                MergeSide::Theirs => self.merge_file(to, None, added, renamed)
+            }
+            return
+        }
+        match side {
+            MergeSide::Ours => self.merge_file(to, Some(base), renamed, other_version),
+            MergeSide::Theirs => self.merge_file(to, Some(base), other_version, renamed)
+        }
+    }
+}
+
+// Three-way merge of two trees against their common ancestor.
+// Renamed files are followed on both sides, and every subtree with the same hash on both
+// sides is taken without being looked at.
+pub fn merge_trees(base: &Tree, ours: &Tree, theirs: &Tree) -> Result<Tree, Vec<TreeConflict>> {
+    merge_trees_with(base, ours, theirs, &MergeOptions::default())
+}
+
+// Like `merge_trees`, with the merge strategy and per-path merge drivers taken from `options`.
+// Without attributes in `options`, those of the attributes file of ours, or else of the base, are used.
+pub fn merge_trees_with(base: &Tree, ours: &Tree, theirs: &Tree, options: &MergeOptions) -> Result<Tree, Vec<TreeConflict>> {
+    if ours.hash == theirs.hash || base.hash == theirs.hash {
+        return Ok(ours.clone())
+    }
+    if base.hash == ours.hash {
+        return Ok(theirs.clone())
+    }
+
+    let with_tree_attributes;
+    let options = if options.attributes.is_empty() {
+        let attributes = match Attributes::from_tree(ours) {
+            attributes if attributes.is_empty() => Attributes::from_tree(base),
+            attributes => attributes
+        };
+        with_tree_attributes = MergeOptions { attributes, ..options.clone() };
+        &with_tree_attributes
+    } else {
+        options
+    };
+
+    let mut changes = TreeChanges::default();
+    collect_listing_changes(Path::new(""), &base.listings, &ours.listings, &theirs.listings, &mut changes);
+    let ours_renames = detect_renames(&changes.ours);
+    let theirs_renames = detect_renames(&changes.theirs);
+
+    let mut state = MergeState::new(options);
+    for (from, ours_to) in &ours_renames {
+        match theirs_renames.get(from) {
+            Some(theirs_to) if theirs_to == ours_to => {
+                state.handled.insert(from.clone());
+                state.handled.insert(ours_to.clone());
+                state.results.push((from.clone(), None));
+                let base_blob = changes.ours[from].base.as_ref();
+                let ours_blob = changes.ours[ours_to].new.as_ref().expect("rename target exists");
+                let theirs_blob = changes.theirs[theirs_to].new.as_ref().expect("rename target exists");
+                state.merge_file(ours_to, base_blob, ours_blob, theirs_blob);
+            },
+            Some(theirs_to) => {
+                state.handled.extend([from.clone(), ours_to.clone(), theirs_to.clone()]);
+                state.conflicts.push(TreeConflict::RenameRename {
+                    base_path: from.clone(),
+                    ours_path: ours_to.clone(),
+                    theirs_path: theirs_to.clone()
+                });
+            },
+            None => state.merge_rename(MergeSide::Ours, from, ours_to, &changes.ours, &changes.theirs)
+        }
+    }
+    for (from, theirs_to) in &theirs_renames {
+        if !ours_renames.contains_key(from) {
+            state.merge_rename(MergeSide::Theirs, from, theirs_to, &changes.theirs, &changes.ours);
+        }
+    }
+
+    let paths: BTreeSet<&PathBuf> = changes.ours.keys().chain(changes.theirs.keys()).collect();
+    for path in paths {
+        if state.handled.contains(path) {
+            continue;
+        }
+        match (changes.ours.get(path), changes.theirs.get(path)) {
+            (Some(change), None) | (None, Some(change)) => state.results.push((path.clone(), change.new.clone())),
+            (Some(o), Some(t)) => match (&o.new, &t.new) {
+                (None, None) => state.results.push((path.clone(), None)),
+                (Some(ours_blob), Some(theirs_blob)) => state.merge_file(path, o.base.as_ref(), ours_blob, theirs_blob),
+                (Some(modified), None) => state.modify_delete(path, modified, MergeSide::Theirs),
+                (None, Some(modified)) => state.modify_delete(path, modified, MergeSide::Ours)
+            },
+            (None, None) => {}
+        }
+    }
+
+    // removals go first so that files and directories can replace each other
+    let mut merged = base.clone();
+    for (path, _) in &changes.agreed {
+        merged.remove_path(path);
+    }
+    for (path, blob) in &state.results {
+        if blob.is_none() {
+            merged.remove_path(path);
+        }
+    }
+    let insertions = changes.agreed.into_iter()
+        .chain(state.results.into_iter().map(|(p, b)| (p, b.map(FsObject::Blob))));
+    for (path, obj) in insertions {
+        if let Some(obj) = obj {
+            if !merged.insert_path(&path, obj) {
+                state.conflicts.push(TreeConflict::DirectoryFile { path });
+            }
+        }
+    }
+
+    if state.conflicts.is_empty() {
+        Ok(merged)
+    } else {
+        Err(state.conflicts)
+    }
+}
+
+#[cfg(test)]
+fn file_at(tree: &Tree, path: &str) -> Option<String> {
+    match tree.get_path(path) {
+        Some(FsObject::Blob(b)) => Some(b.get_data_as_string()),
+        _ => None
+    }
+}
+
+#[test]
+fn test_merge3_clean() {
+    let base = Blob::new(b"a\nb\nc\nd\ne\n");
+    let ours = Blob::new(b"A\nb\nc\nd\ne\n");
+    let theirs = Blob::new(b"a\nb\nc\nd\nE\nf\n");
+    let merged = merge3(&base, &ours, &theirs);
+    assert!(merged.is_clean());
+    assert_eq!(merged.to_blob().get_data(), b"A\nb\nc\nd\nE\nf\n");
+}
+
+#[test]
+fn test_merge3_same_change_on_both_sides() {
+    let base = Blob::new(b"a\nb\n");
+    let changed = Blob::new(b"a\nB\n");
+    let merged = merge3(&base, &changed, &changed);
+    assert!(merged.is_clean());
+    assert_eq!(merged.to_blob().get_data(), changed.get_data());
+}
+
+#[test]
+fn test_merge3_conflict() {
+    let base = Blob::new(b"a\nb\nc\n");
+    let ours = Blob::new(b"a\nours\nc\n");
+    let theirs = Blob::new(b"a\ntheirs\nc");
+    let merged = merge3(&base, &ours, &theirs);
+    assert_eq!(merged.conflict_count(), 1);
+    assert_eq!(
+        merged.to_blob().get_data_as_string(),
+        "a\n<<<<<<< ours\nours\nc\n=======\ntheirs\nc\n>>>>>>> theirs\n"
+    );
+}
+
+#[test]
+fn test_parse_conflicts() {
+    let base = Blob::new(b"a\nb\nc\nd\ne\n");
+    let ours = Blob::new(b"a\nours\nc\nd\nE\n");
+    let theirs = Blob::new(b"a\ntheirs\nc\nd\ne");
+    let merged = merge3(&base, &ours, &theirs);
+    let parsed = parse_conflicts(&merged.to_blob());
+    assert_eq!(parsed.conflict_count(), 2);
+    assert_eq!(parsed.to_blob().get_data(), merged.to_blob().get_data());
+
+    let diff3 = Blob::new(b"x\n<<<<<<< ours\n1\n||||||| base\n0\n=======\n2\n>>>>>>> theirs\n<<<<<<< unterminated\n");
+    let parsed = parse_conflicts(&diff3);
+    assert_eq!(parsed.chunks.len(), 3);
+    assert_eq!(parsed.chunks[1], MergeChunk::Conflict {
+        base: vec![b"0\n"[..].into()],
+        ours: vec![b"1\n"[..].into()],
+        theirs: vec![b"2\n"[..].into()]
+    });
+}
+
+#[test]
+fn test_merge_trees_short_circuits() {
+    let base = tree_of(&[("a.txt", "a\n")]);
+    let ours = tree_of(&[("a.txt", "b\n")]);
+    assert_eq!(merge_trees(&base, &ours, &base).unwrap().hash, ours.hash);
+    assert_eq!(merge_trees(&base, &base, &ours).unwrap().hash, ours.hash);
+    assert_eq!(merge_trees(&base, &ours, &ours).unwrap().hash, ours.hash);
+}
+
+#[test]
+fn test_merge_trees_clean() {
+    let base = tree_of(&[("a.txt", "1\n2\n3\n4\n"), ("dir/b.txt", "b\n"), ("dir/c.txt", "c\n")]);
+    let ours = tree_of(&[("a.txt", "one\n2\n3\n4\n"), ("dir/b.txt", "b\n"), ("new.txt", "new\n")]);
+    let theirs = tree_of(&[("a.txt", "1\n2\n3\nfour\n"), ("dir/b.txt", "B\n"), ("dir/c.txt", "c\n")]);
+    let merged = merge_trees(&base, &ours, &theirs).unwrap();
+    assert_eq!(file_at(&merged, "a.txt").unwrap(), "one\n2\n3\nfour\n");
+    assert_eq!(file_at(&merged, "dir/b.txt").unwrap(), "B\n");
+    assert_eq!(file_at(&merged, "dir/c.txt"), None);
+    assert_eq!(file_at(&merged, "new.txt").unwrap(), "new\n");
+}
+
+#[test]
+fn test_merge_trees_content_conflict() {
+    let base = tree_of(&[("a.txt", "a\n")]);
+    let ours = tree_of(&[("a.txt", "ours\n")]);
+    let theirs = tree_of(&[("a.txt", "theirs\n")]);
+    let conflicts = merge_trees(&base, &ours, &theirs).unwrap_err();
+    assert_eq!(conflicts.len(), 1);
+    let TreeConflict::Content { path, merged, .. } = &conflicts[0] else {
+        panic!("expected a content conflict");
+    };
+    assert_eq!(path, &PathBuf::from("a.txt"));
+    assert!(merged.get_data_as_string().contains(CONFLICT_MARKER_SEPARATOR));
+}
+
+#[test]
+fn test_merge_trees_add_add_and_modify_delete() {
+    let base = tree_of(&[("a.txt", "a\n"), ("keep.txt", "k\n")]);
+    let ours = tree_of(&[("a.txt", "changed\n"), ("keep.txt", "k\n"), ("b.txt", "ours\n")]);
+    let theirs = tree_of(&[("keep.txt", "k\n"), ("b.txt", "theirs\n")]);
+    let conflicts = merge_trees(&base, &ours, &theirs).unwrap_err();
+    assert_eq!(conflicts.len(), 2);
+    assert!(conflicts.iter().any(|c| matches!(c, TreeConflict::AddAdd { path, .. } if path == Path::new("b.txt"))));
+    assert!(conflicts.iter().any(|c| matches!(c,
+        TreeConflict::ModifyDelete { path, deleted_by: MergeSide::Theirs, .. } if path == Path::new("a.txt"))));
+}
+
+#[test]
+fn test_merge_trees_rename_and_modify() {
+    let base = tree_of(&[("src/old.txt", "1\n2\n3\n4\n"), ("other.txt", "o\n")]);
+    let ours = tree_of(&[("lib/new.txt", "1\n2\n3\n4\n"), ("other.txt", "O\n")]);
+    let theirs = tree_of(&[("src/old.txt", "1\n2\n3\nfour\n"), ("other.txt", "o\n")]);
+    let merged = merge_trees(&base, &ours, &theirs).unwrap();
+    assert_eq!(file_at(&merged, "lib/new.txt").unwrap(), "1\n2\n3\nfour\n");
+    assert!(merged.get_path("src").is_none());
+
+    // and the same with the sides swapped
+    let merged = merge_trees(&base, &theirs, &ours).unwrap();
+    assert_eq!(file_at(&merged, "lib/new.txt").unwrap(), "1\n2\n3\nfour\n");
+}
+
+#[test]
+fn test_merge_trees_pure_rename() {
+    // renaming alone changes the tree hash, so neither side is mistaken for the base
+    let base = tree_of(&[("a.txt", "a\n"), ("b.txt", "b\n")]);
+    let ours = tree_of(&[("c.txt", "a\n"), ("b.txt", "b\n")]);
+    let theirs = tree_of(&[("a.txt", "a\n"), ("b.txt", "B\n")]);
+    let merged = merge_trees(&base, &ours, &theirs).unwrap();
+    assert_eq!(file_at(&merged, "c.txt").unwrap(), "a\n");
+    assert_eq!(file_at(&merged, "a.txt"), None);
+    assert_eq!(file_at(&merged, "b.txt").unwrap(), "B\n");
+}
+
+#[test]
+fn test_merge_trees_rename_conflicts() {
+    let base = tree_of(&[("a.txt", "a\nb\n"), ("other.txt", "o\n")]);
+    let ours = tree_of(&[("b.txt", "a\nb\n"), ("other.txt", "O\n")]);
+    let theirs = tree_of(&[("c.txt", "a\nb\n"), ("other.txt", "o\n"), ("new.txt", "n\n")]);
+    let conflicts = merge_trees(&base, &ours, &theirs).unwrap_err();
+    assert!(matches!(&conflicts[..], [TreeConflict::RenameRename { .. }]));
+
+    let theirs = tree_of(&[("other.txt", "o\n")]);
+    let conflicts = merge_trees(&base, &ours, &theirs).unwrap_err();
+    assert!(matches!(&conflicts[..], [TreeConflict::RenameDelete { renamed_by: MergeSide::Ours, .. }]));
+}
+
+#[test]
+fn test_merge_trees_directory_file_conflict() {
+    let base = tree_of(&[("a.txt", "a\n")]);
+    let ours = tree_of(&[("a.txt", "a\n"), ("x", "file\n")]);
+    let theirs = tree_of(&[("a.txt", "a\n"), ("x/y.txt", "nested\n")]);
+    let conflicts = merge_trees(&base, &ours, &theirs).unwrap_err();
+    assert!(matches!(&conflicts[..], [TreeConflict::DirectoryFile { .. }]));
+}
+
+#[test]
+fn test_merge_trees_modes() {
+    let executable = |data: &str| FsObject::Blob(Blob::new(data.as_bytes()).with_executable(true));
+    let base = tree_of(&[("run.sh", "1\n2\n3\n"), ("other.txt", "o\n")]);
+    let mut ours = base.clone();
+    ours.insert_path("run.sh", executable("1\n2\n3\n"));
+    let theirs = tree_of(&[("run.sh", "1\n2\nthree\n"), ("other.txt", "o\n")]);
+    // the mode changed on one side is kept along with the content changed on the other, either way round
+    for merged in [merge_trees(&base, &ours, &theirs).unwrap(), merge_trees(&base, &theirs, &ours).unwrap()] {
+        let Some(FsObject::Blob(blob)) = merged.get_path("run.sh") else { panic!("file not merged") };
+        assert_eq!(blob.get_data(), b"1\n2\nthree\n");
+        assert_eq!(blob.get_mode(), FileMode::Executable);
+    }
+
+    let mut ours = tree_of(&[("other.txt", "o\n")]);
+    ours.insert_path("new.sh", executable("new\n"));
+    let theirs = tree_of(&[("other.txt", "O\n"), ("new.sh", "new\n")]);
+    let conflicts = merge_trees(&base, &ours, &theirs).unwrap_err();
+    assert!(matches!(&conflicts[..], [TreeConflict::Mode { ours: FileMode::Executable, theirs: FileMode::Regular, .. }]));
+}
@@ -0,0 +1,568 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use std::error::Error;
+use serde::Serialize;
+use serde_json::ser::PrettyFormatter;
+use serde_json::Value;
+
+use crate::merge::*;
+use crate::rerere::*;
+use crate::vc::*;
+
+pub const ATTRIBUTES_FILE_NAME: &str = ".vcattributes";
+pub const MERGE_ATTRIBUTE: &str = "merge";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum MergeStrategy {
+    // conflicting changes are reported as conflicts
+    #[default]
+    Recursive,
+    // conflicting changes are resolved in favour of our side
+    Ours,
+    // conflicting changes are resolved in favour of their side
+    Theirs,
+    // conflicting changes keep the lines of both sides, ours first
+    Union
+}
+
+#[derive(Debug)]
+pub struct ParseMergeStrategyError {
+    name: String
+}
+
+impl Display for ParseMergeStrategyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown merge strategy: {}", self.name)
+    }
+}
+
+impl Error for ParseMergeStrategyError {}
+
+impl FromStr for MergeStrategy {
+    type Err = ParseMergeStrategyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "recursive" | "default" => Ok(MergeStrategy::Recursive),
+            "ours" => Ok(MergeStrategy::Ours),
+            "theirs" => Ok(MergeStrategy::Theirs),
+            "union" => Ok(MergeStrategy::Union),
+            _ => Err(ParseMergeStrategyError { name: s.to_string() })
+        }
+    }
+}
+
+impl MergeStrategy {
+    // Resolves the conflicts left by a line merge, if the strategy resolves conflicts at all
+    pub fn resolve(&self, merged: &FileMerge) -> FileMerge {
+        match self {
+            MergeStrategy::Recursive => merged.clone(),
+            MergeStrategy::Ours => merged.resolve_conflicts(|_, ours, _| Some(ours.to_vec())),
+            MergeStrategy::Theirs => merged.resolve_conflicts(|_, _, theirs| Some(theirs.to_vec())),
+            MergeStrategy::Union => merged.resolve_conflicts(|_, ours, theirs| Some([ours, theirs].concat()))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MergeDriver {
+    // line based three-way merge, conflicts resolved by the merge strategy
+    Text,
+    // no content merge, either side wins only through the ours and theirs strategies
+    Binary,
+    // line based merge keeping both sides of every conflict
+    Union,
+    // key-wise merge of JSON documents
+    Json,
+    // entries added by both sides at the same place are kept, ours first
+    Changelog,
+    // runs a shell command; `%O`, `%A` and `%B` are replaced by files holding the base, ours
+    // and theirs, `%P` by the merged path. The result is read back from `%A` and
+    // the merge is clean if the command exits successfully.
+    External {
+        command: String
+    }
+}
+
+// Outcome of merging one file; `blob` holds conflict markers unless the merge is clean
+#[derive(Debug, Clone)]
+pub struct MergedFile {
+    pub blob: Blob,
+    pub clean: bool
+}
+
+impl MergedFile {
+    fn from_merge(merge: &FileMerge) -> Self {
+        Self {
+            blob: merge.to_blob(),
+            clean: merge.is_clean()
+        }
+    }
+}
+
+impl MergeDriver {
+    pub fn merge(&self, path: &Path, base: &Blob, ours: &Blob, theirs: &Blob, strategy: MergeStrategy) -> MergedFile {
+        match self {
+            MergeDriver::Text => MergedFile::from_merge(&strategy.resolve(&merge3(base, ours, theirs))),
+            MergeDriver::Union => MergedFile::from_merge(&MergeStrategy::Union.resolve(&merge3(base, ours, theirs))),
+            MergeDriver::Binary => merge_binary(base, ours, theirs, strategy),
+            MergeDriver::Json => merge_json_blobs(base, ours, theirs, strategy)
+                .unwrap_or_else(|| MergeDriver::Text.merge(path, base, ours, theirs, strategy)),
+            MergeDriver::Changelog => merge_changelog(base, ours, theirs, strategy),
+            MergeDriver::External { command } => merge_external(command, path, base, ours, theirs)
+                .unwrap_or_else(|_| MergedFile { blob: merge3(base, ours, theirs).to_blob(), clean: false })
+        }
+    }
+}
+
+fn merge_binary(base: &Blob, ours: &Blob, theirs: &Blob, strategy: MergeStrategy) -> MergedFile {
+    if ours.hash == base.hash {
+        return MergedFile { blob: theirs.clone(), clean: true }
+    }
+    if theirs.hash == base.hash || theirs.hash == ours.hash {
+        return MergedFile { blob: ours.clone(), clean: true }
+    }
+    match strategy {
+        MergeStrategy::Theirs => MergedFile { blob: theirs.clone(), clean: true },
+        MergeStrategy::Ours => MergedFile { blob: ours.clone(), clean: true },
+        _ => MergedFile { blob: ours.clone(), clean: false }
+    }
+}
+
+fn merge_changelog(base: &Blob, ours: &Blob, theirs: &Blob, strategy: MergeStrategy) -> MergedFile {
+    // entries both sides inserted at the same place are kept, without repeating shared ones
+    let merged = merge3(base, ours, theirs).resolve_conflicts(|base, ours, theirs| {
+        if !base.is_empty() {
+            return None
+        }
+        let mut lines = ours.to_vec();
+        lines.extend(theirs.iter().filter(|l| !ours.contains(l)).cloned());
+        Some(lines)
+    });
+    MergedFile::from_merge(&strategy.resolve(&merged))
+}
+
+// Returns None if any side is not valid JSON
+fn merge_json_blobs(base: &Blob, ours: &Blob, theirs: &Blob, strategy: MergeStrategy) -> Option<MergedFile> {
+    let parse = |b: &Blob| -> Option<Value> {
+        if b.get_data().is_empty() {
+            return Some(Value::Null)
+        }
+        serde_json::from_slice(b.get_data()).ok()
+    };
+    let (base_value, ours_value, theirs_value) = (parse(base)?, parse(ours)?, parse(theirs)?);
+    let merged = merge_json(Some(&base_value), Some(&ours_value), Some(&theirs_value), strategy)?;
+
+    // written back the way ours was, so that a change on one side doesn't rewrite the whole file
+    let mut data = Vec::new();
+    match (merged, json_indent(ours.get_data())) {
+        (Some(value), Some(indent)) => {
+            value.serialize(&mut serde_json::Serializer::with_formatter(&mut data, PrettyFormatter::with_indent(indent))).ok()?;
+        },
+        (Some(value), None) => serde_json::to_writer(&mut data, &value).ok()?,
+        (None, _) => {}
+    }
+    if !data.is_empty() && ours.get_data().ends_with(b"\n") {
+        data.push(b'\n');
+    }
+    Some(MergedFile { blob: Blob::new_owned(data.into_boxed_slice()), clean: true })
+}
+
+// The indentation of the first indented line, or None for a document on a single line
+fn json_indent(data: &[u8]) -> Option<&[u8]> {
+    data.split(|b| *b == b'\n')
+        .skip(1)
+        .map(|line| &line[..line.iter().take_while(|b| matches!(b, b' ' | b'\t')).count()])
+        .find(|indent| !indent.is_empty())
+}
+
+// Keys of a merged object in the order ours has them, with keys only theirs has placed
+// after the key they follow in theirs, and keys only the base has last
+fn merged_key_order<'k>(base: Option<&'k serde_json::Map<String, Value>>, ours: &'k serde_json::Map<String, Value>, theirs: &'k serde_json::Map<String, Value>) -> Vec<&'k String> {
+    let mut keys: Vec<&String> = ours.keys().collect();
+    let mut previous: Option<&String> = None;
+    for key in theirs.keys() {
+        if !ours.contains_key(key) {
+            let at = previous.and_then(|p| keys.iter().position(|k| *k == p)).map_or(0, |i| i + 1);
+            keys.insert(at, key);
+        }
+        previous = Some(key);
+    }
+    for key in base.into_iter().flat_map(|b| b.keys()) {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    keys
+}
+
+// Three-way merge of JSON values, with missing object keys as None.
+// Returns None if the values conflict and the strategy does not pick a side.
+fn merge_json(base: Option<&Value>, ours: Option<&Value>, theirs: Option<&Value>, strategy: MergeStrategy) -> Option<Option<Value>> {
+    if ours == theirs || theirs == base {
+        return Some(ours.cloned())
+    }
+    if ours == base {
+        return Some(theirs.cloned())
+    }
+    if let (Some(Value::Object(o)), Some(Value::Object(t))) = (ours, theirs) {
+        let b = match base {
+            Some(Value::Object(b)) => Some(b),
+            _ => None
+        };
+        let mut merged = serde_json::Map::new();
+        for key in merged_key_order(b, o, t) {
+            if let Some(value) = merge_json(b.and_then(|b| b.get(key)), o.get(key), t.get(key), strategy)? {
+                merged.insert(key.clone(), value);
+            }
+        }
+        return Some(Some(Value::Object(merged)))
+    }
+    match strategy {
+        MergeStrategy::Ours => Some(ours.cloned()),
+        MergeStrategy::Theirs => Some(theirs.cloned()),
+        _ => None
+    }
+}
+
+fn merge_external(command: &str, path: &Path, base: &Blob, ours: &Blob, theirs: &Blob) -> Result<MergedFile, Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let base_path = dir.path().join("base");
+    let ours_path = dir.path().join("ours");
+    let theirs_path = dir.path().join("theirs");
+    fs::write(&base_path, base.get_data())?;
+    fs::write(&ours_path, ours.get_data())?;
+    fs::write(&theirs_path, theirs.get_data())?;
+
+    let quote = |p: &Path| format!("'{}'", p.to_string_lossy().replace('\'', "'\\''"));
+    let command = command
+        .replace("%O", &quote(&base_path))
+        .replace("%A", &quote(&ours_path))
+        .replace("%B", &quote(&theirs_path))
+        .replace("%P", &quote(path));
+    let status = Command::new("sh").arg("-c").arg(&command).status()?;
+
+    let result = fs::read(&ours_path)?;
+    Ok(MergedFile {
+        blob: Blob::new_owned(result.into_boxed_slice()),
+        clean: status.success()
+    })
+}
+
+// Matches `text` against a glob where `*` and `?` stop at `/` and `**` matches anything
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(p: &[u8], t: &[u8]) -> bool {
+        match p.first() {
+            None => t.is_empty(),
+            Some(b'*') if p.get(1) == Some(&b'*') => {
+                let rest = p[2..].strip_prefix(b"/").unwrap_or(&p[2..]);
+                (0..=t.len()).any(|i| matches(rest, &t[i..]))
+            },
+            Some(b'*') => {
+                let limit = t.iter().position(|c| *c == b'/').unwrap_or(t.len());
+                (0..=limit).any(|i| matches(&p[1..], &t[i..]))
+            },
+            Some(b'?') => !t.is_empty() && t[0] != b'/' && matches(&p[1..], &t[1..]),
+            Some(c) => t.first() == Some(c) && matches(&p[1..], &t[1..])
+        }
+    }
+    matches(pattern.as_bytes(), text.as_bytes())
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct AttributeRule {
+    pattern: String,
+    attributes: HashMap<String, String>
+}
+
+impl AttributeRule {
+    // Patterns without a slash match the file name in any directory
+    fn matches(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        let pattern = self.pattern.trim_start_matches('/');
+        if self.pattern.contains('/') {
+            glob_match(pattern, &path)
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(&path);
+            glob_match(pattern, name)
+        }
+    }
+}
+
+// Per-path attributes in the format of `.vcattributes`: every line holds a pattern followed by
+// `key=value`, `key` (set to "true") or `-key` (set to "false") entries. Later lines win.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Attributes {
+    rules: Vec<AttributeRule>
+}
+
+impl Attributes {
+    pub fn parse(text: &str) -> Self {
+        let rules = text.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| {
+                let mut fields = l.split_whitespace();
+                let pattern = fields.next()?.to_string();
+                let attributes = fields.map(|f| match f.split_once('=') {
+                    Some((k, v)) => (k.to_string(), v.to_string()),
+                    None => match f.strip_prefix('-') {
+                        Some(k) => (k.to_string(), "false".to_string()),
+                        None => (f.to_string(), "true".to_string())
+                    }
+                }).collect();
+                Some(AttributeRule { pattern, attributes })
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn from_file<P>(path: P) -> Result<Self, std::io::Error>
+    where P: AsRef<Path>
+    {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    // Reads the attributes file at the root of a tree, if it has one
+    pub fn from_tree(tree: &Tree) -> Self {
+        match tree.get_path(ATTRIBUTES_FILE_NAME) {
+            Some(FsObject::Blob(b)) => Self::parse(&b.get_data_as_string()),
+            _ => Self::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn get<P>(&self, path: P, attribute: &str) -> Option<&str>
+    where P: AsRef<Path>
+    {
+        self.rules.iter().rev()
+            .filter(|r| r.matches(path.as_ref()))
+            .find_map(|r| r.attributes.get(attribute))
+            .map(|v| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MergeOptions {
+    pub strategy: MergeStrategy,
+    pub attributes: Attributes,
+    // drivers by the name used in `merge=<name>` attributes
+    pub drivers: HashMap<Name, MergeDriver>,
+    // recorded resolutions applied to conflicts seen before
+    pub rerere: Option<Rerere>
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        let drivers = [
+            ("text", MergeDriver::Text),
+            ("binary", MergeDriver::Binary),
+            ("union", MergeDriver::Union),
+            ("json", MergeDriver::Json),
+            ("changelog", MergeDriver::Changelog)
+        ].into_iter().map(|(name, driver)| (name.to_string(), driver)).collect();
+
+        Self {
+            strategy: MergeStrategy::default(),
+            attributes: Attributes::default(),
+            drivers,
+            rerere: None
+        }
+    }
+}
+
+impl MergeOptions {
+    pub fn with_strategy(strategy: MergeStrategy) -> Self {
+        Self {
+            strategy,
+            ..Self::default()
+        }
+    }
+
+    pub fn register_driver(&mut self, name: &str, driver: MergeDriver) {
+        self.drivers.insert(name.to_string(), driver);
+    }
+
+    // The driver named by the path's merge attribute, falling back to the text driver
+    pub fn get_driver<P>(&self, path: P) -> &MergeDriver
+    where P: AsRef<Path>
+    {
+        self.attributes.get(path, MERGE_ATTRIBUTE)
+            .and_then(|name| self.drivers.get(name))
+            .unwrap_or(&MergeDriver::Text)
+    }
+
+    pub fn merge_file(&self, path: &Path, base: Option<&Blob>, ours: &Blob, theirs: &Blob) -> MergedFile {
+        let empty = Blob::new(b"");
+        let merged = self.get_driver(path).merge(path, base.unwrap_or(&empty), ours, theirs, self.strategy);
+        // only conflict markers can be resolved, a binary or external driver's conflict stays as it is
+        match &self.rerere {
+            Some(rerere) if !merged.clean && !parse_conflicts(&merged.blob).is_clean() => {
+                let (blob, unresolved) = rerere.resolve(&merged.blob);
+                MergedFile { blob, clean: unresolved == 0 }
+            },
+            _ => merged
+        }
+    }
+}
+
+#[cfg(test)]
+fn conflicting_blobs() -> (Blob, Blob, Blob) {
+    (Blob::new(b"a\nb\nc\n"), Blob::new(b"a\nours\nc\n"), Blob::new(b"a\ntheirs\nc\n"))
+}
+
+#[test]
+fn test_merge_strategies() {
+    let (base, ours, theirs) = conflicting_blobs();
+    let path = Path::new("file.txt");
+    let merge = |strategy: MergeStrategy| MergeDriver::Text.merge(path, &base, &ours, &theirs, strategy);
+
+    assert!(!merge(MergeStrategy::Recursive).clean);
+    assert_eq!(merge(MergeStrategy::Ours).blob.get_data(), ours.get_data());
+    assert_eq!(merge(MergeStrategy::Theirs).blob.get_data(), theirs.get_data());
+    let union = merge(MergeStrategy::Union);
+    assert!(union.clean);
+    assert_eq!(union.blob.get_data(), b"a\nours\ntheirs\nc\n");
+    assert_eq!("default".parse::<MergeStrategy>().unwrap(), MergeStrategy::Recursive);
+    assert!("octopus".parse::<MergeStrategy>().is_err());
+}
+
+#[test]
+fn test_changelog_driver() {
+    let base = Blob::new(b"# Changelog\n- old entry\n");
+    let ours = Blob::new(b"# Changelog\n- ours\n- shared\n- old entry\n");
+    let theirs = Blob::new(b"# Changelog\n- shared\n- theirs\n- old entry\n");
+    let merged = MergeDriver::Changelog.merge(Path::new("CHANGELOG.md"), &base, &ours, &theirs, MergeStrategy::Recursive);
+    assert!(merged.clean);
+    assert_eq!(merged.blob.get_data_as_string(), "# Changelog\n- ours\n- shared\n- theirs\n- old entry\n");
+}
+
+#[test]
+fn test_json_driver() {
+    let base = Blob::new(b"{\"a\": 1, \"b\": {\"x\": 1}}\n");
+    let ours = Blob::new(b"{\"a\": 2, \"b\": {\"x\": 1}}\n");
+    let theirs = Blob::new(b"{\"a\": 1, \"b\": {\"x\": 1, \"y\": 2}, \"c\": 3}\n");
+    let merged = MergeDriver::Json.merge(Path::new("package.json"), &base, &ours, &theirs, MergeStrategy::Recursive);
+    assert!(merged.clean);
+    let value: Value = serde_json::from_slice(merged.blob.get_data()).unwrap();
+    assert_eq!(value, serde_json::json!({"a": 2, "b": {"x": 1, "y": 2}, "c": 3}));
+
+    let theirs = Blob::new(b"{\"a\": 3, \"b\": {\"x\": 1}}\n");
+    let path = Path::new("package.json");
+    assert!(!MergeDriver::Json.merge(path, &base, &ours, &theirs, MergeStrategy::Recursive).clean);
+    let merged = MergeDriver::Json.merge(path, &base, &ours, &theirs, MergeStrategy::Theirs);
+    let value: Value = serde_json::from_slice(merged.blob.get_data()).unwrap();
+    assert_eq!(value["a"], 3);
+}
+
+#[test]
+fn test_json_driver_keeps_layout() {
+    let base = Blob::new(b"{\n    \"name\": \"app\",\n    \"version\": \"1.0.0\",\n    \"dependencies\": {\n        \"zlib\": \"1\",\n        \"abc\": \"2\"\n    }\n}\n");
+    let ours = Blob::new(b"{\n    \"name\": \"app\",\n    \"version\": \"1.1.0\",\n    \"dependencies\": {\n        \"zlib\": \"1\",\n        \"abc\": \"2\"\n    }\n}\n");
+    let theirs = Blob::new(b"{\n    \"name\": \"app\",\n    \"version\": \"1.0.0\",\n    \"dependencies\": {\n        \"zlib\": \"1\",\n        \"new\": \"3\",\n        \"abc\": \"2\"\n    }\n}\n");
+    let merged = MergeDriver::Json.merge(Path::new("package.json"), &base, &ours, &theirs, MergeStrategy::Recursive);
+    assert!(merged.clean);
+    // untouched keys keep their unsorted order and the added key lands where theirs put it
+    assert_eq!(
+        merged.blob.get_data_as_string(),
+        "{\n    \"name\": \"app\",\n    \"version\": \"1.1.0\",\n    \"dependencies\": {\n        \"zlib\": \"1\",\n        \"new\": \"3\",\n        \"abc\": \"2\"\n    }\n}\n"
+    );
+}
+
+#[test]
+fn test_external_driver() {
+    let (base, ours, theirs) = conflicting_blobs();
+    let path = Path::new("file.txt");
+    let driver = MergeDriver::External { command: "cat %O %B > %A".to_string() };
+    let merged = driver.merge(path, &base, &ours, &theirs, MergeStrategy::Recursive);
+    assert!(merged.clean);
+    assert_eq!(merged.blob.get_data(), b"a\nb\nc\na\ntheirs\nc\n");
+
+    let driver = MergeDriver::External { command: "exit 1".to_string() };
+    let merged = driver.merge(path, &base, &ours, &theirs, MergeStrategy::Recursive);
+    assert!(!merged.clean);
+    assert_eq!(merged.blob.get_data(), ours.get_data());
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*.lock", "Cargo.lock"));
+    assert!(!glob_match("*.lock", "dir/Cargo.lock"));
+    assert!(glob_match("docs/*.md", "docs/a.md"));
+    assert!(!glob_match("docs/*.md", "docs/sub/a.md"));
+    assert!(glob_match("docs/**/*.md", "docs/sub/a.md"));
+    assert!(glob_match("docs/**/*.md", "docs/a.md"));
+    assert!(glob_match("file?.txt", "file1.txt"));
+}
+
+#[test]
+fn test_attributes() {
+    let attributes = Attributes::parse("# comment\n*.json merge=json\nCargo.lock merge=ours -diff\nvendor/** merge=binary\n*.json merge=union\n");
+    assert_eq!(attributes.get("a/b.json", MERGE_ATTRIBUTE), Some("union"));
+    assert_eq!(attributes.get("sub/Cargo.lock", MERGE_ATTRIBUTE), Some("ours"));
+    assert_eq!(attributes.get("sub/Cargo.lock", "diff"), Some("false"));
+    assert_eq!(attributes.get("vendor/x/y.c", MERGE_ATTRIBUTE), Some("binary"));
+    assert_eq!(attributes.get("main.rs", MERGE_ATTRIBUTE), None);
+}
+
+#[test]
+fn test_merge_trees_with_drivers() {
+    let attributes = "CHANGELOG merge=changelog\n*.lock merge=lockfile\n";
+    let base = tree_of(&[(ATTRIBUTES_FILE_NAME, attributes), ("CHANGELOG", "v1\n"), ("deps.lock", "a\n"), ("gone.txt", "g\n")]);
+    let ours = tree_of(&[(ATTRIBUTES_FILE_NAME, attributes), ("CHANGELOG", "v2 ours\nv1\n"), ("deps.lock", "b\n"), ("gone.txt", "G\n")]);
+    let theirs = tree_of(&[(ATTRIBUTES_FILE_NAME, attributes), ("CHANGELOG", "v2 theirs\nv1\n"), ("deps.lock", "c\n")]);
+
+    // the attributes file of the trees picks the changelog driver, the lockfile one isn't registered
+    let conflicts = merge_trees(&base, &ours, &theirs).unwrap_err();
+    let mut paths: Vec<&Path> = conflicts.iter().map(|c| c.get_path()).collect();
+    paths.sort();
+    assert_eq!(paths, vec![Path::new("deps.lock"), Path::new("gone.txt")]);
+
+    let mut options = MergeOptions::with_strategy(MergeStrategy::Theirs);
+    options.register_driver("lockfile", MergeDriver::External { command: "echo regenerated > %A".to_string() });
+    let merged = merge_trees_with(&base, &ours, &theirs, &options).unwrap();
+    let file_at = |path: &str| match merged.get_path(path) {
+        Some(FsObject::Blob(b)) => Some(b.get_data_as_string()),
+        _ => None
+    };
+    assert_eq!(file_at("CHANGELOG").unwrap(), "v2 ours\nv2 theirs\nv1\n");
+    assert_eq!(file_at("deps.lock").unwrap(), "regenerated\n");
+    assert_eq!(file_at("gone.txt"), None);
+}
+
+#[test]
+fn test_rerere_in_tree_merge() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = |data: &str| FsObject::Blob(Blob::new(data.as_bytes()));
+    let base = Tree::new([("a.txt".to_string(), file("x = 1\n"))].into());
+    let ours = Tree::new([("a.txt".to_string(), file("x = 2\n"))].into());
+    let theirs = Tree::new([("a.txt".to_string(), file("x = 3\n"))].into());
+
+    let conflicts = merge_trees(&base, &ours, &theirs).unwrap_err();
+    let resolved = Tree::new([("a.txt".to_string(), file("x = 4\n"))].into());
+    let rerere = Rerere::new(dir.path());
+    assert_eq!(rerere.record_tree(&conflicts, &resolved).unwrap(), 1);
+
+    let options = MergeOptions { rerere: Some(rerere), ..Default::default() };
+    let merged = merge_trees_with(&base, &ours, &theirs, &options).unwrap();
+    let Some(FsObject::Blob(merged)) = merged.get_path("a.txt") else { panic!() };
+    assert_eq!(merged.get_data(), b"x = 4\n");
+}
+
+#[test]
+fn test_rerere_keeps_binary_conflicts() {
+    let dir = tempfile::tempdir().unwrap();
+    let (base, ours, theirs) = conflicting_blobs();
+    let mut options = MergeOptions { rerere: Some(Rerere::new(dir.path())), ..Default::default() };
+    options.attributes = Attributes::parse("*.bin merge=binary\n");
+    let merged = options.merge_file(Path::new("data.bin"), Some(&base), &ours, &theirs);
+    assert!(!merged.clean);
+    assert_eq!(merged.blob.get_data(), ours.get_data());
+}
@@ -1,6 +1,5 @@
-use std::{fmt::Display, path::PathBuf, time::SystemTime, error::Error};
-use digest::{Digest, generic_array::GenericArray};
-use sha2::Sha256;
+use std::{fmt::Display, error::Error};
+use digest::Digest;
 use crate::hashing::*;
 
 #[derive(Debug)]
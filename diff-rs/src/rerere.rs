@@ -0,0 +1,201 @@
+use std::path::{Path, PathBuf};
+use std::error::Error;
+use std::fs;
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::*;
+use crate::line_diff::*;
+use crate::merge::*;
+use crate::util::*;
+use crate::vc::*;
+use crate::vc_serialize::*;
+
+// Directory of the repository holding the recorded resolutions
+pub const RERERE_DIR_NAME: &str = "rr-cache";
+
+// A recorded conflict and the lines it was resolved to.
+// The sides are normalized and sorted, so a conflict is recognized whichever branch it is merged from.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RerereRecord {
+    pub preimage: [String; 2],
+    pub postimage: String
+}
+impl SerializeDeserializeJson for RerereRecord {}
+
+// Reuses recorded resolutions: conflict hunks are keyed by a hash of their normalized sides
+#[derive(Debug, Clone)]
+pub struct Rerere {
+    dir: PathBuf
+}
+
+impl Rerere {
+    pub fn new<P>(dir: P) -> Self
+    where P: AsRef<Path>
+    {
+        Self { dir: dir.as_ref().to_path_buf() }
+    }
+
+    pub fn get_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn conflict_id(ours: &[Line], theirs: &[Line]) -> VcHashString {
+        let [a, b] = normalize_sides(ours, theirs);
+        let mut data = a.into_bytes();
+        data.push(0);
+        data.extend_from_slice(b.as_bytes());
+        hash_to_hex_string(&hash::<VcHasher>(&data))
+    }
+
+    pub fn get_record(&self, ours: &[Line], theirs: &[Line]) -> Option<RerereRecord> {
+        let path = self.dir.join(Self::conflict_id(ours, theirs));
+        let record = RerereRecord::deserialize_json_from_file(path).ok()?;
+        (record.preimage == normalize_sides(ours, theirs)).then_some(record)
+    }
+
+    // Records how the conflicts of `conflicted` (a file holding conflict markers) were resolved in `resolved`.
+    // Conflicts are located in the resolved file by the unchanged lines around them; conflicts which cannot be
+    // told apart or which still hold markers are skipped. Returns the number of recorded resolutions.
+    pub fn record(&self, conflicted: &Blob, resolved: &Blob) -> Result<usize, Box<dyn Error>> {
+        let merge = parse_conflicts(conflicted);
+        let mut conflicts = Vec::new();
+        // the conflicted file with every conflict standing in as a line which matches nothing
+        let mut old: Vec<Option<&[u8]>> = Vec::new();
+        for chunk in &merge.chunks {
+            match chunk {
+                MergeChunk::Resolved(lines) => old.extend(lines.iter().map(|l| Some(&**l))),
+                MergeChunk::Conflict { ours, theirs, .. } => {
+                    conflicts.push((old.len(), ours, theirs));
+                    old.push(None);
+                }
+            }
+        }
+        let new: Vec<Option<&[u8]>> = split_lines_inclusive(resolved.get_data()).into_iter().map(Some).collect();
+
+        let mut resolutions = Vec::new();
+        let mut replaced: Vec<usize> = Vec::new();
+        let mut inserted: Vec<&[u8]> = Vec::new();
+        for op in diff_lines(&old, &new).iter().chain(std::iter::once(&DiffOp::Equal { old_index: 0, new_index: 0 })) {
+            match op {
+                DiffOp::Delete { old_index } => {
+                    if old[*old_index].is_none() {
+                        replaced.push(*old_index);
+                    }
+                },
+                DiffOp::Insert { new_index } => inserted.push(new[*new_index].unwrap_or_default()),
+                DiffOp::Equal { .. } => {
+                    if let [old_index] = replaced[..] {
+                        resolutions.push((old_index, std::mem::take(&mut inserted)));
+                    }
+                    replaced.clear();
+                    inserted.clear();
+                }
+            }
+        }
+
+        let mut count = 0;
+        for (old_index, lines) in resolutions {
+            let Some((_, ours, theirs)) = conflicts.iter().find(|(i, _, _)| *i == old_index) else { continue };
+            if lines.iter().any(|l| is_conflict_marker(l)) {
+                continue
+            }
+            let Ok(postimage) = String::from_utf8(lines.concat()) else { continue };
+            let record = RerereRecord { preimage: normalize_sides(ours, theirs), postimage };
+            fs::create_dir_all(&self.dir)?;
+            write_atomic(self.dir.join(Self::conflict_id(ours, theirs)), record.serialize_json()?.as_bytes())?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Records the resolutions of the content conflicts of a tree merge, as committed in `resolved`
+    pub fn record_tree(&self, conflicts: &[TreeConflict], resolved: &Tree) -> Result<usize, Box<dyn Error>> {
+        let mut count = 0;
+        for conflict in conflicts {
+            let (TreeConflict::Content { path, merged, .. } | TreeConflict::AddAdd { path, merged, .. }) = conflict else { continue };
+            if let Some(FsObject::Blob(blob)) = resolved.get_path(path) {
+                count += self.record(merged, blob)?;
+            }
+        }
+        Ok(count)
+    }
+
+    // Applies recorded resolutions to the conflicts of `conflicted`.
+    // Returns the file along with the number of conflicts left in it.
+    pub fn resolve(&self, conflicted: &Blob) -> (Blob, usize) {
+        let merge = parse_conflicts(conflicted);
+        if merge.is_clean() {
+            return (conflicted.clone(), 0)
+        }
+        let merge = merge.resolve_conflicts(|_, ours, theirs| {
+            let record = self.get_record(ours, theirs)?;
+            Some(split_lines_inclusive(record.postimage.as_bytes()).into_iter().map(Line::from).collect())
+        });
+        (merge.to_blob(), merge.conflict_count())
+    }
+}
+
+// Line endings and a missing final newline do not make a conflict different
+fn normalize_side(lines: &[Line]) -> String {
+    let mut side = String::from_utf8_lossy(&lines.concat()).replace("\r\n", "\n");
+    if !side.is_empty() && !side.ends_with('\n') {
+        side.push('\n');
+    }
+    side
+}
+
+fn normalize_sides(ours: &[Line], theirs: &[Line]) -> [String; 2] {
+    let mut sides = [normalize_side(ours), normalize_side(theirs)];
+    sides.sort();
+    sides
+}
+
+fn is_conflict_marker(line: &[u8]) -> bool {
+    [CONFLICT_MARKER_OURS, CONFLICT_MARKER_SEPARATOR, CONFLICT_MARKER_THEIRS].iter()
+        .any(|marker| line.starts_with(&marker.as_bytes()[..7]))
+}
+
+#[cfg(test)]
+fn lines(data: &str) -> Vec<Line> {
+    split_lines_inclusive(data.as_bytes()).into_iter().map(Line::from).collect()
+}
+
+#[test]
+fn test_conflict_id_is_normalized() {
+    let id = Rerere::conflict_id(&lines("a\n"), &lines("b\n"));
+    assert_eq!(id, Rerere::conflict_id(&lines("b\n"), &lines("a\n")));
+    assert_eq!(id, Rerere::conflict_id(&lines("a\r\n"), &lines("b")));
+    assert_ne!(id, Rerere::conflict_id(&lines("a\n"), &lines("c\n")));
+}
+
+#[test]
+fn test_rerere_record_and_resolve() {
+    let dir = tempfile::tempdir().unwrap();
+    let rerere = Rerere::new(dir.path());
+
+    let base = Blob::new(b"one\nvalue = 1\nthree\n");
+    let ours = Blob::new(b"one\nvalue = 2\nthree\n");
+    let theirs = Blob::new(b"one\nvalue = 3\nthree\n");
+    let conflicted = merge3(&base, &ours, &theirs).to_blob();
+    assert_eq!(rerere.resolve(&conflicted).1, 1);
+
+    let resolved = Blob::new(b"one\nvalue = 5\nthree\n");
+    assert_eq!(rerere.record(&conflicted, &resolved).unwrap(), 1);
+
+    // the same conflict in another context and with the sides swapped
+    let base = Blob::new(b"zero\none\nvalue = 1\nthree\n");
+    let ours = Blob::new(b"zero\none\nvalue = 3\nthree\n");
+    let theirs = Blob::new(b"zero\none\nvalue = 2\nthree\n");
+    let (blob, remaining) = rerere.resolve(&merge3(&base, &ours, &theirs).to_blob());
+    assert_eq!(remaining, 0);
+    assert_eq!(blob.get_data(), b"zero\none\nvalue = 5\nthree\n");
+}
+
+#[test]
+fn test_rerere_skips_unresolved() {
+    let dir = tempfile::tempdir().unwrap();
+    let rerere = Rerere::new(dir.path());
+    let conflicted = merge3(&Blob::new(b"a\n"), &Blob::new(b"b\n"), &Blob::new(b"c\n")).to_blob();
+    assert_eq!(rerere.record(&conflicted, &conflicted).unwrap(), 0);
+    assert_eq!(rerere.resolve(&conflicted).0.get_data(), conflicted.get_data());
+}
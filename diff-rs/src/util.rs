@@ -1,5 +1,9 @@
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+// Prefix of the temporary files atomic writes go through, which listings of stored files skip
+pub const TEMP_FILE_PREFIX: &str = ".tmp-";
 
 pub fn read_lines<P>(filename: P) -> std::io::Result<Vec<String>>
 where
@@ -12,4 +16,88 @@ where
         lines.push(line?);
     }
     Ok(lines)
-}
\ No newline at end of file
+}
+
+pub fn write_atomic<P>(path: P, data: &[u8]) -> std::io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    write_atomic_with(path, |f| f.write_all(data))
+}
+
+// Writes a file so that it holds either its old or its new contents, whenever the process or the machine dies:
+// `write` fills a temporary file in the same directory, which is synced to disk and renamed over the target,
+// and the directory is synced so that the rename itself is durable. If `write` fails, the target is untouched.
+pub fn write_atomic_with<P, F>(path: P, write: F) -> std::io::Result<()>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut File) -> std::io::Result<()>,
+{
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut temp = tempfile::Builder::new().prefix(TEMP_FILE_PREFIX).tempfile_in(dir)?;
+    write(temp.as_file_mut())?;
+    temp.as_file().sync_all()?;
+    temp.persist(path).map_err(|e| e.error)?;
+    sync_dir(dir)
+}
+
+// Makes the entries of a directory durable, i.e. files created or renamed in it
+#[cfg(unix)]
+pub fn sync_dir<P>(dir: P) -> std::io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    File::open(dir)?.sync_all()
+}
+
+// Directories can't be opened for syncing on other platforms, where renames are durable by themselves
+#[cfg(not(unix))]
+pub fn sync_dir<P>(_dir: P) -> std::io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    Ok(())
+}
+
+pub fn is_temp_file_name(name: &str) -> bool {
+    name.starts_with(TEMP_FILE_PREFIX)
+}
+
+#[cfg(test)]
+fn dir_entries(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_write_atomic_replaces_contents() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    write_atomic(&path, b"a much longer first version").unwrap();
+    write_atomic(&path, b"short").unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"short");
+    assert_eq!(dir_entries(tempdir.path()), vec!["file"]);
+}
+
+#[test]
+fn test_write_atomic_crash_during_write() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    write_atomic(&path, b"old").unwrap();
+
+    // a writer dying half way leaves the old contents in place and no temporary file behind
+    let result = write_atomic_with(&path, |f| {
+        f.write_all(b"new but incompl")?;
+        Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "crash"))
+    });
+    assert!(result.is_err());
+    assert_eq!(std::fs::read(&path).unwrap(), b"old");
+    assert_eq!(dir_entries(tempdir.path()), vec!["file"]);
+}
@@ -1,89 +1,190 @@
 use std::collections::HashMap;
-use std::fmt::Write;
-use std::io::{Read, Write as IoWrite};
+use std::fmt::Debug;
 use std::path::Path;
-use std::fs::{File, OpenOptions};
-use std::{fmt::Display, path::PathBuf, time::SystemTime, error::Error};
-use digest::{Digest, generic_array::GenericArray};
+use std::{path::PathBuf, time::SystemTime, error::Error};
+use digest::Digest;
 use sha2::Sha256;
 
 use crate::hashing::*;
 use crate::merkle::*;
-use crate::util::*;
+use crate::vc_binary::*;
+use crate::vc_object::*;
+use crate::vc_store::*;
+use crate::vc_serialize::*;
+
+// A digest objects can be named by. Each repository records the one its objects are hashed with in its config.
+pub trait VcDigest : Digest + Clone + Debug + 'static {
+    // name of the digest in the repository config
+    const NAME: &'static str;
+}
+
+impl VcDigest for md5::Md5 {
+    const NAME: &'static str = "md5";
+}
+
+// git-compatible
+impl VcDigest for sha1::Sha1 {
+    const NAME: &'static str = "sha1";
+}
+
+impl VcDigest for sha2::Sha224 {
+    const NAME: &'static str = "sha224";
+}
 
+impl VcDigest for Sha256 {
+    const NAME: &'static str = "sha256";
+}
+
+impl VcDigest for sha2::Sha384 {
+    const NAME: &'static str = "sha384";
+}
+
+impl VcDigest for sha2::Sha512 {
+    const NAME: &'static str = "sha512";
+}
+
+// Names of all digests a repository can be hashed with
+impl VcDigest for sha3::Sha3_256 {
+    const NAME: &'static str = "sha3-256";
+}
+
+impl VcDigest for sha3::Sha3_512 {
+    const NAME: &'static str = "sha3-512";
+}
+
+impl VcDigest for blake2::Blake2b512 {
+    const NAME: &'static str = "blake2b";
+}
+
+impl VcDigest for blake2::Blake2s256 {
+    const NAME: &'static str = "blake2s";
+}
+
+impl VcDigest for blake3::Hasher {
+    const NAME: &'static str = "blake3";
+}
+
+pub const VC_DIGEST_NAMES: [&str; 11] = [
+    md5::Md5::NAME, sha1::Sha1::NAME, sha2::Sha224::NAME, Sha256::NAME, sha2::Sha384::NAME, sha2::Sha512::NAME,
+    sha3::Sha3_256::NAME, sha3::Sha3_512::NAME, blake2::Blake2b512::NAME, blake2::Blake2s256::NAME, blake3::Hasher::NAME
+];
+
+// The digest of repositories which don't say otherwise. Objects and stores default to it.
 pub type VcHasher = Sha256;
-pub type VcHash = DigestByteArray<VcHasher>;
+pub type VcHash<D = VcHasher> = DigestByteArray<D>;
 pub type VcHashString = String;
 pub type Name = String;
 
-pub trait VcHashId {
-    fn get_hash_bytes(&self) -> VcHash;
+// Version of the on-disk object format. Version 0 trees were hashed from their children's hashes alone,
+// without names, types or modes, and version 1 objects were hashed without an object header,
+// so their hashes can't be verified against the current encoding.
+pub const REPOSITORY_FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FileMode {
+    Regular,
+    Executable,
+    Directory
+}
+
+impl FileMode {
+    // git-compatible mode bits
+    pub fn as_octal(&self) -> u32 {
+        match self {
+            FileMode::Regular => 0o100644,
+            FileMode::Executable => 0o100755,
+            FileMode::Directory => 0o040000
+        }
+    }
+}
+
+pub trait VcHashId<D: VcDigest = VcHasher> {
+    fn get_hash_bytes(&self) -> VcHash<D>;
     fn get_hash_str(&self) -> VcHashString;
 }
 
-#[derive(Debug)]
-pub struct Blob {
+#[derive(Debug, Clone)]
+pub struct Blob<D: VcDigest = VcHasher> {
     pub data: Box<[u8]>,
-    pub hash: VcHash
+    pub hash: VcHash<D>,
+    // only part of the mode trees list the blob under, not of its hash
+    pub executable: bool
 }
 
-#[derive(Debug)]
-pub struct Tree {
-    pub listings: HashMap<Name, FsObject>,
-    pub hash: VcHash
+#[derive(Debug, Clone)]
+pub struct Tree<D: VcDigest = VcHasher> {
+    pub listings: HashMap<Name, FsObject<D>>,
+    pub hash: VcHash<D>
 }
 
 #[derive(Debug)]
-pub struct Commit<'a> {
-    pub tree: Tree,
-    pub hash: VcHash,
-    pub parent: Option<&'a Commit<'a>>,
+pub struct Commit<'a, D: VcDigest = VcHasher> {
+    pub tree: Tree<D>,
+    pub hash: VcHash<D>,
+    pub parent: Option<&'a Commit<'a, D>>,
+    // kept apart from `parent`, as a commit loaded on its own has its parent's hash but not the parent
+    pub parent_hash: Option<VcHash<D>>,
     pub author: String,
     pub message: String,
     pub timestamp: SystemTime
 }
 
-#[derive(Debug)]
-pub enum FsObject {
-    Blob(Blob),
-    Tree(Tree)
+#[derive(Debug, Clone)]
+pub enum FsObject<D: VcDigest = VcHasher> {
+    Blob(Blob<D>),
+    Tree(Tree<D>)
 }
 
 #[derive(Debug)]
-pub enum VcObject<'a> {
-    FsObject(FsObject),
-    Commit(Commit<'a>)
+pub enum VcObject<'a, D: VcDigest = VcHasher> {
+    FsObject(FsObject<D>),
+    Commit(Commit<'a, D>)
 }
 
 #[derive(Debug)]
-pub enum HeadRef<'a> {
+pub enum HeadRef<'a, D: VcDigest = VcHasher> {
     Tag(Name),
     Head(Name),
-    Commit(&'a Commit<'a>)
+    Commit(&'a Commit<'a, D>)
 }
 
 #[derive(Debug)]
-pub struct Index<'a> {
+pub struct Index<'a, D: VcDigest = VcHasher> {
     pub root_path: &'a Path,
-    pub tags: HashMap<&'a str, &'a Commit<'a>>,
-    pub heads: HashMap<&'a str, &'a Commit<'a>>,
-    pub objects: HashMap<VcHash, VcObject<'a>>,
-    pub head: HeadRef<'a>
+    pub tags: HashMap<&'a str, &'a Commit<'a, D>>,
+    pub heads: HashMap<&'a str, &'a Commit<'a, D>>,
+    pub objects: HashMap<VcHash<D>, VcObject<'a, D>>,
+    pub head: HeadRef<'a, D>
 }
 
-impl Blob {
+impl<D: VcDigest> Blob<D> {
     pub fn new(data: &[u8]) -> Self {
         Self {
             data: data.into(),
-            hash: hash::<VcHasher>(data)
+            hash: object_hash::<D>(ObjectType::Blob, data),
+            executable: false
         }
     }
 
     pub fn new_owned(data: Box<[u8]>) -> Self {
-        let hash = hash::<VcHasher>(&data);
+        let hash = object_hash::<D>(ObjectType::Blob, &data);
         Self {
             data,
-            hash
+            hash,
+            executable: false
+        }
+    }
+
+    pub fn with_executable(mut self, executable: bool) -> Self {
+        self.executable = executable;
+        self
+    }
+
+    pub fn get_mode(&self) -> FileMode {
+        if self.executable {
+            FileMode::Executable
+        } else {
+            FileMode::Regular
         }
     }
 
@@ -92,48 +193,77 @@ impl Blob {
     }
 
     pub fn get_data_as_string(&self) -> String {
-        String::from_utf8_lossy(&self.get_data()).to_string()
+        String::from_utf8_lossy(self.get_data()).to_string()
     }
 
     pub fn get_data_as_lines(&self) -> Vec<String> {
         self.get_data_as_string().lines().map(|s| s.to_string()).collect()
     }
 
-    pub fn from_file<P>(path: P) -> Result<Self, std::io::Error>
+    pub fn from_file<P>(path: P) -> Result<Self, ObjectError>
     where P: AsRef<Path>
     {
-        let mut f = File::open(path)?;
-        let mut buf: Vec<u8> = Vec::new();
-        let _filesize = f.read_to_end(&mut buf)?;
-        Ok(Self::new(&buf))
+        Ok(Self::new_owned(read_object_of_type(path, ObjectType::Blob)?))
     }
 
-    pub fn to_file<P>(&self, parent_path: P) -> Result<(), std::io::Error>
+    // Reads a file of the working tree as a blob, executable if any of its execute permission bits is set.
+    // Only Unix has those bits; elsewhere files are never executable.
+    pub fn from_working_file<P>(path: P) -> Result<Self, ObjectError>
     where P: AsRef<Path>
     {
-        let path = PathBuf::from(parent_path.as_ref()).join(hash_to_hex_string(&self.get_hash()));
-        if Path::exists(&path) { // assume if file exists with same hash name, it contains the same data
-            return Ok(())
-        }
-        let mut f = File::create(path)?;
-        f.write_all(&self.get_data())?;
-        Ok(())
+        let metadata = std::fs::metadata(path.as_ref())?;
+        Ok(Self::new_owned(std::fs::read(path.as_ref())?.into()).with_executable(is_executable(&metadata)))
+    }
+
+    // The hash a file of the working tree would be stored under as a blob, read a buffer at a time
+    pub fn hash_file<P>(path: P) -> Result<VcHash<D>, ObjectError>
+    where P: AsRef<Path>
+    {
+        let f = std::fs::File::open(path.as_ref())?;
+        let len = f.metadata()?.len();
+        object_hash_reader::<D, _>(ObjectType::Blob, len, f)?.ok_or_else(|| {
+            // the file was truncated while it was read
+            let actual = std::fs::metadata(path.as_ref()).map_or(0, |m| m.len() as usize);
+            ObjectError::LengthMismatch { path: path.as_ref().to_path_buf(), expected: len as usize, actual }
+        })
+    }
+
+    pub fn to_file<P>(&self, parent_path: P) -> Result<(), ObjectError>
+    where P: AsRef<Path>
+    {
+        self.to_file_with(parent_path, Compression::default())
+    }
+
+    pub fn to_file_with<P>(&self, parent_path: P, compression: Compression) -> Result<(), ObjectError>
+    where P: AsRef<Path>
+    {
+        self.to_store(&mut FsObjectStore::with_compression(parent_path, compression))
+    }
+
+    pub fn to_store(&self, store: &mut dyn ObjectStore<D>) -> Result<(), ObjectError> {
+        store.put(&self.hash, ObjectType::Blob, self.get_data())
+    }
+
+    pub fn from_store(store: &dyn ObjectStore<D>, hash: &VcHash<D>) -> Result<Self, ObjectError> {
+        let blob = Self::new_owned(store.get_of_type(hash, ObjectType::Blob)?);
+        check_hash(&store.location(hash), &hash_to_hex_string(hash), &blob.get_hash())?;
+        Ok(blob)
     }
 }
 
 
-impl MerkleNode<VcHasher> for Blob {
-    fn get_hash(&self) -> VcHash {
-        hash::<VcHasher>(&self.data)
+impl<D: VcDigest> MerkleNode<D> for Blob<D> {
+    fn get_hash(&self) -> VcHash<D> {
+        object_hash::<D>(ObjectType::Blob, &self.data)
     }
 
-    fn get_children(&self) -> Vec<&dyn MerkleNode<VcHasher>> {
+    fn get_children(&self) -> Vec<&dyn MerkleNode<D>> {
         Vec::new()
     }
 }
 
-impl VcHashId for Blob {
-    fn get_hash_bytes(&self) -> VcHash {
+impl<D: VcDigest> VcHashId<D> for Blob<D> {
+    fn get_hash_bytes(&self) -> VcHash<D> {
         self.hash.clone()
     }
 
@@ -146,66 +276,272 @@ impl VcHashId for Blob {
 fn test_blob_to_file_file_saved() {
     use tempfile;
 
-    let mut tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
     let parent_path = tempdir.path().to_str().expect("Failed to convert temp dir path to string");
-    let blob = Blob::new(b"hello world");
+    let blob: Blob = Blob::new(b"hello world");
     blob.to_file(parent_path).expect("Failed to write blob to file");
-    let path = PathBuf::from(parent_path).join(hash_to_hex_string(&blob.get_hash()));
+    let path = object_path(parent_path, &hash_to_hex_string(&blob.get_hash()));
     assert!(Path::exists(path.as_path()));
 }
 
+#[test]
+fn test_blob_hash_file() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let path = tempdir.path().join("large.bin");
+    // longer than the buffer files are hashed through
+    let data: Vec<u8> = (0..2 * HASH_BUFFER_LEN + 5).map(|i| (i % 253) as u8).collect();
+    std::fs::write(&path, &data).unwrap();
+    assert_eq!(Blob::<VcHasher>::hash_file(&path).unwrap(), Blob::<VcHasher>::new(&data).hash);
+    assert_eq!(Blob::<sha1::Sha1>::hash_file(&path).unwrap(), Blob::<sha1::Sha1>::new(&data).hash);
+
+    let empty = tempdir.path().join("empty");
+    std::fs::write(&empty, b"").unwrap();
+    assert_eq!(Blob::<VcHasher>::hash_file(&empty).unwrap(), Blob::<VcHasher>::new(b"").hash);
+}
+
 #[test]
 fn test_blob_to_file_from_file() {
     use tempfile;
 
-    let mut tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
     let parent_path = tempdir.path().to_str().expect("Failed to convert temp dir path to string");
-    let blob = Blob::new(b"hello world");
+    let blob: Blob = Blob::new(b"hello world");
     blob.to_file(parent_path).expect("Failed to write blob to file");
-    let blob2 = Blob::from_file(PathBuf::from(parent_path).join(hash_to_hex_string(&blob.get_hash()))).expect("Failed to read blob from file");
+    let blob2: Blob = Blob::from_file(object_path(parent_path, &hash_to_hex_string(&blob.get_hash()))).expect("Failed to read blob from file");
     assert_eq!(blob.get_hash(), blob2.get_hash());
     assert_eq!(blob.get_data(), blob2.get_data());
 }
 
-impl Tree {
-    pub fn new(listings: HashMap<Name, FsObject>) -> Self {
-        let child_hashes = listings.values().map(|c| c.get_hash()).collect::<Vec<VcHash>>();
-        let hash = combine_hashes::<VcHasher>(&child_hashes);
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+impl<D: VcDigest> Tree<D> {
+    pub fn new(listings: HashMap<Name, FsObject<D>>) -> Self {
+        let hash = Self::compute_hash(&listings);
         Self {
             listings,
             hash
         }
     }
 
+    fn compute_hash(listings: &HashMap<Name, FsObject<D>>) -> VcHash<D> {
+        Self::hash_entries(listings.iter().map(|(name, obj)| (name.as_str(), obj.get_mode(), obj.get_hash())).collect())
+    }
+
+    // Hash of a tree from the name, mode and hash of each child, without needing the children themselves
+    pub fn hash_entries(entries: Vec<(&str, FileMode, VcHash<D>)>) -> VcHash<D> {
+        object_hash::<D>(ObjectType::Tree, &Self::encode_entries(entries))
+    }
+
+    // Canonical encoding the tree hash is computed over: one entry per child, sorted by name,
+    // each as `<octal mode> <type> <name>\0<hash bytes>`
+    fn encode_entries(mut entries: Vec<(&str, FileMode, VcHash<D>)>) -> Vec<u8> {
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        let mut data = Vec::new();
+        for (name, mode, hash) in entries {
+            let type_name = match mode {
+                FileMode::Regular | FileMode::Executable => "blob",
+                FileMode::Directory => "tree"
+            };
+            data.extend_from_slice(format!("{:o} {} {}\0", mode.as_octal(), type_name, name).as_bytes());
+            data.extend_from_slice(&hash);
+        }
+        data
+    }
+
+    pub fn get_path<P>(&self, path: P) -> Option<&FsObject<D>>
+    where P: AsRef<Path>
+    {
+        let mut components = path.as_ref().iter();
+        let mut obj = self.listings.get(components.next()?.to_str()?)?;
+        for name in components {
+            match obj {
+                FsObject::Tree(t) => obj = t.listings.get(name.to_str()?)?,
+                FsObject::Blob(_) => return None
+            }
+        }
+        Some(obj)
+    }
+
+    // All blobs below this tree, paired with their path relative to it
+    pub fn get_blobs(&self) -> Vec<(PathBuf, &Blob<D>)> {
+        let mut blobs = Vec::new();
+        for (name, obj) in &self.listings {
+            match obj {
+                FsObject::Blob(b) => blobs.push((PathBuf::from(name), b)),
+                FsObject::Tree(t) => {
+                    blobs.extend(t.get_blobs().into_iter().map(|(p, b)| (PathBuf::from(name).join(p), b)));
+                }
+            }
+        }
+        blobs
+    }
+
+    // Inserts `obj` at `path`, creating intermediate trees as needed.
+    // Returns false without changing anything if a blob is in the way of an intermediate tree,
+    // or if the object at `path` would change from a blob to a tree or the other way round.
+    pub fn insert_path<P>(&mut self, path: P, obj: FsObject<D>) -> bool
+    where P: AsRef<Path>
+    {
+        let components = path.as_ref().iter().map(|c| c.to_string_lossy().to_string()).collect::<Vec<Name>>();
+        self.insert_components(&components, obj)
+    }
+
+    fn insert_components(&mut self, components: &[Name], obj: FsObject<D>) -> bool {
+        let Some((name, rest)) = components.split_first() else {
+            return false
+        };
+        if rest.is_empty() {
+            match (self.listings.get(name), &obj) {
+                (Some(FsObject::Tree(_)), FsObject::Blob(_)) | (Some(FsObject::Blob(_)), FsObject::Tree(_)) => return false,
+                _ => {}
+            }
+            self.listings.insert(name.clone(), obj);
+        } else {
+            let child = self.listings.entry(name.clone())
+                .or_insert_with(|| FsObject::Tree(Tree::new(HashMap::new())));
+            match child {
+                FsObject::Tree(t) => if !t.insert_components(rest, obj) {
+                    return false
+                },
+                FsObject::Blob(_) => return false
+            }
+        }
+        self.hash = Self::compute_hash(&self.listings);
+        true
+    }
+
+    // Removes and returns the object at `path`, dropping trees which become empty
+    pub fn remove_path<P>(&mut self, path: P) -> Option<FsObject<D>>
+    where P: AsRef<Path>
+    {
+        let components = path.as_ref().iter().map(|c| c.to_string_lossy().to_string()).collect::<Vec<Name>>();
+        self.remove_components(&components)
+    }
+
+    fn remove_components(&mut self, components: &[Name]) -> Option<FsObject<D>> {
+        let (name, rest) = components.split_first()?;
+        let removed = if rest.is_empty() {
+            self.listings.remove(name)?
+        } else {
+            let FsObject::Tree(t) = self.listings.get_mut(name)? else {
+                return None
+            };
+            let removed = t.remove_components(rest)?;
+            if t.listings.is_empty() {
+                self.listings.remove(name);
+            }
+            removed
+        };
+        self.hash = Self::compute_hash(&self.listings);
+        Some(removed)
+    }
+
     pub fn to_file<P>(&self, parent_path: P) -> Result<(), Box<dyn Error>>
     where P: AsRef<Path>
     {
-        let path = parent_path.as_ref().join(hash_to_hex_string(&self.get_hash()));
-        if Path::exists(&path) { // assume if file exists with same hash name, it contains the same data
+        self.to_file_with(parent_path, Compression::default())
+    }
+
+    pub fn to_file_with<P>(&self, parent_path: P, compression: Compression) -> Result<(), Box<dyn Error>>
+    where P: AsRef<Path>
+    {
+        self.to_store(&mut FsObjectStore::with_compression(parent_path, compression))?;
+        Ok(())
+    }
+
+    // Saves the tree as a binary `TreeStub` along with its children, writing the children first
+    pub fn to_store(&self, store: &mut dyn ObjectStore<D>) -> Result<(), ObjectError> {
+        if store.contains(&self.hash)? { // assume an object stored under the same hash holds the same data
             return Ok(())
         }
-        todo!();
+        for obj in self.listings.values() {
+            obj.to_store(store)?;
+        }
+        let body = encode_stub::<D, _>(&TreeStub::from_tree(self), StubEncoding::default())
+            .map_err(|e| ObjectError::InvalidBody { path: store.location(&self.hash), message: e.to_string() })?;
+        store.put(&self.hash, ObjectType::Tree, &body)
     }
 
-    pub fn from_file<P>(path: P) -> Result<Self, Box<dyn Error>>
+    // Reads a directory of the working tree, along with everything below it, with the modes of its files.
+    // Empty directories are left out, as a tree can't hold them, and so is anything but files and directories.
+    pub fn from_working_dir<P>(path: P) -> Result<Self, ObjectError>
     where P: AsRef<Path>
     {
-        todo!();
+        let mut listings = HashMap::new();
+        for entry in std::fs::read_dir(path.as_ref())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let metadata = std::fs::metadata(entry.path())?;
+            if metadata.is_dir() {
+                let tree = Self::from_working_dir(entry.path())?;
+                if !tree.listings.is_empty() {
+                    listings.insert(name, FsObject::Tree(tree));
+                }
+            } else if metadata.is_file() {
+                listings.insert(name, FsObject::Blob(Blob::from_working_file(entry.path())?));
+            }
+        }
+        Ok(Self::new(listings))
+    }
+
+    // Loads a tree saved by `to_file`, resolving its children from the same directory.
+    // Fails if the hash of any loaded object doesn't match the hash it is listed under.
+    pub fn from_file<P>(path: P) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        let body = read_object_of_type(path.as_ref(), ObjectType::Tree)?;
+        Self::from_body(&FsObjectStore::new(objects_dir_of(path.as_ref())), path.as_ref(), &body)
+    }
+
+    pub fn from_store(store: &dyn ObjectStore<D>, hash: &VcHash<D>) -> Result<Self, ObjectError> {
+        let body = store.get_of_type(hash, ObjectType::Tree)?;
+        let tree = Self::from_body(store, &store.location(hash), &body)?;
+        check_hash(&store.location(hash), &hash_to_hex_string(hash), &tree.hash)?;
+        Ok(tree)
+    }
+
+    fn from_body(store: &dyn ObjectStore<D>, path: &Path, body: &[u8]) -> Result<Self, ObjectError> {
+        let stub: TreeStub = decode_stub::<D, _>(path, body)?;
+        if stub.format_version != REPOSITORY_FORMAT_VERSION {
+            return Err(ObjectError::UnsupportedFormatVersion { path: path.to_path_buf(), version: stub.format_version });
+        }
+        let mut listings = HashMap::new();
+        for (name, (fs_object_type, hashstr)) in stub.listings {
+            let hash = parse_hash::<D>(path, &hashstr)?;
+            let obj = match fs_object_type {
+                FsObjectType::Blob => FsObject::Blob(Blob::from_store(store, &hash)?),
+                FsObjectType::Executable => FsObject::Blob(Blob::from_store(store, &hash)?.with_executable(true)),
+                FsObjectType::Tree => FsObject::Tree(Tree::from_store(store, &hash)?)
+            };
+            listings.insert(name, obj);
+        }
+        let tree = Tree::new(listings);
+        check_hash(path, &stub.hashstr, &tree.get_hash())?;
+        Ok(tree)
     }
 }
 
-impl MerkleNode<VcHasher> for Tree {
-    fn get_hash(&self) -> VcHash {
+impl<D: VcDigest> MerkleNode<D> for Tree<D> {
+    fn get_hash(&self) -> VcHash<D> {
         self.hash.clone()
     }
 
-    fn get_children(&self) -> Vec<&dyn MerkleNode<VcHasher>> {
-        self.listings.values().map(|c| c as &dyn MerkleNode<VcHasher>).collect()
+    fn get_children(&self) -> Vec<&dyn MerkleNode<D>> {
+        self.listings.values().map(|c| c as &dyn MerkleNode<D>).collect()
     }
 }
 
-impl VcHashId for Tree {
-    fn get_hash_bytes(&self) -> VcHash {
+impl<D: VcDigest> VcHashId<D> for Tree<D> {
+    fn get_hash_bytes(&self) -> VcHash<D> {
         self.hash.clone()
     }
 
@@ -214,40 +550,286 @@ impl VcHashId for Tree {
     }
 }
 
-impl<'a> Commit<'a> {
-    pub fn new(tree: Tree, parent: Option<&'a Commit<'a>>, author: String, message: String) -> Self {
+fn parse_hash<D: VcDigest>(path: &Path, hashstr: &str) -> Result<VcHash<D>, ObjectError> {
+    hex_string_to_hash::<D>(hashstr).map_err(|e| ObjectError::InvalidBody { path: path.to_path_buf(), message: e.to_string() })
+}
+
+// Test fixtures shared by the tests of every module
+#[cfg(test)]
+pub(crate) fn sample_tree() -> Tree {
+    let mut tree = Tree::new(HashMap::new());
+    tree.insert_path("README", FsObject::Blob(Blob::new(b"readme")));
+    tree.insert_path("src/main.rs", FsObject::Blob(Blob::new(b"fn main() {}")));
+    tree.insert_path("src/lib/mod.rs", FsObject::Blob(Blob::new(b"")));
+    tree
+}
+
+#[cfg(test)]
+pub(crate) fn sample_commit() -> Commit<'static> {
+    Commit::new(sample_tree(), None, "alice".to_string(), "init".to_string())
+}
+
+// A tree of the given files, by path and contents
+#[cfg(test)]
+pub(crate) fn tree_of(files: &[(&str, &str)]) -> Tree {
+    let mut tree = Tree::new(HashMap::new());
+    for (path, data) in files {
+        assert!(tree.insert_path(path, FsObject::Blob(Blob::new(data.as_bytes()))));
+    }
+    tree
+}
+
+#[test]
+fn test_tree_hash_is_deterministic() {
+    let tree = sample_tree();
+    let rebuilt = Tree::new(tree.listings.clone());
+    assert_eq!(tree.get_hash(), rebuilt.get_hash());
+}
+
+#[test]
+fn test_tree_hash_is_name_sensitive() {
+    let tree = sample_tree();
+    let mut renamed = tree.clone();
+    let readme = renamed.remove_path("README").unwrap();
+    renamed.insert_path("README.md", readme);
+    assert_ne!(tree.get_hash(), renamed.get_hash());
+
+    // a blob and a tree with the same hash under the same name are different entries
+    let empty_tree: Tree = Tree::new(HashMap::new());
+    let blob: Blob = Blob { data: Box::new([]), hash: empty_tree.get_hash(), executable: false };
+    let with_blob = Tree::new(HashMap::from([("x".to_string(), FsObject::Blob(blob))]));
+    let with_tree = Tree::new(HashMap::from([("x".to_string(), FsObject::Tree(empty_tree))]));
+    assert_ne!(with_blob.get_hash(), with_tree.get_hash());
+}
+
+#[test]
+fn test_tree_to_file_from_file() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let mut tree = sample_tree();
+    tree.insert_path("run.sh", FsObject::Blob(Blob::new(b"#!/bin/sh").with_executable(true)));
+    tree.to_file(tempdir.path()).expect("Failed to write tree to file");
+
+    let tree2: Tree = Tree::from_file(object_path(tempdir.path(), &tree.get_hash_str())).expect("Failed to read tree from file");
+    assert_eq!(tree.get_hash(), tree2.get_hash());
+    let Some(FsObject::Blob(blob)) = tree2.get_path("src/main.rs") else { panic!("blob not loaded") };
+    assert_eq!(blob.get_data(), b"fn main() {}");
+    assert!(matches!(tree2.get_path("src/lib"), Some(FsObject::Tree(_))));
+    assert_eq!(tree2.get_path("run.sh").unwrap().get_mode(), FileMode::Executable);
+    assert_eq!(tree2.get_path("README").unwrap().get_mode(), FileMode::Regular);
+
+    let obj: FsObject = FsObject::from_file(object_path(tempdir.path(), &tree.get_hash_str())).unwrap();
+    assert!(matches!(obj, FsObject::Tree(_)));
+    let obj: FsObject = FsObject::from_file(object_path(tempdir.path(), &blob.get_hash_str())).unwrap();
+    assert!(matches!(obj, FsObject::Blob(_)));
+}
+
+#[test]
+fn test_tree_from_working_dir() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    std::fs::create_dir_all(tempdir.path().join("src/lib")).unwrap();
+    std::fs::create_dir_all(tempdir.path().join("empty")).unwrap();
+    std::fs::write(tempdir.path().join("src/lib/mod.rs"), b"").unwrap();
+    std::fs::write(tempdir.path().join("README"), b"readme").unwrap();
+    std::fs::write(tempdir.path().join("src/main.rs"), b"fn main() {}").unwrap();
+    std::fs::write(tempdir.path().join("run.sh"), b"#!/bin/sh").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(tempdir.path().join("run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let tree: Tree = Tree::from_working_dir(tempdir.path()).unwrap();
+    let mut expected = sample_tree();
+    expected.insert_path("run.sh", FsObject::Blob(Blob::new(b"#!/bin/sh").with_executable(cfg!(unix))));
+    assert_eq!(tree.hash, expected.hash);
+    assert_eq!(tree.get_path("README").unwrap().get_mode(), FileMode::Regular);
+    assert!(tree.get_path("empty").is_none());
+}
+
+#[test]
+fn test_tree_from_file_verifies_hashes() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let tree = sample_tree();
+    tree.to_file(tempdir.path()).expect("Failed to write tree to file");
+
+    let Some(FsObject::Blob(blob)) = tree.get_path("README") else { panic!() };
+    let blob_path = object_path(tempdir.path(), &blob.get_hash_str());
+    std::fs::write(&blob_path, encode_object(ObjectType::Blob, b"tampered")).unwrap();
+    let err = Tree::<VcHasher>::from_file(object_path(tempdir.path(), &tree.get_hash_str())).unwrap_err();
+    assert!(matches!(err, ObjectError::HashMismatch { path, .. } if path == blob_path));
+
+    // a tree stored under a blob's hash is mislabeled
+    std::fs::copy(object_path(tempdir.path(), &tree.get_hash_str()), &blob_path).unwrap();
+    let err = Tree::<VcHasher>::from_file(object_path(tempdir.path(), &tree.get_hash_str())).unwrap_err();
+    assert!(matches!(err, ObjectError::UnexpectedType { actual: ObjectType::Tree, .. }));
+}
+
+#[test]
+fn test_tree_from_file_rejects_legacy_format() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let tree: Tree = Tree::new(HashMap::new());
+    let mut stub = TreeStub::from_tree(&tree);
+    stub.format_version = 0;
+    stub.save_object_json(tempdir.path()).unwrap();
+    let err = Tree::<VcHasher>::from_file(object_path(tempdir.path(), &tree.get_hash_str())).unwrap_err();
+    assert!(matches!(err, ObjectError::InvalidHeader(_)));
+
+    let body = stub.serialize_json().unwrap();
+    write_object(tempdir.path(), "legacy", ObjectType::Tree, body.as_bytes()).unwrap();
+    let err = Tree::<VcHasher>::from_file(object_path(tempdir.path(), "legacy")).unwrap_err();
+    assert!(matches!(err, ObjectError::UnsupportedFormatVersion { version: 0, .. }));
+}
+
+#[test]
+fn test_commit_to_file_compressed() {
+    for compression in [Compression::None, Compression::Zlib, Compression::Zstd] {
+        let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+        let commit = Commit::new(sample_tree(), None, "alice".to_string(), "init".to_string());
+        commit.to_file_with(tempdir.path(), compression).expect("Failed to write commit to file");
+
+        let stored = std::fs::read(object_path(tempdir.path(), &commit.get_hash_str())).unwrap();
+        assert_eq!(Compression::detect(&stored), compression);
+        let loaded: Commit = Commit::from_file(object_path(tempdir.path(), &commit.get_hash_str())).unwrap();
+        assert_eq!(loaded.hash, commit.hash);
+        assert_eq!(loaded.tree.get_hash(), commit.tree.get_hash());
+    }
+}
+
+#[test]
+fn test_vc_object_from_file_dispatches_on_header() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let tree = sample_tree();
+    let commit = Commit::new(tree.clone(), None, "alice".to_string(), "init".to_string());
+    let child = Commit::new(tree.clone(), Some(&commit), "bob".to_string(), "again".to_string());
+    child.to_file(tempdir.path()).expect("Failed to write commit to file");
+
+    // a blob whose data looks like a stored tree is still a blob
+    let lookalike: Blob = Blob::new(&std::fs::read(object_path(tempdir.path(), &tree.get_hash_str())).unwrap());
+    lookalike.to_file(tempdir.path()).unwrap();
+
+    let obj: VcObject = VcObject::load(tempdir.path(), &tree.get_hash_str()).unwrap();
+    assert!(matches!(obj, VcObject::FsObject(FsObject::Tree(_))));
+    let obj: VcObject = VcObject::load(tempdir.path(), &lookalike.get_hash_str()).unwrap();
+    assert!(matches!(obj, VcObject::FsObject(FsObject::Blob(_))));
+    let VcObject::Commit(loaded) = VcObject::<VcHasher>::load(tempdir.path(), &child.get_hash_str()).unwrap() else {
+        panic!("commit not loaded")
+    };
+    assert_eq!(loaded.hash, child.hash);
+    assert_eq!(loaded.parent_hash, Some(commit.hash));
+    assert_eq!(loaded.tree.get_hash(), tree.get_hash());
+
+    let err = FsObject::<VcHasher>::from_file(object_path(tempdir.path(), &child.get_hash_str())).unwrap_err();
+    assert!(matches!(err, ObjectError::UnexpectedType { actual: ObjectType::Commit, .. }));
+}
+
+impl<'a, D: VcDigest> Commit<'a, D> {
+    pub fn new(tree: Tree<D>, parent: Option<&'a Commit<'a, D>>, author: String, message: String) -> Self {
         let timestamp = SystemTime::now();
-        let mut hasher = VcHasher::new();
-        hasher.update(&tree.get_hash());
-        hasher.update(&parent.as_ref().map(|p| p.get_hash()).unwrap_or([0; 32].into()));
-        hasher.update(&author.as_bytes());
-        hasher.update(&message.as_bytes());
-        hasher.update(&timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs().to_le_bytes());
-        let hash = hasher.finalize().into();
+        let parent_hash = parent.map(|p| p.get_hash());
+        let hash = Self::compute_hash(&tree, parent_hash.as_ref(), &author, &message, timestamp);
 
         Self {
             tree,
             hash,
             parent,
+            parent_hash,
             author,
             message,
             timestamp
         }
     }
+
+    fn compute_hash(tree: &Tree<D>, parent_hash: Option<&VcHash<D>>, author: &str, message: &str, timestamp: SystemTime) -> VcHash<D> {
+        Self::hash_fields(&tree.get_hash(), parent_hash, author, message, timestamp)
+    }
+
+    // Hash of a commit from its fields, without needing its tree
+    pub fn hash_fields(tree_hash: &VcHash<D>, parent_hash: Option<&VcHash<D>>, author: &str, message: &str, timestamp: SystemTime) -> VcHash<D> {
+        object_hash::<D>(ObjectType::Commit, &Self::encode(tree_hash, parent_hash, author, message, timestamp))
+    }
+
+    // Canonical encoding the commit hash is computed over, in the manner of git's commit objects
+    fn encode(tree_hash: &VcHash<D>, parent_hash: Option<&VcHash<D>>, author: &str, message: &str, timestamp: SystemTime) -> Vec<u8> {
+        let mut data = format!("tree {}\n", hash_to_hex_string(tree_hash));
+        if let Some(parent_hash) = parent_hash {
+            data.push_str(&format!("parent {}\n", hash_to_hex_string(parent_hash)));
+        }
+        let secs = timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        data.push_str(&format!("author {}\ntimestamp {}\n\n{}", author, secs, message));
+        data.into_bytes()
+    }
+
+    pub fn to_file<P>(&self, parent_path: P) -> Result<(), Box<dyn Error>>
+    where P: AsRef<Path>
+    {
+        self.to_file_with(parent_path, Compression::default())
+    }
+
+    // Saves the commit as a `CommitStub` along with its tree. The parent commit is not saved.
+    pub fn to_file_with<P>(&self, parent_path: P, compression: Compression) -> Result<(), Box<dyn Error>>
+    where P: AsRef<Path>
+    {
+        self.to_store(&mut FsObjectStore::with_compression(parent_path, compression))?;
+        Ok(())
+    }
+
+    // Saves the commit as a binary `CommitStub` along with its tree
+    pub fn to_store(&self, store: &mut dyn ObjectStore<D>) -> Result<(), ObjectError> {
+        self.tree.to_store(store)?;
+        let body = encode_stub::<D, _>(&CommitStub::from_commit(self), StubEncoding::default())
+            .map_err(|e| ObjectError::InvalidBody { path: store.location(&self.hash), message: e.to_string() })?;
+        store.put(&self.hash, ObjectType::Commit, &body)
+    }
+
+    // Loads a commit saved by `to_file` along with its tree. The parent is only known by its hash.
+    pub fn from_file<P>(path: P) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        let body = read_object_of_type(path.as_ref(), ObjectType::Commit)?;
+        Self::from_body(&FsObjectStore::new(objects_dir_of(path.as_ref())), path.as_ref(), &body)
+    }
+
+    pub fn from_store(store: &dyn ObjectStore<D>, hash: &VcHash<D>) -> Result<Self, ObjectError> {
+        let body = store.get_of_type(hash, ObjectType::Commit)?;
+        let commit = Self::from_body(store, &store.location(hash), &body)?;
+        check_hash(&store.location(hash), &hash_to_hex_string(hash), &commit.hash)?;
+        Ok(commit)
+    }
+
+    fn from_body(store: &dyn ObjectStore<D>, path: &Path, body: &[u8]) -> Result<Self, ObjectError> {
+        let stub: CommitStub = decode_stub::<D, _>(path, body)?;
+        let tree = Tree::from_store(store, &parse_hash::<D>(path, &stub.tree_hashstr)?)?;
+        let parent_hash = match &stub.parent_hashstr {
+            Some(hashstr) => Some(parse_hash::<D>(path, hashstr)?),
+            None => None
+        };
+        let hash = Self::compute_hash(&tree, parent_hash.as_ref(), &stub.author, &stub.message, stub.timestamp);
+        check_hash(path, &stub.hashstr, &hash)?;
+
+        Ok(Self {
+            tree,
+            hash,
+            parent: None,
+            parent_hash,
+            author: stub.author,
+            message: stub.message,
+            timestamp: stub.timestamp
+        })
+    }
 }
 
-impl<'a> MerkleNode<VcHasher> for Commit<'a> {
-    fn get_hash(&self) -> VcHash {
+impl<'a, D: VcDigest> MerkleNode<D> for Commit<'a, D> {
+    fn get_hash(&self) -> VcHash<D> {
         self.hash.clone()
     }
 
-    fn get_children(&self) -> Vec<&dyn MerkleNode<VcHasher>> {
+    fn get_children(&self) -> Vec<&dyn MerkleNode<D>> {
         vec![&self.tree]
     }
 }
 
-impl VcHashId for Commit<'_> {
-    fn get_hash_bytes(&self) -> VcHash {
+impl<D: VcDigest> VcHashId<D> for Commit<'_, D> {
+    fn get_hash_bytes(&self) -> VcHash<D> {
         self.hash.clone()
     }
 
@@ -256,43 +838,63 @@ impl VcHashId for Commit<'_> {
     }
 }
 
-impl FsObject {
-    pub fn from_file<P>(path: P) -> Result<Self, Box<dyn Error>>
+impl<D: VcDigest> FsObject<D> {
+    pub fn get_mode(&self) -> FileMode {
+        match self {
+            FsObject::Blob(b) => b.get_mode(),
+            FsObject::Tree(_) => FileMode::Directory
+        }
+    }
+
+    // Loads a blob or a tree, telling them apart by the object header
+    pub fn from_file<P>(path: P) -> Result<Self, ObjectError>
     where P: AsRef<Path>
     {
-        let err = std::io::Error::new(std::io::ErrorKind::InvalidData, "Failed to parse object file");
         let path = path.as_ref();
-        if !Path::exists(path) {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "File does not exist")));
+        match read_object(path)? {
+            (ObjectType::Blob, body) => Ok(Self::Blob(Blob::new_owned(body))),
+            (ObjectType::Tree, body) => Ok(Self::Tree(Tree::from_body(&FsObjectStore::new(objects_dir_of(path)), path, &body)?)),
+            (actual, _) => Err(ObjectError::UnexpectedType { path: path.to_path_buf(), actual })
         }
-        Blob::from_file(path).map(|b| Self::Blob(b))
-            .or_else(|_| Tree::from_file(path).map(|t| Self::Tree(t)))
     }
 
     pub fn to_file<P>(&self, parent_path: P) -> Result<(), Box<dyn Error>>
     where P: AsRef<Path>
+    {
+        self.to_file_with(parent_path, Compression::default())
+    }
+
+    pub fn to_file_with<P>(&self, parent_path: P, compression: Compression) -> Result<(), Box<dyn Error>>
+    where P: AsRef<Path>
     {
         match self {
             FsObject::Blob(b) => {
-                b.to_file(parent_path)?;
+                b.to_file_with(parent_path, compression)?;
             },
             FsObject::Tree(t) => {
-                t.to_file(parent_path)?;
+                t.to_file_with(parent_path, compression)?;
             }
         }
         Ok(())
     }
+
+    pub fn to_store(&self, store: &mut dyn ObjectStore<D>) -> Result<(), ObjectError> {
+        match self {
+            FsObject::Blob(b) => b.to_store(store),
+            FsObject::Tree(t) => t.to_store(store)
+        }
+    }
 }
 
-impl MerkleNode<VcHasher> for FsObject {
-    fn get_hash(&self) -> VcHash {
+impl<D: VcDigest> MerkleNode<D> for FsObject<D> {
+    fn get_hash(&self) -> VcHash<D> {
         match self {
             FsObject::Blob(b) => b.get_hash(),
             FsObject::Tree(t) => t.get_hash()
         }
     }
 
-    fn get_children(&self) -> Vec<&dyn MerkleNode<VcHasher>> {
+    fn get_children(&self) -> Vec<&dyn MerkleNode<D>> {
         match self {
             FsObject::Blob(b) => b.get_children(),
             FsObject::Tree(t) => t.get_children()
@@ -300,8 +902,8 @@ impl MerkleNode<VcHasher> for FsObject {
     }
 }
 
-impl VcHashId for FsObject {
-    fn get_hash_bytes(&self) -> VcHash {
+impl<D: VcDigest> VcHashId<D> for FsObject<D> {
+    fn get_hash_bytes(&self) -> VcHash<D> {
         match self {
             FsObject::Blob(b) => b.get_hash(),
             FsObject::Tree(t) => t.get_hash()
@@ -313,15 +915,50 @@ impl VcHashId for FsObject {
     }
 }
 
-impl<'a> MerkleNode<VcHasher> for VcObject<'a> {
-    fn get_hash(&self) -> VcHash {
+impl<'a, D: VcDigest> VcObject<'a, D> {
+    // Loads any stored object, dispatching on its header
+    pub fn from_file<P>(path: P) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+        Self::from_stored(&FsObjectStore::new(objects_dir_of(path)), path, read_object(path)?)
+    }
+
+    // Loads the object stored under `hashstr`, loose or packed, checking that its contents hash to it
+    pub fn load<P>(parent_path: P, hashstr: &str) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        let hash = hex_string_to_hash::<D>(hashstr).map_err(|_| ObjectError::NotFound(hashstr.to_string()))?;
+        Self::from_store(&FsObjectStore::new(parent_path), &hash)
+    }
+
+    pub fn from_store(store: &dyn ObjectStore<D>, hash: &VcHash<D>) -> Result<Self, ObjectError> {
+        let path = store.location(hash);
+        let stored = store.get(hash)?.ok_or_else(|| ObjectError::NotFound(hash_to_hex_string(hash)))?;
+        let obj = Self::from_stored(store, &path, stored)?;
+        check_hash(&path, &hash_to_hex_string(hash), &obj.get_hash())?;
+        Ok(obj)
+    }
+
+    fn from_stored(store: &dyn ObjectStore<D>, path: &Path, stored: StoredObject) -> Result<Self, ObjectError> {
+        match stored {
+            (ObjectType::Blob, body) => Ok(Self::FsObject(FsObject::Blob(Blob::new_owned(body)))),
+            (ObjectType::Tree, body) => Ok(Self::FsObject(FsObject::Tree(Tree::from_body(store, path, &body)?))),
+            (ObjectType::Commit, body) => Ok(Self::Commit(Commit::from_body(store, path, &body)?)),
+            (ObjectType::Tag, _) => Err(ObjectError::UnsupportedType(ObjectType::Tag))
+        }
+    }
+}
+
+impl<'a, D: VcDigest> MerkleNode<D> for VcObject<'a, D> {
+    fn get_hash(&self) -> VcHash<D> {
         match self {
             VcObject::FsObject(f) => f.get_hash(),
             VcObject::Commit(c) => c.get_hash()
         }
     }
 
-    fn get_children(&self) -> Vec<&dyn MerkleNode<VcHasher>> {
+    fn get_children(&self) -> Vec<&dyn MerkleNode<D>> {
         match self {
             VcObject::FsObject(f) => f.get_children(),
             VcObject::Commit(c) => c.get_children()
@@ -329,8 +966,8 @@ impl<'a> MerkleNode<VcHasher> for VcObject<'a> {
     }
 }
 
-impl VcHashId for VcObject<'_> {
-    fn get_hash_bytes(&self) -> VcHash {
+impl<D: VcDigest> VcHashId<D> for VcObject<'_, D> {
+    fn get_hash_bytes(&self) -> VcHash<D> {
         match self {
             VcObject::FsObject(f) => f.get_hash(),
             VcObject::Commit(c) => c.get_hash()
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use digest::Digest;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::*;
+use crate::vc::*;
+use crate::vc_object::*;
+use crate::vc_serialize::*;
+
+// Binary stubs start with this byte, which can't start a JSON stub (always `{`), so either can be read
+const BINARY_MARKER: u8 = 0;
+
+const BINARY_BLOB: u8 = 0;
+const BINARY_TREE: u8 = 1;
+const BINARY_EXECUTABLE: u8 = 2;
+
+// How the stub of a tree or commit is encoded in the body of its object
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StubEncoding {
+    // readable, for debugging and exporting
+    Json,
+    // compact and canonical: raw hashes, length-prefixed strings and varints
+    #[default]
+    Binary
+}
+
+impl StubEncoding {
+    pub fn detect(body: &[u8]) -> Self {
+        match body.first() {
+            Some(&BINARY_MARKER) => StubEncoding::Binary,
+            _ => StubEncoding::Json
+        }
+    }
+}
+
+impl FromStr for StubEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(StubEncoding::Json),
+            "binary" => Ok(StubEncoding::Binary),
+            _ => Err(format!("unknown stub encoding: {}", s))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BinaryError(pub String);
+
+impl Display for BinaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for BinaryError {}
+
+// LEB128: seven bits at a time, least significant first, with the high bit set on all but the last byte
+pub fn push_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+pub fn take_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut n: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let b = *data.get(*pos)?;
+        *pos += 1;
+        n |= ((b & 0x7f) as u64).checked_shl(shift)?;
+        if b & 0x80 == 0 {
+            return Some(n)
+        }
+        shift += 7;
+    }
+}
+
+// Reads the parts of a binary stub in order, failing on anything out of bounds.
+// Hashes are as long as the output of the repository's digest.
+struct BinaryReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    hash_len: usize
+}
+
+impl<'a> BinaryReader<'a> {
+    fn new<D: VcDigest>(data: &'a [u8]) -> Self {
+        Self { data, pos: 0, hash_len: <D as Digest>::output_size() }
+    }
+
+    fn byte(&mut self) -> Result<u8, BinaryError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], BinaryError> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len())
+            .ok_or_else(|| BinaryError("unexpected end of data".to_string()))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64, BinaryError> {
+        take_varint(self.data, &mut self.pos).ok_or_else(|| BinaryError("invalid varint".to_string()))
+    }
+
+    fn hash(&mut self) -> Result<VcHashString, BinaryError> {
+        Ok(hex::encode(self.bytes(self.hash_len)?))
+    }
+
+    fn string(&mut self) -> Result<String, BinaryError> {
+        let len = self.varint()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|e| BinaryError(e.to_string()))
+    }
+
+    fn finish(self) -> Result<(), BinaryError> {
+        if self.pos != self.data.len() {
+            return Err(BinaryError(format!("{} trailing bytes", self.data.len() - self.pos)))
+        }
+        Ok(())
+    }
+}
+
+fn push_hash<D: VcDigest>(out: &mut Vec<u8>, hashstr: &str) -> Result<(), BinaryError> {
+    let hash = hex_string_to_hash::<D>(hashstr).map_err(|e| BinaryError(format!("{}: {}", e, hashstr)))?;
+    out.extend_from_slice(&hash);
+    Ok(())
+}
+
+fn push_string(out: &mut Vec<u8>, s: &str) {
+    push_varint(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+// Hashes are written as raw bytes, so both ways need to know the digest they are of
+pub trait BinaryEncode : Sized {
+    fn encode_binary<D: VcDigest>(&self) -> Result<Vec<u8>, BinaryError>;
+
+    fn decode_binary<D: VcDigest>(data: &[u8]) -> Result<Self, BinaryError>;
+}
+
+// marker, format version, own hash, entry count, then per entry sorted by name:
+// type (0 blob, 1 tree, 2 executable blob), name, hash
+impl BinaryEncode for TreeStub {
+    fn encode_binary<D: VcDigest>(&self) -> Result<Vec<u8>, BinaryError> {
+        let mut out = vec![BINARY_MARKER];
+        push_varint(&mut out, self.format_version as u64);
+        push_hash::<D>(&mut out, &self.hashstr)?;
+        push_varint(&mut out, self.listings.len() as u64);
+        let mut names: Vec<&Name> = self.listings.keys().collect();
+        names.sort();
+        for name in names {
+            let (fs_object_type, hashstr) = &self.listings[name];
+            out.push(match fs_object_type {
+                FsObjectType::Blob => BINARY_BLOB,
+                FsObjectType::Tree => BINARY_TREE,
+                FsObjectType::Executable => BINARY_EXECUTABLE
+            });
+            push_string(&mut out, name);
+            push_hash::<D>(&mut out, hashstr)?;
+        }
+        Ok(out)
+    }
+
+    fn decode_binary<D: VcDigest>(data: &[u8]) -> Result<Self, BinaryError> {
+        let mut reader = BinaryReader::new::<D>(data);
+        if reader.byte()? != BINARY_MARKER {
+            return Err(BinaryError("not a binary stub".to_string()))
+        }
+        let format_version = u32::try_from(reader.varint()?).map_err(|e| BinaryError(e.to_string()))?;
+        let hashstr = reader.hash()?;
+        let count = reader.varint()?;
+        let mut listings = HashMap::new();
+        for _ in 0..count {
+            let fs_object_type = match reader.byte()? {
+                BINARY_BLOB => FsObjectType::Blob,
+                BINARY_TREE => FsObjectType::Tree,
+                BINARY_EXECUTABLE => FsObjectType::Executable,
+                b => return Err(BinaryError(format!("unknown listing type {}", b)))
+            };
+            let name = reader.string()?;
+            let hashstr = reader.hash()?;
+            if listings.insert(name.clone(), (fs_object_type, hashstr)).is_some() {
+                return Err(BinaryError(format!("duplicate listing {}", name)))
+            }
+        }
+        reader.finish()?;
+        Ok(Self { listings, hashstr, format_version })
+    }
+}
+
+// marker, own hash, tree hash, parent flag and hash, timestamp seconds and nanoseconds, author, message
+impl BinaryEncode for CommitStub {
+    fn encode_binary<D: VcDigest>(&self) -> Result<Vec<u8>, BinaryError> {
+        let mut out = vec![BINARY_MARKER];
+        push_hash::<D>(&mut out, &self.hashstr)?;
+        push_hash::<D>(&mut out, &self.tree_hashstr)?;
+        match &self.parent_hashstr {
+            Some(parent_hashstr) => {
+                out.push(1);
+                push_hash::<D>(&mut out, parent_hashstr)?;
+            },
+            None => out.push(0)
+        }
+        let since_epoch = self.timestamp.duration_since(SystemTime::UNIX_EPOCH).map_err(|e| BinaryError(e.to_string()))?;
+        push_varint(&mut out, since_epoch.as_secs());
+        push_varint(&mut out, since_epoch.subsec_nanos() as u64);
+        push_string(&mut out, &self.author);
+        push_string(&mut out, &self.message);
+        Ok(out)
+    }
+
+    fn decode_binary<D: VcDigest>(data: &[u8]) -> Result<Self, BinaryError> {
+        let mut reader = BinaryReader::new::<D>(data);
+        if reader.byte()? != BINARY_MARKER {
+            return Err(BinaryError("not a binary stub".to_string()))
+        }
+        let hashstr = reader.hash()?;
+        let tree_hashstr = reader.hash()?;
+        let parent_hashstr = match reader.byte()? {
+            0 => None,
+            1 => Some(reader.hash()?),
+            b => return Err(BinaryError(format!("invalid parent flag {}", b)))
+        };
+        let secs = reader.varint()?;
+        let nanos = u32::try_from(reader.varint()?).ok().filter(|n| *n < 1_000_000_000)
+            .ok_or_else(|| BinaryError("invalid timestamp".to_string()))?;
+        let timestamp = SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+            .ok_or_else(|| BinaryError("invalid timestamp".to_string()))?;
+        let author = reader.string()?;
+        let message = reader.string()?;
+        reader.finish()?;
+        Ok(Self { tree_hashstr, hashstr, parent_hashstr, author, message, timestamp })
+    }
+}
+
+pub fn encode_stub<D, S>(stub: &S, encoding: StubEncoding) -> Result<Vec<u8>, BinaryError>
+where D: VcDigest, S: BinaryEncode + Serialize
+{
+    match encoding {
+        StubEncoding::Json => serialize_json_compact(stub).map(String::into_bytes).map_err(|e| BinaryError(e.to_string())),
+        StubEncoding::Binary => stub.encode_binary::<D>()
+    }
+}
+
+// Decodes the stub in the body of a stored object, in whichever encoding it was written
+pub fn decode_stub<D, S>(path: &Path, body: &[u8]) -> Result<S, ObjectError>
+where D: VcDigest, S: BinaryEncode + DeserializeOwned
+{
+    let message = match StubEncoding::detect(body) {
+        StubEncoding::Binary => S::decode_binary::<D>(body).map_err(|e| e.to_string()),
+        StubEncoding::Json => serde_json::from_slice(body).map_err(|e| e.to_string())
+    };
+    message.map_err(|message| ObjectError::InvalidBody { path: path.to_path_buf(), message })
+}
+
+// Rewrites the body of a stored stub in another encoding. The stub is unchanged either way.
+pub fn reencode_stub<D, S>(path: &Path, body: &[u8], encoding: StubEncoding) -> Result<Vec<u8>, ObjectError>
+where D: VcDigest, S: BinaryEncode + Serialize + DeserializeOwned
+{
+    let stub: S = decode_stub::<D, _>(path, body)?;
+    encode_stub::<D, _>(&stub, encoding).map_err(|e| ObjectError::InvalidBody { path: path.to_path_buf(), message: e.to_string() })
+}
+
+#[test]
+fn test_varint_round_trip() {
+    for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+        let mut out = Vec::new();
+        push_varint(&mut out, n);
+        let mut pos = 0;
+        assert_eq!(take_varint(&out, &mut pos), Some(n));
+        assert_eq!(pos, out.len());
+    }
+    assert_eq!(take_varint(&[0x80], &mut 0), None);
+}
+
+#[test]
+fn test_binary_stubs_convert_losslessly() {
+    let parent = sample_commit();
+    let commit = Commit::new(parent.tree.clone(), Some(&parent), "bob".to_string(), "second\n\nwith a body".to_string());
+    let tree_stub = TreeStub::from_tree(&commit.tree);
+    let commit_stub = CommitStub::from_commit(&commit);
+    let path = Path::new("stub");
+
+    for encoding in [StubEncoding::Json, StubEncoding::Binary] {
+        let body = encode_stub::<VcHasher, _>(&tree_stub, encoding).unwrap();
+        assert_eq!(StubEncoding::detect(&body), encoding);
+        assert_eq!(decode_stub::<VcHasher, TreeStub>(path, &body).unwrap(), tree_stub);
+        let body = encode_stub::<VcHasher, _>(&commit_stub, encoding).unwrap();
+        assert_eq!(decode_stub::<VcHasher, CommitStub>(path, &body).unwrap(), commit_stub);
+    }
+
+    let json = encode_stub::<VcHasher, _>(&tree_stub, StubEncoding::Json).unwrap();
+    let binary = reencode_stub::<VcHasher, TreeStub>(path, &json, StubEncoding::Binary).unwrap();
+    assert_eq!(binary, tree_stub.encode_binary::<VcHasher>().unwrap());
+    let json_again = reencode_stub::<VcHasher, TreeStub>(path, &binary, StubEncoding::Json).unwrap();
+    assert_eq!(decode_stub::<VcHasher, TreeStub>(path, &json_again).unwrap(), tree_stub);
+    assert!(binary.len() * 2 < json.len());
+
+    // without listings to reorder, the JSON comes back byte for byte
+    let json = encode_stub::<VcHasher, _>(&commit_stub, StubEncoding::Json).unwrap();
+    let binary = reencode_stub::<VcHasher, CommitStub>(path, &json, StubEncoding::Binary).unwrap();
+    assert_eq!(reencode_stub::<VcHasher, CommitStub>(path, &binary, StubEncoding::Json).unwrap(), json);
+}
+
+#[test]
+fn test_binary_stub_errors() {
+    let stub = TreeStub::from_tree(&sample_tree());
+    let binary = stub.encode_binary::<VcHasher>().unwrap();
+    assert!(TreeStub::decode_binary::<VcHasher>(&binary[..binary.len() - 1]).is_err());
+    let mut trailing = binary.clone();
+    trailing.push(0);
+    assert!(TreeStub::decode_binary::<VcHasher>(&trailing).is_err());
+    assert!(matches!(decode_stub::<VcHasher, CommitStub>(Path::new("x"), &binary), Err(ObjectError::InvalidBody { .. })));
+
+    let mut invalid = stub.clone();
+    invalid.hashstr = "not a hash".to_string();
+    assert!(invalid.encode_binary::<VcHasher>().is_err());
+    // hashes of another digest
+    assert!(stub.encode_binary::<sha1::Sha1>().is_err());
+}
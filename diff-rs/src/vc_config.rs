@@ -0,0 +1,662 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use digest::Digest;
+
+use crate::hashing::*;
+use crate::util::*;
+use crate::vc::*;
+use crate::vc_binary::*;
+use crate::vc_lock::*;
+use crate::vc_object::*;
+use crate::vc_refs::*;
+use crate::vc_serialize::*;
+use crate::vc_store::*;
+
+// File of the repository recording how its objects are stored
+pub const CONFIG_FILE_NAME: &str = "config";
+// What objects are named by in repositories created without saying otherwise, and in those predating configs
+pub const DEFAULT_HASH_ALGORITHM: &str = VcHasher::NAME;
+
+// How loose objects are laid out in the objects directory
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectLayout {
+    // `<objects>/<hash>`, as written before fan-out
+    Flat,
+    // `<objects>/ab/cdef...`
+    #[default]
+    Sharded
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct RepositoryConfig {
+    pub format_version: u32,
+    pub hash_algorithm: String,
+    // what trees and commits are migrated to. Either encoding is always readable.
+    pub object_encoding: StubEncoding,
+    // what new loose objects are written with
+    pub compression: Compression,
+    pub layout: ObjectLayout
+}
+impl SerializeDeserializeJson for RepositoryConfig {}
+
+impl Default for RepositoryConfig {
+    fn default() -> Self {
+        Self {
+            format_version: REPOSITORY_FORMAT_VERSION,
+            hash_algorithm: DEFAULT_HASH_ALGORITHM.to_string(),
+            object_encoding: StubEncoding::default(),
+            compression: Compression::default(),
+            layout: ObjectLayout::default()
+        }
+    }
+}
+
+// Read before the rest of the config, so that a newer config is refused for its version
+// rather than for whatever fields it has changed
+#[derive(Deserialize)]
+struct ConfigVersion {
+    format_version: u32
+}
+
+impl RepositoryConfig {
+    // Works out the config of a repository written before configs, from the layout of its objects
+    // and the first tree found among them. A repository without trees is taken to be current.
+    pub fn detect<P>(root: P) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        let objects_dir = root.as_ref().join(OBJECTS_DIR_NAME);
+        let mut config = Self::default();
+        if !objects_dir.is_dir() {
+            return Ok(config)
+        }
+        // repositories predating configs all named their objects with the default digest
+        let flat = flat_objects::<VcHasher>(&objects_dir)?;
+        if !flat.is_empty() {
+            config.layout = ObjectLayout::Flat;
+        }
+        let paths = flat.into_iter().map(|hashstr| objects_dir.join(hashstr))
+            .chain(list_objects(&objects_dir)?.into_iter().map(|hashstr| object_path(&objects_dir, &hashstr)));
+        for path in paths {
+            if let ((ObjectType::Tree, body), _) = read_legacy_object(&path)? {
+                let stub: TreeStub = decode_stub::<VcHasher, _>(&path, &body)?;
+                config.format_version = stub.format_version;
+                config.object_encoding = StubEncoding::detect(&body);
+                break
+            }
+        }
+        Ok(config)
+    }
+
+    // The config of a repository naming its objects with `D`
+    pub fn with_digest<D: VcDigest>() -> Self {
+        Self { hash_algorithm: D::NAME.to_string(), ..Self::default() }
+    }
+
+    // The migrations needed before a repository with this config can be opened, in the order they have to be run
+    pub fn pending_migrations(&self) -> Vec<Migration> {
+        let mut migrations = Vec::new();
+        if self.layout == ObjectLayout::Flat {
+            migrations.push(Migration::Fanout);
+        }
+        if self.format_version < REPOSITORY_FORMAT_VERSION {
+            migrations.push(Migration::Rehash);
+        }
+        migrations
+    }
+}
+
+// Reads the config of a repository, or detects it if the repository predates configs
+pub fn read_config<P>(root: P) -> Result<RepositoryConfig, ObjectError>
+where P: AsRef<Path>
+{
+    let path = root.as_ref().join(CONFIG_FILE_NAME);
+    if !path.is_file() {
+        return RepositoryConfig::detect(root)
+    }
+    let json = fs::read_to_string(&path)?;
+    let invalid = |e: serde_json::Error| ObjectError::InvalidBody { path: path.clone(), message: e.to_string() };
+    let version: ConfigVersion = deserialize_json(&json).map_err(invalid)?;
+    if version.format_version > REPOSITORY_FORMAT_VERSION {
+        return Err(ObjectError::UnsupportedFormatVersion { path, version: version.format_version })
+    }
+    RepositoryConfig::deserialize_json(&json).map_err(invalid)
+}
+
+pub fn write_config<P>(root: P, config: &RepositoryConfig) -> Result<(), ObjectError>
+where P: AsRef<Path>
+{
+    let path = root.as_ref().join(CONFIG_FILE_NAME);
+    let json = config.serialize_json().map_err(|e| ObjectError::InvalidBody { path: path.clone(), message: e.to_string() })?;
+    write_atomic(path, json.as_bytes())?;
+    Ok(())
+}
+
+// Reads a loose object which may have been written before objects had headers, when blobs were stored
+// as their raw bytes and trees as JSON stubs. Anything which doesn't read as a current object is taken
+// to be one of those: a tree if it parses as a stub, as they were told apart then, and a blob otherwise.
+// Returns whether the object had no header.
+fn read_legacy_object(path: &Path) -> Result<(StoredObject, bool), ObjectError> {
+    match read_object(path) {
+        Ok(stored) => Ok((stored, false)),
+        Err(ObjectError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => Err(e.into()),
+        Err(_) => {
+            let body = fs::read(path)?;
+            let object_type = match serde_json::from_slice::<TreeStub>(&body) {
+                Ok(_) => ObjectType::Tree,
+                Err(_) => ObjectType::Blob
+            };
+            Ok(((object_type, body.into()), true))
+        }
+    }
+}
+
+// Reads loose objects through `read_legacy_object`, so that migrations can rebuild objects written
+// before headers. Everything else, writes included, goes to the inner store.
+struct LegacyObjectStore<D: VcDigest> {
+    inner: FsObjectStore<D>
+}
+
+impl<D: VcDigest> ObjectStore<D> for LegacyObjectStore<D> {
+    fn put(&mut self, hash: &VcHash<D>, object_type: ObjectType, body: &[u8]) -> Result<(), ObjectError> {
+        self.inner.put(hash, object_type, body)
+    }
+
+    fn get(&self, hash: &VcHash<D>) -> Result<Option<StoredObject>, ObjectError> {
+        let path = self.inner.location(hash);
+        if !path.is_file() {
+            return self.inner.get(hash)
+        }
+        Ok(Some(read_legacy_object(&path)?.0))
+    }
+
+    fn contains(&self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        self.inner.contains(hash)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = VcHash<D>> + '_>, ObjectError> {
+        self.inner.iter()
+    }
+
+    fn delete(&mut self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        self.inner.delete(hash)
+    }
+
+    fn location(&self, hash: &VcHash<D>) -> PathBuf {
+        self.inner.location(hash)
+    }
+
+    // any bytes were a blob before headers, even ones which happen to parse as a stub
+    fn get_of_type(&self, hash: &VcHash<D>, expected: ObjectType) -> Result<Box<[u8]>, ObjectError> {
+        let path = self.inner.location(hash);
+        if !path.is_file() {
+            return self.inner.get_of_type(hash, expected)
+        }
+        match read_legacy_object(&path)? {
+            ((actual, body), legacy) if actual == expected || (legacy && expected == ObjectType::Blob) => Ok(body),
+            ((actual, _), _) => Err(ObjectError::UnexpectedType { path, actual })
+        }
+    }
+}
+
+// Names of the loose objects of `D` lying directly in the objects directory
+fn flat_objects<D: VcDigest>(objects_dir: &Path) -> Result<Vec<VcHashString>, std::io::Error> {
+    let hex_len = <D as Digest>::output_size() * 2;
+    let mut hashes = Vec::new();
+    for entry in fs::read_dir(objects_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_file() && name.len() == hex_len && name.bytes().all(|b| b.is_ascii_hexdigit()) {
+            hashes.push(name);
+        }
+    }
+    Ok(hashes)
+}
+
+// A step upgrading how a repository's objects are stored
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Migration {
+    // moves loose objects from `<objects>/<hash>` into shard directories
+    Fanout,
+    // rewrites loose trees and commits in another encoding. Their hashes don't change.
+    Reencode(StubEncoding),
+    // rebuilds the commits refs point at, and everything they reach, under the current hashing and
+    // points the refs at the rebuilt commits, then rebuilds every other tree of an older format version,
+    // such as those written before object headers. The old objects are left to gc, and reflogs keep their old hashes.
+    Rehash
+}
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Migration::Fanout => write!(f, "fan out loose objects"),
+            Migration::Reencode(StubEncoding::Json) => write!(f, "re-encode trees and commits as JSON"),
+            Migration::Reencode(StubEncoding::Binary) => write!(f, "re-encode trees and commits as binary"),
+            Migration::Rehash => write!(f, "rehash objects under the current format version")
+        }
+    }
+}
+
+// Runs a migration on a repository and records it in the config, returning the number of objects it rewrote.
+// Objects are all written before any ref is moved, and the config last, so an interrupted migration can be run again.
+pub fn migrate<P>(root: P, migration: Migration) -> Result<usize, ObjectError>
+where P: AsRef<Path>
+{
+    let root = root.as_ref();
+    let _lock = LockFile::try_acquire(root.join(CONFIG_FILE_NAME))?;
+    let mut config = read_config(root)?;
+    let rewritten = match config.hash_algorithm.as_str() {
+        "md5" => migrate_with::<md5::Md5>(root, migration, &mut config)?,
+        "sha1" => migrate_with::<sha1::Sha1>(root, migration, &mut config)?,
+        "sha224" => migrate_with::<sha2::Sha224>(root, migration, &mut config)?,
+        "sha256" => migrate_with::<sha2::Sha256>(root, migration, &mut config)?,
+        "sha384" => migrate_with::<sha2::Sha384>(root, migration, &mut config)?,
+        "sha512" => migrate_with::<sha2::Sha512>(root, migration, &mut config)?,
+        "sha3-256" => migrate_with::<sha3::Sha3_256>(root, migration, &mut config)?,
+        "sha3-512" => migrate_with::<sha3::Sha3_512>(root, migration, &mut config)?,
+        "blake2b" => migrate_with::<blake2::Blake2b512>(root, migration, &mut config)?,
+        "blake2s" => migrate_with::<blake2::Blake2s256>(root, migration, &mut config)?,
+        "blake3" => migrate_with::<blake3::Hasher>(root, migration, &mut config)?,
+        _ => return Err(ObjectError::UnsupportedHashAlgorithm(config.hash_algorithm))
+    };
+    write_config(root, &config)?;
+    Ok(rewritten)
+}
+
+fn migrate_with<D: VcDigest>(root: &Path, migration: Migration, config: &mut RepositoryConfig) -> Result<usize, ObjectError> {
+    let objects_dir = root.join(OBJECTS_DIR_NAME);
+    let rewritten = match migration {
+        Migration::Fanout => {
+            let moved = migrate_to_fanout::<D, _>(&objects_dir)?;
+            config.layout = ObjectLayout::Sharded;
+            moved
+        },
+        Migration::Reencode(encoding) => {
+            config.object_encoding = encoding;
+            reencode_objects::<D>(&objects_dir, encoding, config.compression)?
+        },
+        Migration::Rehash => {
+            let mut store = LegacyObjectStore { inner: FsObjectStore::with_compression(&objects_dir, config.compression) };
+            let mut trees = HashMap::new();
+            let rehashed = rehash_refs(&mut store, &RefStore::<D>::new(root), &mut trees)? + rehash_trees(&mut store, &mut trees)?;
+            if config.object_encoding != StubEncoding::default() {
+                reencode_objects::<D>(&objects_dir, config.object_encoding, config.compression)?;
+            }
+            config.format_version = REPOSITORY_FORMAT_VERSION;
+            rehashed
+        }
+    };
+    Ok(rewritten)
+}
+
+// Packed objects keep their encoding until they are repacked, and objects without headers are left to gc
+fn reencode_objects<D: VcDigest>(objects_dir: &Path, encoding: StubEncoding, compression: Compression) -> Result<usize, ObjectError> {
+    let mut rewritten = 0;
+    for hashstr in list_objects(objects_dir)? {
+        let path = object_path(objects_dir, &hashstr);
+        let ((object_type, body), legacy) = read_legacy_object(&path)?;
+        if legacy || StubEncoding::detect(&body) == encoding {
+            continue
+        }
+        let body = match object_type {
+            ObjectType::Tree => reencode_stub::<D, TreeStub>(&path, &body, encoding)?,
+            ObjectType::Commit => reencode_stub::<D, CommitStub>(&path, &body, encoding)?,
+            _ => continue
+        };
+        replace_object_with(objects_dir, &hashstr, object_type, &body, compression)?;
+        rewritten += 1;
+    }
+    Ok(rewritten)
+}
+
+fn parse_stub_hash<D: VcDigest>(path: &Path, hashstr: &str) -> Result<VcHash<D>, ObjectError> {
+    hex_string_to_hash::<D>(hashstr).map_err(|e| ObjectError::InvalidBody { path: path.to_path_buf(), message: e.to_string() })
+}
+
+// Rebuilds a tree from its stub and the stubs below it, ignoring the hashes they were stored with
+fn rehash_tree<D: VcDigest>(store: &dyn ObjectStore<D>, hash: &VcHash<D>, trees: &mut HashMap<VcHash<D>, Tree<D>>) -> Result<Tree<D>, ObjectError> {
+    if let Some(tree) = trees.get(hash) {
+        return Ok(tree.clone())
+    }
+    let path = store.location(hash);
+    let stub: TreeStub = decode_stub::<D, _>(&path, &store.get_of_type(hash, ObjectType::Tree)?)?;
+    let mut listings = HashMap::new();
+    for (name, (fs_object_type, hashstr)) in stub.listings {
+        let listed = parse_stub_hash::<D>(&path, &hashstr)?;
+        let obj = match fs_object_type {
+            FsObjectType::Blob => FsObject::Blob(Blob::new_owned(store.get_of_type(&listed, ObjectType::Blob)?)),
+            FsObjectType::Executable => FsObject::Blob(Blob::new_owned(store.get_of_type(&listed, ObjectType::Blob)?).with_executable(true)),
+            FsObjectType::Tree => FsObject::Tree(rehash_tree(store, &listed, trees)?)
+        };
+        listings.insert(name, obj);
+    }
+    let tree = Tree::new(listings);
+    trees.insert(hash.clone(), tree.clone());
+    Ok(tree)
+}
+
+// Rebuilds the commits refs and HEAD point at along with their history, then moves the refs.
+// Returns the number of rebuilt commits.
+fn rehash_refs<D: VcDigest>(store: &mut dyn ObjectStore<D>, refs: &RefStore<D>, trees: &mut HashMap<VcHash<D>, Tree<D>>) -> Result<usize, ObjectError> {
+    let mut roots = Vec::new();
+    for kind in [RefKind::Head, RefKind::Tag] {
+        roots.extend(refs.list_refs(kind)?.into_iter().map(|(name, hash)| (Some((kind, name)), hash)));
+    }
+    let head = refs.get_head()?;
+    if let Some(HeadRefStub::Commit(hashstr)) = &head {
+        roots.push((None, parse_stub_hash::<D>(&refs.get_root().join(HEAD_FILE_NAME), hashstr)?));
+    }
+
+    let mut commits: HashMap<VcHash<D>, VcHash<D>> = HashMap::new();
+    for (_, root) in &roots {
+        // walk back to the first commit already rebuilt, then rebuild forwards so parents come first
+        let mut chain = Vec::new();
+        let mut next = Some(root.clone());
+        while let Some(hash) = next.filter(|h| !commits.contains_key(h)) {
+            let path = store.location(&hash);
+            let stub: CommitStub = decode_stub::<D, _>(&path, &store.get_of_type(&hash, ObjectType::Commit)?)?;
+            next = stub.parent_hashstr.as_deref().map(|p| parse_stub_hash::<D>(&path, p)).transpose()?;
+            chain.push((hash, path, stub));
+        }
+        for (hash, path, stub) in chain.into_iter().rev() {
+            let tree = rehash_tree(store, &parse_stub_hash::<D>(&path, &stub.tree_hashstr)?, trees)?;
+            let parent_hash = match &stub.parent_hashstr {
+                Some(p) => Some(commits[&parse_stub_hash::<D>(&path, p)?].clone()),
+                None => None
+            };
+            let commit = Commit {
+                hash: Commit::<D>::hash_fields(&tree.hash, parent_hash.as_ref(), &stub.author, &stub.message, stub.timestamp),
+                tree,
+                parent: None,
+                parent_hash,
+                author: stub.author,
+                message: stub.message,
+                timestamp: stub.timestamp
+            };
+            commit.to_store(store)?;
+            commits.insert(hash, commit.hash.clone());
+        }
+    }
+
+    for (name, old) in roots {
+        let new = commits[&old].clone();
+        match name {
+            Some((kind, name)) if new != old => refs.update_ref(kind, &name, Some(&old), &new, "migrate: rehash")?,
+            None if new != old => refs.set_head(&HeadRefStub::Commit(hash_to_hex_string(&new)), "migrate: rehash")?,
+            _ => {}
+        }
+    }
+    Ok(commits.len())
+}
+
+// Rebuilds the trees of an older format version which no commit reached, storing them under their
+// current hashes. Returns the number of rebuilt trees.
+fn rehash_trees<D: VcDigest>(store: &mut dyn ObjectStore<D>, trees: &mut HashMap<VcHash<D>, Tree<D>>) -> Result<usize, ObjectError> {
+    let hashes: Vec<VcHash<D>> = store.iter()?.collect();
+    let mut rehashed = 0;
+    for hash in hashes {
+        if trees.contains_key(&hash) {
+            continue
+        }
+        let Some((ObjectType::Tree, body)) = store.get(&hash)? else { continue };
+        let stub: TreeStub = decode_stub::<D, _>(&store.location(&hash), &body)?;
+        if stub.format_version < REPOSITORY_FORMAT_VERSION {
+            rehash_tree(store, &hash, trees)?.to_store(store)?;
+            rehashed += 1;
+        }
+    }
+    Ok(rehashed)
+}
+
+// A repository whose config has been checked, so its objects can be read and written.
+// `D` has to be the digest the config names.
+#[derive(Debug)]
+pub struct Repository<D: VcDigest = VcHasher> {
+    root: PathBuf,
+    config: RepositoryConfig,
+    digest: PhantomData<D>
+}
+
+impl<D: VcDigest> Repository<D> {
+    // Creates a repository with the given config, or opens the one already there
+    pub fn init<P>(root: P, config: RepositoryConfig) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        if root.as_ref().join(CONFIG_FILE_NAME).is_file() {
+            return Self::open(root)
+        }
+        check_hash_algorithm::<D>(&root.as_ref().join(CONFIG_FILE_NAME), &config)?;
+        fs::create_dir_all(root.as_ref().join(OBJECTS_DIR_NAME))?;
+        write_config(root.as_ref(), &config)?;
+        Self::open(root)
+    }
+
+    // Opens a repository, refusing newer format versions and hash algorithms this version doesn't know,
+    // and older formats which have to be migrated first
+    pub fn open<P>(root: P) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        let config = read_config(root.as_ref())?;
+        let path = root.as_ref().join(CONFIG_FILE_NAME);
+        if config.format_version > REPOSITORY_FORMAT_VERSION {
+            return Err(ObjectError::UnsupportedFormatVersion { path, version: config.format_version })
+        }
+        check_hash_algorithm::<D>(&path, &config)?;
+        let migrations = config.pending_migrations();
+        if !migrations.is_empty() {
+            return Err(ObjectError::MigrationRequired { path, migrations })
+        }
+        Ok(Self { root: root.as_ref().to_path_buf(), config, digest: PhantomData })
+    }
+
+    // Runs whatever migrations the repository needs, then opens it
+    pub fn upgrade<P>(root: P) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        for migration in read_config(root.as_ref())?.pending_migrations() {
+            migrate(root.as_ref(), migration)?;
+        }
+        Self::open(root)
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn get_config(&self) -> &RepositoryConfig {
+        &self.config
+    }
+
+    pub fn get_objects_dir(&self) -> PathBuf {
+        self.root.join(OBJECTS_DIR_NAME)
+    }
+
+    pub fn object_store(&self) -> FsObjectStore<D> {
+        FsObjectStore::with_compression(self.get_objects_dir(), self.config.compression)
+    }
+
+    pub fn ref_store(&self) -> RefStore<D> {
+        RefStore::new(&self.root)
+    }
+}
+
+fn check_hash_algorithm<D: VcDigest>(path: &Path, config: &RepositoryConfig) -> Result<(), ObjectError> {
+    if !VC_DIGEST_NAMES.contains(&config.hash_algorithm.as_str()) {
+        return Err(ObjectError::UnsupportedHashAlgorithm(config.hash_algorithm.clone()))
+    }
+    if config.hash_algorithm != D::NAME {
+        return Err(ObjectError::HashAlgorithmMismatch {
+            path: path.to_path_buf(),
+            expected: D::NAME.to_string(),
+            actual: config.hash_algorithm.clone()
+        })
+    }
+    Ok(())
+}
+
+#[test]
+fn test_open_checks_config() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let config = RepositoryConfig { compression: Compression::Zstd, ..Default::default() };
+    let repo: Repository = Repository::init(tempdir.path(), config.clone()).unwrap();
+    assert_eq!(repo.get_config(), &config);
+    assert_eq!(Repository::<VcHasher>::open(tempdir.path()).unwrap().get_config(), &config);
+
+    let commit = sample_commit();
+    commit.to_store(&mut repo.object_store()).unwrap();
+    let stored = fs::read(object_path(repo.get_objects_dir(), &commit.get_hash_str())).unwrap();
+    assert_eq!(Compression::detect(&stored), Compression::Zstd);
+
+    // a newer config may have changed anything but its version
+    fs::write(tempdir.path().join(CONFIG_FILE_NAME), r#"{"format_version": 9, "layout": "something new"}"#).unwrap();
+    let err = Repository::<VcHasher>::open(tempdir.path()).unwrap_err();
+    assert!(matches!(err, ObjectError::UnsupportedFormatVersion { version: 9, .. }));
+    assert!(err.to_string().contains("unsupported repository format version 9"));
+
+    // xxh3 isn't cryptographic, so objects can't be named by it
+    write_config(tempdir.path(), &RepositoryConfig { hash_algorithm: "xxh3".to_string(), ..config.clone() }).unwrap();
+    assert!(matches!(Repository::<VcHasher>::open(tempdir.path()), Err(ObjectError::UnsupportedHashAlgorithm(a)) if a == "xxh3"));
+    write_config(tempdir.path(), &RepositoryConfig { hash_algorithm: "blake3".to_string(), ..config.clone() }).unwrap();
+    assert!(matches!(Repository::<VcHasher>::open(tempdir.path()), Err(ObjectError::HashAlgorithmMismatch { actual, .. }) if actual == "blake3"));
+    assert!(Repository::<blake3::Hasher>::open(tempdir.path()).is_ok());
+
+    write_config(tempdir.path(), &RepositoryConfig { hash_algorithm: "sha1".to_string(), ..config }).unwrap();
+    let err = Repository::<VcHasher>::open(tempdir.path()).unwrap_err();
+    assert!(matches!(&err, ObjectError::HashAlgorithmMismatch { expected, actual, .. } if expected == "sha256" && actual == "sha1"));
+    assert!(Repository::<sha1::Sha1>::open(tempdir.path()).is_ok());
+}
+
+#[test]
+fn test_sha1_repository() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let config = RepositoryConfig::with_digest::<sha1::Sha1>();
+    assert!(matches!(Repository::<VcHasher>::init(tempdir.path(), config.clone()), Err(ObjectError::HashAlgorithmMismatch { .. })));
+    let repo: Repository<sha1::Sha1> = Repository::init(tempdir.path(), config).unwrap();
+    assert_eq!(read_config(tempdir.path()).unwrap().hash_algorithm, "sha1");
+
+    let mut tree = Tree::new(HashMap::new());
+    tree.insert_path("src/main.rs", FsObject::Blob(Blob::new(b"fn main() {}")));
+    let commit = Commit::new(tree, None, "alice".to_string(), "init".to_string());
+    commit.to_store(&mut repo.object_store()).unwrap();
+    repo.ref_store().set_ref(RefKind::Head, "main", &commit.hash, "commit").unwrap();
+    assert_eq!(commit.get_hash_str().len(), 40);
+    assert_eq!(commit.tree.get_hash_str().len(), 40);
+
+    let loaded = Commit::from_store(&repo.object_store(), &commit.hash).unwrap();
+    assert_eq!(loaded.tree.hash, commit.tree.hash);
+    assert!(crate::vc_fsck::fsck_repository::<sha1::Sha1, _>(tempdir.path()).unwrap().is_ok());
+
+    // migrations work on the repository's own digest
+    assert_eq!(migrate(tempdir.path(), Migration::Reencode(StubEncoding::Json)).unwrap(), 3);
+    assert_eq!(Commit::from_store(&repo.object_store(), &commit.hash).unwrap().tree.hash, commit.tree.hash);
+}
+
+#[test]
+fn test_sha3_and_blake_repositories() {
+    fn check<D: VcDigest>() {
+        let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+        let repo: Repository<D> = Repository::init(tempdir.path(), RepositoryConfig::with_digest::<D>()).unwrap();
+        let mut tree = Tree::new(HashMap::new());
+        tree.insert_path("src/main.rs", FsObject::Blob(Blob::new(b"fn main() {}")));
+        let commit = Commit::<D>::new(tree, None, "alice".to_string(), "init".to_string());
+        commit.to_store(&mut repo.object_store()).unwrap();
+        assert_eq!(commit.get_hash_str().len(), 2 * <D as Digest>::output_size());
+        assert_eq!(migrate(tempdir.path(), Migration::Reencode(StubEncoding::Json)).unwrap(), 3, "{}", D::NAME);
+        assert_eq!(Commit::from_store(&repo.object_store(), &commit.hash).unwrap().tree.hash, commit.tree.hash);
+    }
+    check::<sha3::Sha3_256>();
+    check::<sha3::Sha3_512>();
+    check::<blake2::Blake2b512>();
+    check::<blake2::Blake2s256>();
+    check::<blake3::Hasher>();
+}
+
+#[test]
+fn test_upgrade_legacy_repository() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let objects_dir = tempdir.path().join(OBJECTS_DIR_NAME);
+    fs::create_dir_all(&objects_dir).unwrap();
+
+    // a version 1 repository as `Blob::to_file` and `Tree::to_file` wrote it: flat, without headers,
+    // blobs as their raw bytes and trees as JSON stubs, hashed over the canonical tree encoding
+    let write_blob = |data: &[u8]| {
+        let hashstr = hash_to_hex_string(&hash::<VcHasher>(data));
+        fs::write(objects_dir.join(&hashstr), data).unwrap();
+        hashstr
+    };
+    let write_tree = |entries: &[(&str, FsObjectType, &str)]| {
+        let mut sorted = entries.to_vec();
+        sorted.sort_by_key(|(name, _, _)| *name);
+        let mut data = b"tree\0".to_vec();
+        for (name, fs_object_type, hashstr) in &sorted {
+            let (mode, type_name) = match fs_object_type {
+                FsObjectType::Tree => (0o040000, "tree"),
+                _ => (0o100644, "blob")
+            };
+            data.extend_from_slice(format!("{:o} {} {}\0", mode, type_name, name).as_bytes());
+            data.extend_from_slice(&hex_string_to_hash::<VcHasher>(hashstr).unwrap());
+        }
+        let stub = TreeStub {
+            listings: entries.iter().map(|(name, fs_object_type, hashstr)| (name.to_string(), (fs_object_type.clone(), hashstr.to_string()))).collect(),
+            hashstr: hash_to_hex_string(&hash::<VcHasher>(&data)),
+            format_version: 1
+        };
+        fs::write(objects_dir.join(&stub.hashstr), stub.serialize_json().unwrap()).unwrap();
+        stub.hashstr
+    };
+    let main_hashstr = write_blob(b"fn main() {}");
+    let lib_hashstr = write_tree(&[("mod.rs", FsObjectType::Blob, &write_blob(b""))]);
+    let src_hashstr = write_tree(&[("main.rs", FsObjectType::Blob, &main_hashstr), ("lib", FsObjectType::Tree, &lib_hashstr)]);
+    let readme_hashstr = write_blob(b"readme");
+    write_tree(&[("README", FsObjectType::Blob, &readme_hashstr), ("src", FsObjectType::Tree, &src_hashstr)]);
+    // a blob which happens to parse as a stub is still read as a blob where one is listed
+    let stub_like = TreeStub { listings: HashMap::new(), hashstr: String::new(), format_version: 1 }.serialize_json().unwrap();
+    let stub_like_hashstr = write_blob(stub_like.as_bytes());
+    write_tree(&[("empty.json", FsObjectType::Blob, &stub_like_hashstr)]);
+
+    let err = Repository::<VcHasher>::open(tempdir.path()).unwrap_err();
+    assert!(matches!(&err, ObjectError::MigrationRequired { migrations, .. } if *migrations == vec![Migration::Fanout, Migration::Rehash]));
+    assert!(err.to_string().contains("fan out loose objects, rehash objects"));
+
+    let repo: Repository = Repository::upgrade(tempdir.path()).unwrap();
+    // the encoding is only changed when asked to
+    assert_eq!(repo.get_config(), &RepositoryConfig { object_encoding: StubEncoding::Json, ..Default::default() });
+    let tree = sample_tree();
+    let loaded = Tree::from_store(&repo.object_store(), &tree.hash).unwrap();
+    let Some(FsObject::Blob(main)) = loaded.get_path("src/main.rs") else { panic!() };
+    assert_eq!(main.get_data(), b"fn main() {}");
+    let mut stub_like_tree: Tree = Tree::new(HashMap::new());
+    stub_like_tree.insert_path("empty.json", FsObject::Blob(Blob::new(stub_like.as_bytes())));
+    let loaded = Tree::from_store(&repo.object_store(), &stub_like_tree.hash).unwrap();
+    assert!(matches!(loaded.get_path("empty.json"), Some(FsObject::Blob(b)) if b.get_data() == stub_like.as_bytes()));
+    // the old objects are left to gc, and nothing asks to be migrated again
+    assert!(object_path(repo.get_objects_dir(), &src_hashstr).is_file());
+    assert_eq!(Repository::<VcHasher>::open(tempdir.path()).unwrap().get_config(), repo.get_config());
+}
+
+#[test]
+fn test_reencode_migration() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let repo: Repository = Repository::init(tempdir.path(), RepositoryConfig::default()).unwrap();
+    let commit = sample_commit();
+    commit.to_store(&mut repo.object_store()).unwrap();
+    let read_body = |hashstr: &str| read_object(object_path(repo.get_objects_dir(), hashstr)).unwrap().1;
+
+    // three blobs are left as they are
+    assert_eq!(migrate(tempdir.path(), Migration::Reencode(StubEncoding::Json)).unwrap(), 4);
+    assert_eq!(StubEncoding::detect(&read_body(&commit.get_hash_str())), StubEncoding::Json);
+    assert_eq!(StubEncoding::detect(&read_body(&commit.tree.get_hash_str())), StubEncoding::Json);
+    assert_eq!(read_config(tempdir.path()).unwrap().object_encoding, StubEncoding::Json);
+    assert_eq!(Commit::from_store(&repo.object_store(), &commit.hash).unwrap().tree.hash, commit.tree.hash);
+
+    assert_eq!(migrate(tempdir.path(), Migration::Reencode(StubEncoding::Binary)).unwrap(), 4);
+    assert_eq!(migrate(tempdir.path(), Migration::Reencode(StubEncoding::Binary)).unwrap(), 0);
+    assert_eq!(StubEncoding::detect(&read_body(&commit.get_hash_str())), StubEncoding::Binary);
+    assert!(Repository::<VcHasher>::open(tempdir.path()).is_ok());
+}
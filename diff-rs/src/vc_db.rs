@@ -0,0 +1,220 @@
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use digest::Digest;
+use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
+
+use crate::hashing::*;
+use crate::vc::*;
+use crate::vc_object::*;
+use crate::vc_store::*;
+
+// File name of the object database within a repository
+pub const OBJECT_DB_FILE_NAME: &str = "objects.redb";
+
+// Objects keyed by hash bytes, stored with their header but uncompressed
+const OBJECTS_TABLE: TableDefinition<&[u8], &[u8]> = TableDefinition::new("objects");
+
+fn db_error<E>(e: E) -> ObjectError
+where E: Into<redb::Error>
+{
+    ObjectError::Database(Box::new(e.into()))
+}
+
+fn decode_value(path: &Path, value: &[u8]) -> Result<StoredObject, ObjectError> {
+    let (object_type, body) = decode_object(value, path)?;
+    Ok((object_type, body.into()))
+}
+
+// Objects kept in an embedded database file. Writes made through a `DbTransaction` become visible
+// all at once when it is committed, and not at all if it is dropped or the process dies first.
+pub struct DbObjectStore<D: VcDigest = VcHasher> {
+    db: Database,
+    path: PathBuf,
+    digest: PhantomData<D>
+}
+
+impl<D: VcDigest> DbObjectStore<D> {
+    // Opens the database, creating it if it doesn't exist
+    pub fn open<P>(path: P) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        let db = Database::create(path.as_ref()).map_err(db_error)?;
+        let txn = db.begin_write().map_err(db_error)?;
+        txn.open_table(OBJECTS_TABLE).map_err(db_error)?;
+        txn.commit().map_err(db_error)?;
+        Ok(Self {
+            db,
+            path: path.as_ref().to_path_buf(),
+            digest: PhantomData
+        })
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn begin(&self) -> Result<DbTransaction<D>, ObjectError> {
+        Ok(DbTransaction {
+            txn: self.db.begin_write().map_err(db_error)?,
+            path: self.path.clone(),
+            digest: PhantomData
+        })
+    }
+
+    // Runs `f` in a transaction, committing its writes only if it succeeds
+    pub fn transaction<T, F>(&self, f: F) -> Result<T, ObjectError>
+    where F: FnOnce(&mut DbTransaction<D>) -> Result<T, ObjectError>
+    {
+        let mut txn = self.begin()?;
+        let result = f(&mut txn)?;
+        txn.commit()?;
+        Ok(result)
+    }
+}
+
+impl<D: VcDigest> ObjectStore<D> for DbObjectStore<D> {
+    fn put(&mut self, hash: &VcHash<D>, object_type: ObjectType, body: &[u8]) -> Result<(), ObjectError> {
+        self.transaction(|txn| txn.put(hash, object_type, body))
+    }
+
+    fn get(&self, hash: &VcHash<D>) -> Result<Option<StoredObject>, ObjectError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(OBJECTS_TABLE).map_err(db_error)?;
+        let value = table.get(hash.as_slice()).map_err(db_error)?;
+        value.map(|v| decode_value(&self.location(hash), v.value())).transpose()
+    }
+
+    fn contains(&self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(OBJECTS_TABLE).map_err(db_error)?;
+        Ok(table.get(hash.as_slice()).map_err(db_error)?.is_some())
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = VcHash<D>> + '_>, ObjectError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(OBJECTS_TABLE).map_err(db_error)?;
+        Ok(Box::new(collect_hashes::<D, _>(&table)?.into_iter()))
+    }
+
+    fn delete(&mut self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        self.transaction(|txn| txn.delete(hash))
+    }
+
+    fn location(&self, hash: &VcHash<D>) -> PathBuf {
+        self.path.join(hash_to_hex_string(hash))
+    }
+}
+
+// Keys of another length were written under a different digest
+fn collect_hashes<D, T>(table: &T) -> Result<Vec<VcHash<D>>, ObjectError>
+where D: VcDigest, T: ReadableTable<&'static [u8], &'static [u8]>
+{
+    let mut hashes = Vec::new();
+    for entry in table.iter().map_err(db_error)? {
+        let (key, _) = entry.map_err(db_error)?;
+        if key.value().len() == <D as Digest>::output_size() {
+            hashes.push(VcHash::<D>::clone_from_slice(key.value()));
+        }
+    }
+    Ok(hashes)
+}
+
+// A write transaction on a `DbObjectStore`. Reads through it see its own uncommitted writes.
+pub struct DbTransaction<D: VcDigest = VcHasher> {
+    txn: WriteTransaction,
+    path: PathBuf,
+    digest: PhantomData<D>
+}
+
+impl<D: VcDigest> DbTransaction<D> {
+    pub fn commit(self) -> Result<(), ObjectError> {
+        self.txn.commit().map_err(db_error)
+    }
+
+    pub fn abort(self) -> Result<(), ObjectError> {
+        self.txn.abort().map_err(db_error)
+    }
+}
+
+impl<D: VcDigest> ObjectStore<D> for DbTransaction<D> {
+    fn put(&mut self, hash: &VcHash<D>, object_type: ObjectType, body: &[u8]) -> Result<(), ObjectError> {
+        let mut table = self.txn.open_table(OBJECTS_TABLE).map_err(db_error)?;
+        if table.get(hash.as_slice()).map_err(db_error)?.is_none() {
+            table.insert(hash.as_slice(), encode_object(object_type, body).as_slice()).map_err(db_error)?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, hash: &VcHash<D>) -> Result<Option<StoredObject>, ObjectError> {
+        let table = self.txn.open_table(OBJECTS_TABLE).map_err(db_error)?;
+        let value = table.get(hash.as_slice()).map_err(db_error)?;
+        value.map(|v| decode_value(&self.location(hash), v.value())).transpose()
+    }
+
+    fn contains(&self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        let table = self.txn.open_table(OBJECTS_TABLE).map_err(db_error)?;
+        let found = table.get(hash.as_slice()).map_err(db_error)?.is_some();
+        Ok(found)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = VcHash<D>> + '_>, ObjectError> {
+        let table = self.txn.open_table(OBJECTS_TABLE).map_err(db_error)?;
+        let hashes = collect_hashes::<D, _>(&table)?;
+        Ok(Box::new(hashes.into_iter()))
+    }
+
+    fn delete(&mut self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        let mut table = self.txn.open_table(OBJECTS_TABLE).map_err(db_error)?;
+        let removed = table.remove(hash.as_slice()).map_err(db_error)?.is_some();
+        Ok(removed)
+    }
+
+    fn location(&self, hash: &VcHash<D>) -> PathBuf {
+        self.path.join(hash_to_hex_string(hash))
+    }
+}
+
+#[test]
+fn test_db_store_round_trip() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let path = tempdir.path().join(OBJECT_DB_FILE_NAME);
+    let commit = sample_commit();
+    {
+        let mut store: DbObjectStore = DbObjectStore::open(&path).unwrap();
+        commit.to_store(&mut store).unwrap();
+        assert_eq!(store.iter().unwrap().count(), 7);
+    }
+
+    // objects persist across reopening
+    let mut store: DbObjectStore = DbObjectStore::open(&path).unwrap();
+    let loaded = Commit::from_store(&store, &commit.hash).unwrap();
+    assert_eq!(loaded.tree.hash, commit.tree.hash);
+    let Some(FsObject::Blob(blob)) = commit.tree.get_path("README") else { panic!() };
+    assert!(store.delete(&blob.hash).unwrap());
+    assert!(!store.contains(&blob.hash).unwrap());
+    assert!(matches!(Commit::from_store(&store, &commit.hash), Err(ObjectError::NotFound(_))));
+}
+
+#[test]
+fn test_db_transaction_is_atomic() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let store = DbObjectStore::open(tempdir.path().join(OBJECT_DB_FILE_NAME)).unwrap();
+    let commit = sample_commit();
+
+    // a failing transaction leaves nothing behind, even what it wrote before failing
+    let result = store.transaction(|txn| {
+        commit.tree.to_store(txn)?;
+        assert!(txn.contains(&commit.tree.hash)?);
+        Err::<(), _>(ObjectError::NotFound("oops".to_string()))
+    });
+    assert!(result.is_err());
+    assert_eq!(store.iter().unwrap().count(), 0);
+
+    // nothing is visible to readers until the commit
+    let mut txn = store.begin().unwrap();
+    commit.to_store(&mut txn).unwrap();
+    assert!(Commit::from_store(&txn, &commit.hash).is_ok());
+    assert!(!store.contains(&commit.hash).unwrap());
+    txn.commit().unwrap();
+    assert!(Commit::from_store(&store, &commit.hash).is_ok());
+}
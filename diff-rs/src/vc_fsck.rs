@@ -0,0 +1,241 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::Display;
+use std::path::Path;
+
+use crate::hashing::*;
+use crate::vc::*;
+use crate::vc_binary::*;
+use crate::vc_object::*;
+use crate::vc_refs::*;
+use crate::vc_serialize::*;
+use crate::vc_store::*;
+
+#[derive(Debug)]
+pub enum FsckError<D: VcDigest = VcHasher> {
+    // the object can't be read, decoded or parsed
+    Corrupt { hash: VcHash<D>, error: ObjectError },
+    // the object's contents hash to something other than its name
+    HashMismatch { hash: VcHash<D>, actual: VcHash<D> },
+    // an object refers to, or a ref points at, an object which isn't stored.
+    // `referenced_by` is None for refs.
+    Missing { hash: VcHash<D>, referenced_by: Option<VcHash<D>> },
+    // an object is referred to as one type but stored as another
+    WrongType { hash: VcHash<D>, referenced_by: Option<VcHash<D>>, expected: ObjectType, actual: ObjectType },
+    // a listing or reference isn't a valid hash
+    InvalidReference { referenced_by: VcHash<D>, reference: String }
+}
+
+impl<D: VcDigest> Display for FsckError<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let referrer = |referenced_by: &Option<VcHash<D>>| match referenced_by {
+            Some(hash) => hash_to_hex_string(hash),
+            None => "a ref".to_string()
+        };
+        match self {
+            FsckError::Corrupt { hash, error } => write!(f, "corrupt object {}: {}", hash_to_hex_string(hash), error),
+            FsckError::HashMismatch { hash, actual } =>
+                write!(f, "object {} hashes to {}", hash_to_hex_string(hash), hash_to_hex_string(actual)),
+            FsckError::Missing { hash, referenced_by } =>
+                write!(f, "missing object {} referenced by {}", hash_to_hex_string(hash), referrer(referenced_by)),
+            FsckError::WrongType { hash, referenced_by, expected, actual } =>
+                write!(f, "object {} referenced by {} as a {} is a {}", hash_to_hex_string(hash), referrer(referenced_by), expected, actual),
+            FsckError::InvalidReference { referenced_by, reference } =>
+                write!(f, "invalid reference {} in {}", reference, hash_to_hex_string(referenced_by))
+        }
+    }
+}
+
+impl<D: VcDigest> Error for FsckError<D> {}
+
+#[derive(Debug)]
+pub struct FsckReport<D: VcDigest = VcHasher> {
+    pub checked: usize,
+    pub errors: Vec<FsckError<D>>,
+    // objects neither referred to by another object nor by a ref
+    pub dangling: Vec<VcHash<D>>
+}
+
+impl<D: VcDigest> Default for FsckReport<D> {
+    fn default() -> Self {
+        Self { checked: 0, errors: Vec::new(), dangling: Vec::new() }
+    }
+}
+
+impl<D: VcDigest> FsckReport<D> {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+// (object referred to, type it is expected to be, object referring to it)
+type Reference<D> = (VcHash<D>, ObjectType, Option<VcHash<D>>);
+
+// Re-hashes every object of the store, parsing trees and commits to check that what they refer to
+// is stored and of the right type. `roots` are the commits refs point at.
+pub fn fsck<D: VcDigest>(store: &dyn ObjectStore<D>, roots: &[VcHash<D>]) -> Result<FsckReport<D>, ObjectError> {
+    let mut report = FsckReport::default();
+    let mut hashes: Vec<VcHash<D>> = store.iter()?.collect();
+    hashes.sort();
+
+    let mut types: HashMap<VcHash<D>, ObjectType> = HashMap::new();
+    let mut corrupt: HashSet<VcHash<D>> = HashSet::new();
+    let mut references: Vec<Reference<D>> = roots.iter().map(|h| (h.clone(), ObjectType::Commit, None)).collect();
+    for hash in hashes {
+        report.checked += 1;
+        let (object_type, body) = match store.get(&hash) {
+            Ok(Some(obj)) => obj,
+            Ok(None) => continue,
+            Err(error) => {
+                corrupt.insert(hash.clone());
+                report.errors.push(FsckError::Corrupt { hash, error });
+                continue
+            }
+        };
+        match check_object(store, &hash, object_type, &body, &mut references, &mut report.errors) {
+            Ok(actual) if actual != hash => report.errors.push(FsckError::HashMismatch { hash: hash.clone(), actual }),
+            Ok(_) => {},
+            Err(error) => {
+                corrupt.insert(hash.clone());
+                report.errors.push(FsckError::Corrupt { hash, error });
+                continue
+            }
+        }
+        types.insert(hash, object_type);
+    }
+
+    let mut referenced = HashSet::new();
+    for (hash, expected, referenced_by) in references {
+        referenced.insert(hash.clone());
+        match types.get(&hash) {
+            Some(actual) if *actual != expected => report.errors.push(FsckError::WrongType { hash, referenced_by, expected, actual: *actual }),
+            Some(_) => {},
+            None if !corrupt.contains(&hash) => report.errors.push(FsckError::Missing { hash, referenced_by }),
+            None => {}
+        }
+    }
+    report.dangling = types.keys().filter(|h| !referenced.contains(*h)).cloned().collect();
+    report.dangling.sort();
+    Ok(report)
+}
+
+// Computes the hash of an object from its body, collecting what it refers to
+fn check_object<D: VcDigest>(
+    store: &dyn ObjectStore<D>,
+    hash: &VcHash<D>,
+    object_type: ObjectType,
+    body: &[u8],
+    references: &mut Vec<Reference<D>>,
+    errors: &mut Vec<FsckError<D>>
+) -> Result<VcHash<D>, ObjectError> {
+    let path = store.location(hash);
+    let mut reference = |hashstr: &str, expected: ObjectType| match hex_string_to_hash::<D>(hashstr) {
+        Ok(target) => {
+            references.push((target.clone(), expected, Some(hash.clone())));
+            Some(target)
+        },
+        Err(_) => {
+            errors.push(FsckError::InvalidReference { referenced_by: hash.clone(), reference: hashstr.to_string() });
+            None
+        }
+    };
+    match object_type {
+        ObjectType::Blob => Ok(object_hash::<D>(ObjectType::Blob, body)),
+        ObjectType::Tree => {
+            let stub: TreeStub = decode_stub::<D, _>(&path, body)?;
+            let mut entries = Vec::new();
+            for (name, (fs_object_type, hashstr)) in &stub.listings {
+                let expected = match fs_object_type {
+                    FsObjectType::Blob | FsObjectType::Executable => ObjectType::Blob,
+                    FsObjectType::Tree => ObjectType::Tree
+                };
+                if let Some(target) = reference(hashstr, expected) {
+                    entries.push((name.as_str(), fs_object_type.get_mode(), target));
+                }
+            }
+            Ok(Tree::<D>::hash_entries(entries))
+        },
+        ObjectType::Commit => {
+            let stub: CommitStub = decode_stub::<D, _>(&path, body)?;
+            let tree_hash = reference(&stub.tree_hashstr, ObjectType::Tree);
+            let parent_hash = stub.parent_hashstr.as_ref().map(|p| reference(p, ObjectType::Commit));
+            match (tree_hash, parent_hash) {
+                (Some(tree_hash), None) => Ok(Commit::<D>::hash_fields(&tree_hash, None, &stub.author, &stub.message, stub.timestamp)),
+                (Some(tree_hash), Some(Some(parent_hash))) =>
+                    Ok(Commit::<D>::hash_fields(&tree_hash, Some(&parent_hash), &stub.author, &stub.message, stub.timestamp)),
+                // the invalid reference is already reported
+                _ => Ok(hash.clone())
+            }
+        },
+        ObjectType::Tag => Err(ObjectError::UnsupportedType(ObjectType::Tag))
+    }
+}
+
+// Checks the objects of a repository against its refs, HEAD and reflogs
+pub fn fsck_repository<D, P>(root: P) -> Result<FsckReport<D>, ObjectError>
+where D: VcDigest, P: AsRef<Path>
+{
+    let roots = RefStore::<D>::new(root.as_ref()).all_referenced()?;
+    fsck(&FsObjectStore::new(root.as_ref().join(OBJECTS_DIR_NAME)), &roots)
+}
+
+#[test]
+fn test_fsck_clean_repository() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let commit = sample_commit();
+    commit.to_file(tempdir.path().join(OBJECTS_DIR_NAME)).unwrap();
+    RefStore::<VcHasher>::new(tempdir.path()).set_ref(RefKind::Head, "main", &commit.hash, "commit").unwrap();
+
+    let report = fsck_repository::<VcHasher, _>(tempdir.path()).unwrap();
+    assert!(report.is_ok(), "{:?}", report.errors);
+    assert_eq!(report.checked, 7);
+    assert!(report.dangling.is_empty());
+}
+
+#[test]
+fn test_fsck_finds_broken_objects() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let objects_dir = tempdir.path().join(OBJECTS_DIR_NAME);
+    let commit = sample_commit();
+    commit.to_file(&objects_dir).unwrap();
+    let Some(FsObject::Blob(readme)) = commit.tree.get_path("README") else { panic!() };
+    let Some(FsObject::Blob(main)) = commit.tree.get_path("src/main.rs") else { panic!() };
+    let Some(FsObject::Tree(src)) = commit.tree.get_path("src") else { panic!() };
+    let Some(FsObject::Tree(lib)) = commit.tree.get_path("src/lib") else { panic!() };
+
+    // a truncated write, a silently changed file and a lost file
+    let mut truncated = encode_object(ObjectType::Blob, readme.get_data());
+    truncated.pop();
+    std::fs::write(object_path(&objects_dir, &readme.get_hash_str()), truncated).unwrap();
+    std::fs::write(object_path(&objects_dir, &main.get_hash_str()), encode_object(ObjectType::Blob, b"fn main() { panic!() }")).unwrap();
+    std::fs::remove_file(object_path(&objects_dir, &src.get_hash_str())).unwrap();
+    let orphan: Blob = Blob::new(b"orphan");
+    orphan.to_file(&objects_dir).unwrap();
+
+    let report = fsck(&FsObjectStore::<VcHasher>::new(&objects_dir), &[commit.hash]).unwrap();
+    assert_eq!(report.errors.len(), 3, "{:?}", report.errors);
+    assert!(report.errors.iter().any(|e| matches!(e, FsckError::Corrupt { hash, error: ObjectError::LengthMismatch { .. } } if *hash == readme.hash)));
+    assert!(report.errors.iter().any(|e| matches!(e, FsckError::HashMismatch { hash, .. } if *hash == main.hash)));
+    assert!(report.errors.iter().any(|e| matches!(e, FsckError::Missing { hash, referenced_by: Some(by) } if *hash == src.hash && *by == commit.tree.hash)));
+    // the changed blob and the subtree are no longer referred to by anything, as their parent tree is gone
+    assert_eq!(report.dangling, {
+        let mut dangling = vec![orphan.hash, main.hash, lib.hash];
+        dangling.sort();
+        dangling
+    });
+}
+
+#[test]
+fn test_fsck_wrong_type_and_missing_ref() {
+    let mut store = MemoryObjectStore::new();
+    let commit = sample_commit();
+    commit.to_store(&mut store).unwrap();
+    let Some(FsObject::Tree(src)) = commit.tree.get_path("src") else { panic!() };
+    store.delete(&src.hash).unwrap();
+    store.put(&src.hash, ObjectType::Blob, b"not a tree").unwrap();
+    let missing = Blob::<VcHasher>::new(b"missing").hash;
+
+    let report = fsck(&store, &[commit.hash, missing]).unwrap();
+    assert!(report.errors.iter().any(|e| matches!(e, FsckError::WrongType { expected: ObjectType::Tree, actual: ObjectType::Blob, .. })));
+    assert!(report.errors.iter().any(|e| matches!(e, FsckError::Missing { hash, referenced_by: None } if *hash == missing)));
+}
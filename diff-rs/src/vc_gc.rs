@@ -0,0 +1,183 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::merkle::*;
+use crate::vc::*;
+use crate::vc_lock::*;
+use crate::vc_object::*;
+use crate::vc_refs::*;
+use crate::vc_store::*;
+
+// Only one gc may run on a repository at a time, holding the lock on this file of the repository
+pub const GC_LOCK_NAME: &str = "gc";
+
+// Unreachable objects younger than this may belong to a commit which is still being written
+pub const DEFAULT_GC_GRACE_PERIOD: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    pub grace_period: Duration,
+    // only report what would be removed
+    pub dry_run: bool
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            grace_period: DEFAULT_GC_GRACE_PERIOD,
+            dry_run: false
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GcReport<D: VcDigest = VcHasher> {
+    pub reachable: usize,
+    // every unreachable object, including those kept for being too young
+    pub unreachable: Vec<VcHash<D>>,
+    // the unreachable objects which were removed, or would be in a dry run. Objects the store
+    // can't delete, such as packed ones, are left out.
+    pub pruned: Vec<VcHash<D>>,
+    pub reclaimable_bytes: u64
+}
+
+impl<D: VcDigest> Default for GcReport<D> {
+    fn default() -> Self {
+        Self { reachable: 0, unreachable: Vec::new(), pruned: Vec::new(), reclaimable_bytes: 0 }
+    }
+}
+
+// Marks every object reachable from the given commits, following parents and walking each
+// commit's tree. Roots which aren't in the store are skipped, as reflogs may outlive their commits,
+// but any other missing object fails the walk so that nothing is swept on an incomplete mark.
+pub fn mark_reachable<D: VcDigest>(store: &dyn ObjectStore<D>, roots: &[VcHash<D>]) -> Result<HashSet<VcHash<D>>, ObjectError> {
+    let mut reachable = HashSet::new();
+    let mut pending = Vec::new();
+    for root in roots {
+        if store.contains(root)? {
+            pending.push(root.clone());
+        }
+    }
+    while let Some(hash) = pending.pop() {
+        if reachable.contains(&hash) {
+            continue
+        }
+        let obj = VcObject::from_store(store, &hash)?;
+        if let VcObject::Commit(commit) = &obj {
+            pending.extend(commit.parent_hash.clone());
+        }
+        mark(&obj, &mut reachable);
+    }
+    Ok(reachable)
+}
+
+fn mark<D: VcDigest>(node: &dyn MerkleNode<D>, reachable: &mut HashSet<VcHash<D>>) {
+    if reachable.insert(node.get_hash()) {
+        for child in node.get_children() {
+            mark(child, reachable);
+        }
+    }
+}
+
+// Sweeps the objects of the store not reachable from `roots` and older than the grace period.
+// Objects the store can't date count as old, and those it can't delete are only reported as unreachable.
+pub fn gc<D: VcDigest>(store: &mut dyn ObjectStore<D>, roots: &[VcHash<D>], options: &GcOptions) -> Result<GcReport<D>, ObjectError> {
+    let reachable = mark_reachable(store, roots)?;
+    let mut report = GcReport { reachable: reachable.len(), ..Default::default() };
+    let mut unreachable: Vec<VcHash<D>> = store.iter()?.filter(|h| !reachable.contains(h)).collect();
+    unreachable.sort();
+
+    let now = SystemTime::now();
+    for hash in &unreachable {
+        let Some(stat) = store.stat(hash)? else { continue };
+        let age = stat.modified.map_or(Duration::MAX, |modified| now.duration_since(modified).unwrap_or_default());
+        if age < options.grace_period || !stat.deletable {
+            continue
+        }
+        if options.dry_run || store.delete(hash)? {
+            report.pruned.push(hash.clone());
+            report.reclaimable_bytes += stat.size;
+        }
+    }
+    report.unreachable = unreachable;
+    Ok(report)
+}
+
+// Collects the garbage of a repository, keeping everything its refs, HEAD and reflogs reach
+pub fn gc_repository<D, P>(root: P, options: &GcOptions) -> Result<GcReport<D>, ObjectError>
+where D: VcDigest, P: AsRef<Path>
+{
+    let _lock = LockFile::try_acquire(root.as_ref().join(GC_LOCK_NAME))?;
+    let roots = RefStore::<D>::new(root.as_ref()).all_referenced()?;
+    gc(&mut FsObjectStore::new(root.as_ref().join(OBJECTS_DIR_NAME)), &roots, options)
+}
+
+#[test]
+fn test_mark_reachable_follows_parents_and_trees() {
+    let mut store = MemoryObjectStore::new();
+    let first = Commit::new(tree_of(&[("a.txt", "a"), ("src/b.txt", "b")]), None, "alice".to_string(), "first".to_string());
+    let second = Commit::new(tree_of(&[("a.txt", "a2")]), Some(&first), "alice".to_string(), "second".to_string());
+    second.to_store(&mut store).unwrap();
+    first.to_store(&mut store).unwrap();
+    let orphan = Blob::new(b"orphan");
+    orphan.to_store(&mut store).unwrap();
+
+    let reachable = mark_reachable(&store, &[second.hash, Blob::<VcHasher>::new(b"gone").hash]).unwrap();
+    // two commits, three trees and three blobs
+    assert_eq!(reachable.len(), 8);
+    assert!(!reachable.contains(&orphan.hash));
+
+    let report = gc(&mut store, &[second.hash], &GcOptions::default()).unwrap();
+    assert_eq!(report.pruned, vec![orphan.hash]);
+    assert_eq!(report.reclaimable_bytes, 6);
+    assert!(!store.contains(&orphan.hash).unwrap());
+}
+
+#[test]
+fn test_gc_repository() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let objects_dir = tempdir.path().join(OBJECTS_DIR_NAME);
+    let refs: RefStore = RefStore::new(tempdir.path());
+    let first = Commit::new(tree_of(&[("a.txt", "a")]), None, "alice".to_string(), "first".to_string());
+    let rewritten = Commit::new(tree_of(&[("a.txt", "rewritten")]), None, "alice".to_string(), "rewritten".to_string());
+    first.to_file(&objects_dir).unwrap();
+    rewritten.to_file(&objects_dir).unwrap();
+    refs.set_ref(RefKind::Head, "main", &first.hash, "commit").unwrap();
+    refs.set_ref(RefKind::Head, "main", &rewritten.hash, "rewrite").unwrap();
+
+    // left behind by an aborted commit
+    let orphan: Blob = Blob::new(b"orphaned blob");
+    orphan.to_file(&objects_dir).unwrap();
+
+    let lock = LockFile::try_acquire(tempdir.path().join(GC_LOCK_NAME)).unwrap();
+    assert!(matches!(gc_repository::<VcHasher, _>(tempdir.path(), &GcOptions::default()), Err(ObjectError::Lock(LockError::Held { .. }))));
+    drop(lock);
+
+    // too young to be swept by default
+    let report = gc_repository::<VcHasher, _>(tempdir.path(), &GcOptions::default()).unwrap();
+    assert_eq!(report.unreachable, vec![orphan.hash]);
+    assert!(report.pruned.is_empty());
+
+    let options = GcOptions { grace_period: Duration::ZERO, dry_run: true };
+    let report = gc_repository::<VcHasher, _>(tempdir.path(), &options).unwrap();
+    assert_eq!(report.pruned, vec![orphan.hash]);
+    let orphan_path = object_path(&objects_dir, &orphan.get_hash_str());
+    assert_eq!(report.reclaimable_bytes, std::fs::metadata(&orphan_path).unwrap().len());
+    assert!(orphan_path.exists());
+
+    // the rewritten-away commit is still reachable through the reflog
+    let report = gc_repository::<VcHasher, _>(tempdir.path(), &GcOptions { dry_run: false, ..options }).unwrap();
+    assert_eq!(report.pruned, vec![orphan.hash]);
+    assert!(!orphan_path.exists());
+    assert!(Commit::from_store(&FsObjectStore::<VcHasher>::new(&objects_dir), &first.hash).is_ok());
+
+    // packed objects stay until their pack is rewritten, so they aren't promised in a dry run either
+    let packed_orphan: Blob = Blob::new(b"packed orphan");
+    packed_orphan.to_file(&objects_dir).unwrap();
+    crate::vc_pack::repack::<VcHasher, _>(&objects_dir, &Default::default()).unwrap();
+    let report = gc_repository::<VcHasher, _>(tempdir.path(), &options).unwrap();
+    assert_eq!(report.unreachable, vec![packed_orphan.hash]);
+    assert!(report.pruned.is_empty());
+    assert_eq!(report.reclaimable_bytes, 0);
+}
@@ -0,0 +1,233 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+
+use crate::vc_serialize::*;
+
+// A file is locked by creating `<file>.lock` next to it, which fails while another process holds the lock
+pub const LOCK_SUFFIX: &str = ".lock";
+// How long to keep retrying a held lock before giving up
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+// Locks older than this are assumed to have been left by a process which died holding them,
+// when the process can't be looked for
+pub const STALE_LOCK_AGE: Duration = Duration::from_secs(10 * 60);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// Who holds a lock, as written into the lock file
+#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub created: SystemTime,
+    // where the pid means something, see `process_namespace`. Locks written without one load as None.
+    #[serde(default)]
+    pub namespace: Option<String>
+}
+impl SerializeDeserializeJson for LockOwner {}
+
+// Identifies the PID namespace of this process across the hosts and containers sharing a repository:
+// the host name, the boot and the namespace itself. None where it can't be told.
+pub fn process_namespace() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+        let boot_id = fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+        let pid_ns = fs::read_link("/proc/self/ns/pid").ok()?;
+        Some(format!("{}/{}/{}", hostname.trim(), boot_id.trim(), pid_ns.display()))
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            created: SystemTime::now(),
+            namespace: process_namespace()
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        SystemTime::now().duration_since(self.created).unwrap_or_default()
+    }
+
+    // Whether the owner is known to be gone. An owner in this process's namespace is looked for,
+    // however old the lock; one elsewhere, e.g. on another host sharing the repository, can't be,
+    // and is only taken to be gone once the lock is too old.
+    pub fn is_stale(&self) -> bool {
+        match process_namespace() {
+            Some(namespace) if self.namespace.as_ref() == Some(&namespace) => !Path::new("/proc").join(self.pid.to_string()).exists(),
+            _ => self.age() > STALE_LOCK_AGE
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    Io(std::io::Error),
+    // another live process holds the lock. The owner is None if the lock file is still being written.
+    Held { path: PathBuf, owner: Option<LockOwner> },
+    // the lock was left behind by a process which is gone, and has to be broken with `break_lock`
+    Stale { path: PathBuf, owner: Option<LockOwner> }
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Io(e) => write!(f, "{}", e),
+            LockError::Held { path, owner: Some(LockOwner { pid, namespace: Some(namespace), .. }) } =>
+                write!(f, "locked by process {} in {}: {}", pid, namespace, path.display()),
+            LockError::Held { path, owner: Some(owner) } => write!(f, "locked by process {}: {}", owner.pid, path.display()),
+            LockError::Held { path, owner: None } => write!(f, "locked: {}", path.display()),
+            LockError::Stale { path, owner: Some(owner) } => write!(f,
+                "stale lock left by process {} {} seconds ago, remove it if no other process is running: {}",
+                owner.pid, owner.age().as_secs(), path.display()),
+            LockError::Stale { path, owner: None } =>
+                write!(f, "stale lock, remove it if no other process is running: {}", path.display())
+        }
+    }
+}
+
+impl Error for LockError {}
+
+impl From<std::io::Error> for LockError {
+    fn from(e: std::io::Error) -> Self {
+        LockError::Io(e)
+    }
+}
+
+pub fn lock_path<P>(target: P) -> PathBuf
+where P: AsRef<Path>
+{
+    let mut path = target.as_ref().as_os_str().to_owned();
+    path.push(LOCK_SUFFIX);
+    PathBuf::from(path)
+}
+
+// Reads who holds the lock on `target`, if anyone
+pub fn read_lock_owner<P>(target: P) -> Option<LockOwner>
+where P: AsRef<Path>
+{
+    LockOwner::deserialize_json(&fs::read_to_string(lock_path(target)).ok()?).ok()
+}
+
+// Removes a lock regardless of who holds it, returning whether there was one
+pub fn break_lock<P>(target: P) -> Result<bool, LockError>
+where P: AsRef<Path>
+{
+    match fs::remove_file(lock_path(target)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into())
+    }
+}
+
+// An advisory lock on a file, released when dropped
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf
+}
+
+impl LockFile {
+    // Takes the lock, failing at once if it is held
+    pub fn try_acquire<P>(target: P) -> Result<Self, LockError>
+    where P: AsRef<Path>
+    {
+        let path = lock_path(target.as_ref());
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut f) => {
+                let lock = Self { path };
+                let owner = LockOwner::current().serialize_json().map_err(std::io::Error::other)?;
+                f.write_all(owner.as_bytes())?;
+                Ok(lock)
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                let owner = read_lock_owner(target.as_ref());
+                let stale = match &owner {
+                    Some(owner) => owner.is_stale(),
+                    // a lock file which never got its owner written is only stale once it is old
+                    None => fs::metadata(&path)?.modified()?.elapsed().unwrap_or_default() > STALE_LOCK_AGE
+                };
+                if stale {
+                    Err(LockError::Stale { path, owner })
+                } else {
+                    Err(LockError::Held { path, owner })
+                }
+            },
+            Err(e) => Err(e.into())
+        }
+    }
+
+    // Takes the lock, waiting up to `timeout` for another process to release it. Stale locks fail at once.
+    pub fn acquire<P>(target: P, timeout: Duration) -> Result<Self, LockError>
+    where P: AsRef<Path>
+    {
+        let start = SystemTime::now();
+        loop {
+            match Self::try_acquire(target.as_ref()) {
+                Err(LockError::Held { .. }) if start.elapsed().unwrap_or_default() < timeout => std::thread::sleep(LOCK_RETRY_INTERVAL),
+                result => return result
+            }
+        }
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn test_lock_is_exclusive() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let target = tempdir.path().join("refs/heads/main");
+    let lock = LockFile::try_acquire(&target).unwrap();
+    assert_eq!(lock.get_path(), tempdir.path().join("refs/heads/main.lock"));
+    assert_eq!(read_lock_owner(&target).unwrap().pid, std::process::id());
+    assert!(matches!(LockFile::try_acquire(&target), Err(LockError::Held { owner: Some(_), .. })));
+    assert!(matches!(LockFile::acquire(&target, Duration::from_millis(30)), Err(LockError::Held { .. })));
+    drop(lock);
+    assert!(!lock_path(&target).exists());
+    assert!(LockFile::try_acquire(&target).is_ok());
+}
+
+#[test]
+fn test_stale_lock_is_reported() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let target = tempdir.path().join("HEAD");
+    let write_owner = |owner: &LockOwner| fs::write(lock_path(&target), owner.serialize_json().unwrap()).unwrap();
+    let old = SystemTime::now() - STALE_LOCK_AGE * 2;
+
+    // an owner elsewhere, e.g. a job on another host, can only be judged by its age
+    let elsewhere = LockOwner { pid: std::process::id(), created: SystemTime::now(), namespace: Some("other-host".to_string()) };
+    write_owner(&elsewhere);
+    assert!(matches!(LockFile::try_acquire(&target), Err(LockError::Held { .. })));
+    let owner = LockOwner { created: old, ..elsewhere };
+    write_owner(&owner);
+    let err = LockFile::acquire(&target, Duration::from_secs(5)).unwrap_err();
+    assert!(matches!(&err, LockError::Stale { owner: Some(o), .. } if *o == owner));
+    assert!(err.to_string().contains("stale lock"));
+    assert!(break_lock(&target).unwrap());
+    assert!(LockFile::try_acquire(&target).is_ok());
+
+    // one which can be looked for is held for as long as it runs, and stale once it is gone
+    if process_namespace().is_some() {
+        write_owner(&LockOwner { created: old, ..LockOwner::current() });
+        assert!(matches!(LockFile::try_acquire(&target), Err(LockError::Held { .. })));
+        write_owner(&LockOwner { pid: u32::MAX, ..LockOwner::current() });
+        assert!(matches!(LockFile::try_acquire(&target), Err(LockError::Stale { .. })));
+    }
+}
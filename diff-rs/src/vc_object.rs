@@ -0,0 +1,511 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::error::Error;
+use serde::{Deserialize, Serialize};
+use digest::Digest;
+
+use crate::hashing::*;
+use crate::util::*;
+use crate::vc::*;
+use crate::vc_config::*;
+use crate::vc_lock::*;
+
+// Every stored object starts with a `<type> <body length>\0` header, which is part of the object's hash,
+// so that objects of different types never share a hash and a reader knows what it is looking at
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ObjectType {
+    Blob,
+    Tree,
+    Commit,
+    Tag
+}
+
+impl ObjectType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ObjectType::Blob => "blob",
+            ObjectType::Tree => "tree",
+            ObjectType::Commit => "commit",
+            ObjectType::Tag => "tag"
+        }
+    }
+}
+
+impl Display for ObjectType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ObjectType {
+    type Err = ObjectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blob" => Ok(ObjectType::Blob),
+            "tree" => Ok(ObjectType::Tree),
+            "commit" => Ok(ObjectType::Commit),
+            "tag" => Ok(ObjectType::Tag),
+            _ => Err(ObjectError::UnknownType(s.to_string()))
+        }
+    }
+}
+
+// How loose objects are compressed on disk. Hashes are always computed over the uncompressed object.
+// Objects are told apart by their leading bytes when read, so a store can hold a mix of them.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    #[default]
+    Zlib,
+    Zstd
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+impl Compression {
+    // Guesses the compression from the first bytes of a stored object.
+    // Uncompressed objects start with their header's type name, which can't be mistaken for either magic.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if data.len() >= 2 && data[0] & 0x0f == 8 && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0 {
+            Compression::Zlib
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ObjectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zlib" => Ok(Compression::Zlib),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(ObjectError::UnknownCompression(s.to_string()))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ObjectError {
+    Io(std::io::Error),
+    // the object doesn't start with a well-formed header
+    InvalidHeader(PathBuf),
+    UnknownType(String),
+    // the body is shorter or longer than its header says
+    LengthMismatch { path: PathBuf, expected: usize, actual: usize },
+    // the object is of another type than the one asked for
+    UnexpectedType { path: PathBuf, actual: ObjectType },
+    // the object's contents don't hash to the hash it is stored or listed under
+    HashMismatch { path: PathBuf, expected: VcHashString, actual: VcHashString },
+    // the body of a tree or commit can't be parsed
+    InvalidBody { path: PathBuf, message: String },
+    UnsupportedFormatVersion { path: PathBuf, version: u32 },
+    UnsupportedHashAlgorithm(String),
+    // the repository names its objects with another digest than the one it was opened with
+    HashAlgorithmMismatch { path: PathBuf, expected: String, actual: String },
+    // the repository is of an older format, and has to be migrated before it can be opened
+    MigrationRequired { path: PathBuf, migrations: Vec<Migration> },
+    UnsupportedType(ObjectType),
+    UnknownCompression(String),
+    // the store doesn't accept writes
+    ReadOnly,
+    // the database backing the store failed
+    Database(Box<redb::Error>),
+    Lock(LockError),
+    // a ref didn't point where an update expected it to
+    RefConflict { name: String, expected: Option<VcHashString>, actual: Option<VcHashString> },
+    // the ref name would lead outside the refs directory, or is taken by lock or temporary files
+    InvalidRefName(String),
+    // no object hash starts with the given prefix
+    NotFound(String),
+    // several object hashes start with the given prefix
+    AmbiguousHash { prefix: String, matches: Vec<VcHashString> }
+}
+
+impl Display for ObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectError::Io(e) => write!(f, "{}", e),
+            ObjectError::InvalidHeader(p) => write!(f, "invalid object header: {}", p.display()),
+            ObjectError::UnknownType(t) => write!(f, "unknown object type: {}", t),
+            ObjectError::LengthMismatch { path, expected, actual } =>
+                write!(f, "object length mismatch, expected {} bytes but found {}: {}", expected, actual, path.display()),
+            ObjectError::UnexpectedType { path, actual } => write!(f, "unexpected {} object: {}", actual, path.display()),
+            ObjectError::HashMismatch { path, expected, actual } =>
+                write!(f, "object hash mismatch, expected {} but found {}: {}", expected, actual, path.display()),
+            ObjectError::InvalidBody { path, message } => write!(f, "invalid object body: {}: {}", message, path.display()),
+            ObjectError::UnsupportedFormatVersion { path, version } =>
+                write!(f, "unsupported repository format version {}: {}", version, path.display()),
+            ObjectError::UnsupportedHashAlgorithm(a) => write!(f, "unsupported hash algorithm: {}", a),
+            ObjectError::HashAlgorithmMismatch { path, expected, actual } =>
+                write!(f, "expected hash algorithm {}, found {}: {}", expected, actual, path.display()),
+            ObjectError::MigrationRequired { path, migrations } => write!(f, "repository needs migrating ({}): {}",
+                migrations.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(", "), path.display()),
+            ObjectError::UnsupportedType(t) => write!(f, "unsupported object type: {}", t),
+            ObjectError::UnknownCompression(c) => write!(f, "unknown compression: {}", c),
+            ObjectError::ReadOnly => write!(f, "object store is read-only"),
+            ObjectError::Database(e) => write!(f, "object database error: {}", e),
+            ObjectError::Lock(e) => write!(f, "{}", e),
+            ObjectError::RefConflict { name, expected, actual } => write!(f, "ref {} is at {} but was expected at {}",
+                name, actual.as_deref().unwrap_or("nothing"), expected.as_deref().unwrap_or("nothing")),
+            ObjectError::InvalidRefName(name) => write!(f, "invalid ref name: {}", name),
+            ObjectError::NotFound(prefix) => write!(f, "object not found: {}", prefix),
+            ObjectError::AmbiguousHash { prefix, matches } =>
+                write!(f, "ambiguous hash prefix {} matches {} objects", prefix, matches.len())
+        }
+    }
+}
+
+impl Error for ObjectError {}
+
+impl From<std::io::Error> for ObjectError {
+    fn from(e: std::io::Error) -> Self {
+        ObjectError::Io(e)
+    }
+}
+
+impl From<LockError> for ObjectError {
+    fn from(e: LockError) -> Self {
+        ObjectError::Lock(e)
+    }
+}
+
+// Directory of the repository holding its objects
+pub const OBJECTS_DIR_NAME: &str = "objects";
+
+// Objects are fanned out into subdirectories named by the first characters of their hash,
+// i.e. `<objects>/ab/cdef...`, to keep directories small
+pub const FANOUT_PREFIX_LEN: usize = 2;
+
+pub fn object_path<P>(objects_dir: P, hashstr: &str) -> PathBuf
+where P: AsRef<Path>
+{
+    let split = FANOUT_PREFIX_LEN.min(hashstr.len());
+    objects_dir.as_ref().join(&hashstr[..split]).join(&hashstr[split..])
+}
+
+// The objects directory a stored object lies in, for resolving the objects it refers to
+pub fn objects_dir_of(path: &Path) -> &Path {
+    path.parent().and_then(|shard_dir| shard_dir.parent()).unwrap_or(Path::new(""))
+}
+
+fn is_shard_name(name: &str) -> bool {
+    name.len() == FANOUT_PREFIX_LEN && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn list_shard(objects_dir: &Path, shard: &str) -> Result<Vec<VcHashString>, std::io::Error> {
+    let shard_dir = objects_dir.join(shard);
+    if !shard_dir.is_dir() {
+        return Ok(Vec::new())
+    }
+    let mut hashes = Vec::new();
+    for entry in std::fs::read_dir(shard_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && !is_temp_file_name(&entry.file_name().to_string_lossy()) {
+            hashes.push(format!("{}{}", shard, entry.file_name().to_string_lossy()));
+        }
+    }
+    Ok(hashes)
+}
+
+// Hashes of all objects in the store, in no particular order
+pub fn list_objects<P>(objects_dir: P) -> Result<Vec<VcHashString>, std::io::Error>
+where P: AsRef<Path>
+{
+    let mut hashes = Vec::new();
+    for entry in std::fs::read_dir(objects_dir.as_ref())? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_dir() && is_shard_name(&name) {
+            hashes.extend(list_shard(objects_dir.as_ref(), &name)?);
+        }
+    }
+    Ok(hashes)
+}
+
+// Hashes of all objects starting with `prefix`. Only the shard the prefix points into is read,
+// unless the prefix is shorter than a shard name. Fails if the prefix is not hex.
+pub fn find_objects<P>(objects_dir: P, prefix: &str) -> Result<Vec<VcHashString>, std::io::Error>
+where P: AsRef<Path>
+{
+    if !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid hash prefix: {}", prefix)))
+    }
+    let prefix = prefix.to_ascii_lowercase();
+    let hashes = if prefix.len() >= FANOUT_PREFIX_LEN {
+        list_shard(objects_dir.as_ref(), &prefix[..FANOUT_PREFIX_LEN])?
+    } else {
+        list_objects(objects_dir)?
+    };
+    Ok(hashes.into_iter().filter(|h| h.starts_with(&prefix)).collect())
+}
+
+// Expands an abbreviated hash to the full hash of the single object it matches
+pub fn resolve_hash<P>(objects_dir: P, prefix: &str) -> Result<VcHashString, ObjectError>
+where P: AsRef<Path>
+{
+    let mut matches = find_objects(objects_dir, prefix)?;
+    match matches.len() {
+        0 => Err(ObjectError::NotFound(prefix.to_string())),
+        1 => Ok(matches.remove(0)),
+        _ => {
+            matches.sort();
+            Err(ObjectError::AmbiguousHash { prefix: prefix.to_string(), matches })
+        }
+    }
+}
+
+// Moves the objects of a store written before fan-out from `<objects>/<hash>` to `<objects>/ab/cdef...`.
+// Files which aren't named by a full hash of `D` are left alone. Returns the number of moved objects.
+pub fn migrate_to_fanout<D, P>(objects_dir: P) -> Result<usize, std::io::Error>
+where D: VcDigest, P: AsRef<Path>
+{
+    let hex_len = <D as Digest>::output_size() * 2;
+    let mut moved = 0;
+    for entry in std::fs::read_dir(objects_dir.as_ref())? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !entry.file_type()?.is_file() || name.len() != hex_len || !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+            continue
+        }
+        let path = object_path(objects_dir.as_ref(), &name);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        if Path::exists(&path) {
+            std::fs::remove_file(entry.path())?;
+        } else {
+            std::fs::rename(entry.path(), path)?;
+        }
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+pub fn object_header(object_type: ObjectType, len: usize) -> Vec<u8> {
+    format!("{} {}\0", object_type, len).into_bytes()
+}
+
+pub fn encode_object(object_type: ObjectType, body: &[u8]) -> Vec<u8> {
+    let mut data = object_header(object_type, body.len());
+    data.extend_from_slice(body);
+    data
+}
+
+pub fn object_hash<D: VcDigest>(object_type: ObjectType, body: &[u8]) -> VcHash<D> {
+    hash::<D>(&encode_object(object_type, body))
+}
+
+// Hashes an object whose body of `len` bytes is read from `reader`, without holding the body in memory.
+// Returns None if the reader ends before `len` bytes.
+pub fn object_hash_reader<D, R>(object_type: ObjectType, len: u64, reader: R) -> Result<Option<VcHash<D>>, std::io::Error>
+where D: VcDigest, R: Read
+{
+    let mut writer = HashWriter::<D>::new();
+    writer.write_all(&object_header(object_type, len as usize))?;
+    let copied = std::io::copy(&mut BufReader::with_capacity(HASH_BUFFER_LEN, reader.take(len)), &mut writer)?;
+    Ok((copied == len).then(|| writer.finalize()))
+}
+
+// Splits a stored object into its type and body, checking the header against the body
+pub fn decode_object<P>(data: &[u8], path: P) -> Result<(ObjectType, &[u8]), ObjectError>
+where P: AsRef<Path>
+{
+    let invalid = || ObjectError::InvalidHeader(path.as_ref().to_path_buf());
+    let nul = data.iter().position(|b| *b == 0).ok_or_else(invalid)?;
+    let header = std::str::from_utf8(&data[..nul]).map_err(|_| invalid())?;
+    let (type_name, len) = header.split_once(' ').ok_or_else(invalid)?;
+    let object_type = type_name.parse::<ObjectType>()?;
+    let expected = len.parse::<usize>().map_err(|_| invalid())?;
+    let body = &data[nul + 1..];
+    if body.len() != expected {
+        return Err(ObjectError::LengthMismatch { path: path.as_ref().to_path_buf(), expected, actual: body.len() });
+    }
+    Ok((object_type, body))
+}
+
+// The type and body of a stored object
+pub type StoredObject = (ObjectType, Box<[u8]>);
+
+// Reads a stored object, decompressing it while reading if it was stored compressed
+pub fn read_object<P>(path: P) -> Result<StoredObject, ObjectError>
+where P: AsRef<Path>
+{
+    let mut reader = BufReader::new(File::open(path.as_ref())?);
+    let compression = Compression::detect(reader.fill_buf()?);
+    let mut buf: Vec<u8> = Vec::new();
+    match compression {
+        Compression::None => reader.read_to_end(&mut buf)?,
+        Compression::Zlib => flate2::bufread::ZlibDecoder::new(reader).read_to_end(&mut buf)?,
+        Compression::Zstd => zstd::stream::read::Decoder::with_buffer(reader)?.read_to_end(&mut buf)?
+    };
+    let (object_type, body) = decode_object(&buf, path)?;
+    Ok((object_type, body.into()))
+}
+
+// Reads an object which has to be of type `expected`
+pub fn read_object_of_type<P>(path: P, expected: ObjectType) -> Result<Box<[u8]>, ObjectError>
+where P: AsRef<Path>
+{
+    let (actual, body) = read_object(path.as_ref())?;
+    if actual != expected {
+        return Err(ObjectError::UnexpectedType { path: path.as_ref().to_path_buf(), actual });
+    }
+    Ok(body)
+}
+
+pub fn write_object<P>(parent_path: P, hashstr: &str, object_type: ObjectType, body: &[u8]) -> Result<(), std::io::Error>
+where P: AsRef<Path>
+{
+    write_object_with(parent_path, hashstr, object_type, body, Compression::default())
+}
+
+// Writes the object to `<parent_path>/<hashstr>`, unless an object is already stored under that hash.
+// The header and body are compressed as they are written. The object only appears once it is complete.
+pub fn write_object_with<P>(parent_path: P, hashstr: &str, object_type: ObjectType, body: &[u8], compression: Compression) -> Result<(), std::io::Error>
+where P: AsRef<Path>
+{
+    if Path::exists(&object_path(parent_path.as_ref(), hashstr)) { // assume if file exists with same hash name, it contains the same data
+        return Ok(())
+    }
+    replace_object_with(parent_path, hashstr, object_type, body, compression)
+}
+
+// Writes the object like `write_object_with`, atomically replacing any object already stored under the hash
+pub fn replace_object_with<P>(parent_path: P, hashstr: &str, object_type: ObjectType, body: &[u8], compression: Compression) -> Result<(), std::io::Error>
+where P: AsRef<Path>
+{
+    let path = object_path(parent_path, hashstr);
+    if let Some(shard_dir) = path.parent() {
+        std::fs::create_dir_all(shard_dir)?;
+    }
+    let header = object_header(object_type, body.len());
+    write_atomic_with(path, |f| {
+        match compression {
+            Compression::None => write_parts(f, &header, body)?,
+            Compression::Zlib => write_parts(flate2::write::ZlibEncoder::new(f, flate2::Compression::default()), &header, body)?.finish()?,
+            Compression::Zstd => write_parts(zstd::stream::write::Encoder::new(f, 0)?, &header, body)?.finish()?
+        };
+        Ok(())
+    })
+}
+
+fn write_parts<W: Write>(mut writer: W, header: &[u8], body: &[u8]) -> Result<W, std::io::Error> {
+    writer.write_all(header)?;
+    writer.write_all(body)?;
+    Ok(writer)
+}
+
+pub fn check_hash(path: &Path, expected: &str, actual: &[u8]) -> Result<(), ObjectError> {
+    let actual = hash_to_hex_string(actual);
+    if actual != expected {
+        return Err(ObjectError::HashMismatch { path: path.to_path_buf(), expected: expected.to_string(), actual });
+    }
+    Ok(())
+}
+
+#[test]
+fn test_encode_decode_object() {
+    let data = encode_object(ObjectType::Tree, b"body");
+    assert_eq!(data, b"tree 4\0body");
+    let (object_type, body) = decode_object(&data, "x").unwrap();
+    assert_eq!(object_type, ObjectType::Tree);
+    assert_eq!(body, b"body");
+    assert_ne!(object_hash::<VcHasher>(ObjectType::Blob, b"body"), object_hash::<VcHasher>(ObjectType::Tree, b"body"));
+}
+
+#[test]
+fn test_write_read_object_compressed() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let body = "fn main() {}\n".repeat(100);
+    for (name, compression) in [("none", Compression::None), ("zlib", Compression::Zlib), ("zstd", Compression::Zstd)] {
+        write_object_with(tempdir.path(), name, ObjectType::Blob, body.as_bytes(), compression).unwrap();
+        let stored = std::fs::read(object_path(tempdir.path(), name)).unwrap();
+        assert_eq!(Compression::detect(&stored), compression);
+        assert_eq!(compression == Compression::None, stored.len() > body.len());
+
+        let (object_type, read) = read_object(object_path(tempdir.path(), name)).unwrap();
+        assert_eq!(object_type, ObjectType::Blob);
+        assert_eq!(&*read, body.as_bytes());
+    }
+}
+
+#[test]
+fn test_fanout_layout_and_lookup() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let hashes = ["ab01", "ab02", "cd01"];
+    for hashstr in hashes {
+        write_object(tempdir.path(), hashstr, ObjectType::Blob, hashstr.as_bytes()).unwrap();
+    }
+    assert!(tempdir.path().join("ab").join("01").is_file());
+
+    let mut listed = list_objects(tempdir.path()).unwrap();
+    listed.sort();
+    assert_eq!(listed, hashes);
+    assert_eq!(find_objects(tempdir.path(), "a").unwrap().len(), 2);
+    assert_eq!(resolve_hash(tempdir.path(), "CD").unwrap(), "cd01");
+    assert!(matches!(resolve_hash(tempdir.path(), "ab0"), Err(ObjectError::AmbiguousHash { .. })));
+    assert!(matches!(resolve_hash(tempdir.path(), "ef"), Err(ObjectError::NotFound(_))));
+
+    // sliced into a shard name only once known to be hex
+    for prefix in ["aé", "é", "..", "a/"] {
+        assert_eq!(find_objects(tempdir.path(), prefix).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+    assert!(matches!(resolve_hash(tempdir.path(), "aé"), Err(ObjectError::Io(_))));
+}
+
+#[test]
+fn test_migrate_to_fanout() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let blob: Blob = Blob::new(b"hello");
+    std::fs::write(tempdir.path().join(blob.get_hash_str()), encode_object(ObjectType::Blob, b"hello")).unwrap();
+    std::fs::write(tempdir.path().join("notes.txt"), b"not an object").unwrap();
+
+    assert_eq!(migrate_to_fanout::<VcHasher, _>(tempdir.path()).unwrap(), 1);
+    assert!(!tempdir.path().join(blob.get_hash_str()).exists());
+    assert!(tempdir.path().join("notes.txt").exists());
+    let migrated: Blob = Blob::from_file(object_path(tempdir.path(), &blob.get_hash_str())).unwrap();
+    assert_eq!(migrated.get_data(), b"hello");
+    assert_eq!(migrate_to_fanout::<VcHasher, _>(tempdir.path()).unwrap(), 0);
+
+    // names are only taken for hashes of the store's digest
+    let sha1_blob: Blob<sha1::Sha1> = Blob::new(b"hello");
+    std::fs::write(tempdir.path().join(sha1_blob.get_hash_str()), encode_object(ObjectType::Blob, b"hello")).unwrap();
+    assert_eq!(migrate_to_fanout::<VcHasher, _>(tempdir.path()).unwrap(), 0);
+    assert_eq!(migrate_to_fanout::<sha1::Sha1, _>(tempdir.path()).unwrap(), 1);
+    assert!(object_path(tempdir.path(), &sha1_blob.get_hash_str()).is_file());
+}
+
+#[test]
+fn test_decode_object_errors() {
+    assert!(matches!(decode_object(b"no header", "x"), Err(ObjectError::InvalidHeader(_))));
+    assert!(matches!(decode_object(b"blob x\0", "x"), Err(ObjectError::InvalidHeader(_))));
+    assert!(matches!(decode_object(b"blub 0\0", "x"), Err(ObjectError::UnknownType(_))));
+    assert!(matches!(decode_object(b"blob 5\0abc", "x"), Err(ObjectError::LengthMismatch { expected: 5, actual: 3, .. })));
+}
+
+#[test]
+fn test_interrupted_object_write_leaves_no_object() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let blob: Blob = Blob::new(b"hello");
+    let path = object_path(tempdir.path(), &blob.get_hash_str());
+
+    // a process killed mid-write leaves at most a temporary file, which isn't taken for an object
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path.with_file_name(format!("{}crashed", TEMP_FILE_PREFIX)), b"blob 5\0he").unwrap();
+    assert!(list_objects(tempdir.path()).unwrap().is_empty());
+    assert!(!path.exists());
+
+    // so writing the object again isn't skipped
+    write_object(tempdir.path(), &blob.get_hash_str(), ObjectType::Blob, blob.get_data()).unwrap();
+    assert_eq!(read_object_of_type(&path, ObjectType::Blob).unwrap(), blob.data);
+    assert_eq!(list_objects(tempdir.path()).unwrap(), vec![blob.get_hash_str()]);
+}
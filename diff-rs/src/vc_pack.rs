@@ -0,0 +1,596 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use digest::Digest;
+
+use crate::hashing::*;
+use crate::util::*;
+use crate::vc::*;
+use crate::vc_binary::decode_stub;
+use crate::vc_lock::*;
+use crate::vc_object::*;
+use crate::vc_serialize::*;
+use crate::vc_store::*;
+
+// Packs live in `<objects>/pack` as `pack-<hash>.pack` files, each with a `pack-<hash>.idx` index.
+//
+// A pack starts with `PACK_MAGIC`, a version and the object count, followed by one entry per object:
+//   kind (u8, full or delta), object type (u8), body length (u64), payload length (u64),
+//   the hash of the delta base (delta entries only), and the zlib-compressed payload,
+//   which is either the object body or the delta turning the base's body into it.
+//
+// An index starts with `INDEX_MAGIC`, a version and a fan-out table of 256 cumulative counts
+// of the hashes by their first byte, followed by (hash, pack offset) records sorted by hash.
+// All integers are big-endian. Hashes are as long as the repository's digest makes them, which
+// the reader has to know, so the pack methods are generic over it.
+pub const PACK_DIR_NAME: &str = "pack";
+const PACK_MAGIC: &[u8; 4] = b"VPCK";
+const INDEX_MAGIC: &[u8; 4] = b"VIDX";
+const PACK_VERSION: u32 = 1;
+const INDEX_HEADER_LEN: u64 = 4 + 4 + 256 * 4;
+
+const ENTRY_FULL: u8 = 0;
+const ENTRY_DELTA: u8 = 1;
+
+// Deltas are built from matches of blocks of this many bytes
+const DELTA_BLOCK_LEN: usize = 16;
+const DELTA_COPY: u8 = 0;
+const DELTA_INSERT: u8 = 1;
+
+#[derive(Debug, Clone)]
+pub struct RepackOptions {
+    // how many of the preceding candidates each blob is tried as a delta against
+    pub window: usize,
+    // longest chain of deltas which have to be applied to read an object
+    pub max_depth: usize,
+    // whether loose objects and old packs are deleted once packed
+    pub prune: bool
+}
+
+impl Default for RepackOptions {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            max_depth: 50,
+            prune: true
+        }
+    }
+}
+
+// An object to be packed. The path hint, e.g. a file name, groups blobs which are likely similar.
+#[derive(Debug, Clone)]
+pub struct PackEntry<D: VcDigest = VcHasher> {
+    pub hash: VcHash<D>,
+    pub object_type: ObjectType,
+    pub body: Box<[u8]>,
+    pub path_hint: Option<String>
+}
+
+#[derive(Debug)]
+pub struct Pack {
+    pack_path: PathBuf,
+    index_path: PathBuf,
+    fanout: Vec<u32>
+}
+
+impl Pack {
+    pub fn open<P>(index_path: P) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        let index_path = index_path.as_ref().to_path_buf();
+        let mut f = File::open(&index_path)?;
+        let mut header = vec![0u8; INDEX_HEADER_LEN as usize];
+        f.read_exact(&mut header).map_err(|_| corrupt(&index_path, "truncated index header"))?;
+        if &header[..4] != INDEX_MAGIC || read_u32(&header[4..8]) != PACK_VERSION {
+            return Err(corrupt(&index_path, "not a pack index"));
+        }
+        let fanout = header[8..].chunks(4).map(read_u32).collect();
+        Ok(Self {
+            pack_path: index_path.with_extension("pack"),
+            index_path,
+            fanout
+        })
+    }
+
+    pub fn get_pack_path(&self) -> &Path {
+        &self.pack_path
+    }
+
+    pub fn get_index_path(&self) -> &Path {
+        &self.index_path
+    }
+
+    pub fn len(&self) -> usize {
+        self.fanout[255] as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Binary search of the index records sharing the hash's first byte, reading only the records probed
+    fn find_offset<D: VcDigest>(&self, hash: &VcHash<D>) -> Result<Option<u64>, ObjectError> {
+        let first = hash[0] as usize;
+        let mut lo = if first == 0 { 0 } else { self.fanout[first - 1] as u64 };
+        let mut hi = self.fanout[first] as u64;
+        let mut f = File::open(&self.index_path)?;
+        let mut record = vec![0u8; index_record_len::<D>()];
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            f.seek(SeekFrom::Start(INDEX_HEADER_LEN + mid * record.len() as u64))?;
+            f.read_exact(&mut record).map_err(|_| corrupt(&self.index_path, "truncated index"))?;
+            match record[..hash.len()].cmp(hash.as_slice()) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(read_u64(&record[hash.len()..])))
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn contains<D: VcDigest>(&self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        Ok(self.find_offset::<D>(hash)?.is_some())
+    }
+
+    // Hashes of all objects in the pack, in sorted order
+    pub fn hashes<D: VcDigest>(&self) -> Result<Vec<VcHash<D>>, ObjectError> {
+        let mut f = File::open(&self.index_path)?;
+        f.seek(SeekFrom::Start(INDEX_HEADER_LEN))?;
+        let record_len = index_record_len::<D>();
+        let mut records = vec![0u8; self.len() * record_len];
+        f.read_exact(&mut records).map_err(|_| corrupt(&self.index_path, "truncated index"))?;
+        Ok(records.chunks(record_len).map(|r| VcHash::<D>::clone_from_slice(&r[..record_len - 8])).collect())
+    }
+
+    // Reads the type and body of an object, or None if it isn't in this pack
+    pub fn read<D: VcDigest>(&self, hash: &VcHash<D>) -> Result<Option<StoredObject>, ObjectError> {
+        let Some(offset) = self.find_offset::<D>(hash)? else {
+            return Ok(None)
+        };
+        let mut f = File::open(&self.pack_path)?;
+        self.read_entry::<D>(&mut f, offset, 0).map(Some)
+    }
+
+    // Bytes the object's entry takes up in the pack, without resolving deltas
+    pub fn entry_size<D: VcDigest>(&self, hash: &VcHash<D>) -> Result<Option<u64>, ObjectError> {
+        let Some(offset) = self.find_offset::<D>(hash)? else {
+            return Ok(None)
+        };
+        let mut f = File::open(&self.pack_path)?;
+        f.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 18];
+        f.read_exact(&mut header).map_err(|_| corrupt(&self.pack_path, "truncated entry"))?;
+        let base_len = if header[0] == ENTRY_DELTA { hash.len() as u64 } else { 0 };
+        Ok(Some(header.len() as u64 + base_len + read_u64(&header[10..18])))
+    }
+
+    fn read_entry<D: VcDigest>(&self, f: &mut File, offset: u64, depth: usize) -> Result<StoredObject, ObjectError> {
+        // a chain longer than the pack can hold means the deltas refer to each other in a cycle
+        if depth > self.len() {
+            return Err(corrupt(&self.pack_path, "delta chain loops"));
+        }
+        f.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 18];
+        f.read_exact(&mut header).map_err(|_| corrupt(&self.pack_path, "truncated entry"))?;
+        let object_type = type_from_byte(header[1]).ok_or_else(|| corrupt(&self.pack_path, "unknown object type"))?;
+        let len = read_u64(&header[2..10]) as usize;
+        let payload_len = read_u64(&header[10..18]);
+        let mut base_hash = VcHash::<D>::default();
+        if header[0] == ENTRY_DELTA {
+            f.read_exact(&mut base_hash).map_err(|_| corrupt(&self.pack_path, "truncated entry"))?;
+        }
+        let mut payload = Vec::new();
+        ZlibDecoder::new(Read::by_ref(f).take(payload_len)).read_to_end(&mut payload)?;
+
+        let body = match header[0] {
+            ENTRY_FULL => payload,
+            ENTRY_DELTA => {
+                let base_offset = self.find_offset::<D>(&base_hash)?
+                    .ok_or_else(|| corrupt(&self.pack_path, "missing delta base"))?;
+                let (_, base) = self.read_entry::<D>(f, base_offset, depth + 1)?;
+                apply_delta(&base, &payload).ok_or_else(|| corrupt(&self.pack_path, "invalid delta"))?
+            },
+            _ => return Err(corrupt(&self.pack_path, "unknown entry kind"))
+        };
+        if body.len() != len {
+            return Err(ObjectError::LengthMismatch { path: self.pack_path.clone(), expected: len, actual: body.len() });
+        }
+        Ok((object_type, body.into_boxed_slice()))
+    }
+}
+
+fn index_record_len<D: VcDigest>() -> usize {
+    <D as Digest>::output_size() + 8
+}
+
+fn corrupt(path: &Path, message: &str) -> ObjectError {
+    ObjectError::InvalidBody { path: path.to_path_buf(), message: message.to_string() }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().unwrap())
+}
+
+fn type_to_byte(object_type: ObjectType) -> u8 {
+    match object_type {
+        ObjectType::Blob => 1,
+        ObjectType::Tree => 2,
+        ObjectType::Commit => 3,
+        ObjectType::Tag => 4
+    }
+}
+
+fn type_from_byte(b: u8) -> Option<ObjectType> {
+    match b {
+        1 => Some(ObjectType::Blob),
+        2 => Some(ObjectType::Tree),
+        3 => Some(ObjectType::Commit),
+        4 => Some(ObjectType::Tag),
+        _ => None
+    }
+}
+
+fn push_varint(out: &mut Vec<u8>, n: usize) {
+    crate::vc_binary::push_varint(out, n as u64)
+}
+
+fn take_varint(data: &[u8], pos: &mut usize) -> Option<usize> {
+    crate::vc_binary::take_varint(data, pos)?.try_into().ok()
+}
+
+// Encodes `target` as copies of ranges of `base` and inserts of new bytes
+pub fn create_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for (i, block) in base.chunks_exact(DELTA_BLOCK_LEN).enumerate() {
+        blocks.entry(block).or_insert(i * DELTA_BLOCK_LEN);
+    }
+
+    let mut delta = Vec::new();
+    push_varint(&mut delta, base.len());
+    push_varint(&mut delta, target.len());
+    let mut insert_start = 0;
+    let mut i = 0;
+    while i + DELTA_BLOCK_LEN <= target.len() {
+        let Some(&start) = blocks.get(&target[i..i + DELTA_BLOCK_LEN]) else {
+            i += 1;
+            continue
+        };
+        let mut len = DELTA_BLOCK_LEN;
+        while start + len < base.len() && i + len < target.len() && base[start + len] == target[i + len] {
+            len += 1;
+        }
+        push_insert(&mut delta, &target[insert_start..i]);
+        delta.push(DELTA_COPY);
+        push_varint(&mut delta, start);
+        push_varint(&mut delta, len);
+        i += len;
+        insert_start = i;
+    }
+    push_insert(&mut delta, &target[insert_start..]);
+    delta
+}
+
+fn push_insert(delta: &mut Vec<u8>, data: &[u8]) {
+    if !data.is_empty() {
+        delta.push(DELTA_INSERT);
+        push_varint(delta, data.len());
+        delta.extend_from_slice(data);
+    }
+}
+
+// Rebuilds the target of a delta, or returns None if the delta doesn't fit the base
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    if take_varint(delta, &mut pos)? != base.len() {
+        return None
+    }
+    let len = take_varint(delta, &mut pos)?;
+    let mut target = Vec::with_capacity(len);
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        match op {
+            DELTA_COPY => {
+                let start = take_varint(delta, &mut pos)?;
+                let copy_len = take_varint(delta, &mut pos)?;
+                target.extend_from_slice(base.get(start..start.checked_add(copy_len)?)?);
+            },
+            DELTA_INSERT => {
+                let insert_len = take_varint(delta, &mut pos)?;
+                target.extend_from_slice(delta.get(pos..pos.checked_add(insert_len)?)?);
+                pos += insert_len;
+            },
+            _ => return None
+        }
+    }
+    (target.len() == len).then_some(target)
+}
+
+// Picks a delta base for each blob: blobs are ordered by path hint and then by decreasing size,
+// so that versions of the same file sit next to each other, and each is tried against the
+// preceding `window` blobs. A delta is only kept if it is less than half the size of the blob.
+fn choose_deltas<D: VcDigest>(entries: &[PackEntry<D>], options: &RepackOptions) -> HashMap<usize, (usize, Vec<u8>)> {
+    let mut order: Vec<usize> = (0..entries.len()).filter(|i| entries[*i].object_type == ObjectType::Blob).collect();
+    order.sort_by(|a, b| {
+        let (a, b) = (&entries[*a], &entries[*b]);
+        a.path_hint.cmp(&b.path_hint).then(b.body.len().cmp(&a.body.len()))
+    });
+
+    let mut depths: HashMap<usize, usize> = HashMap::new();
+    let mut deltas = HashMap::new();
+    for (n, &i) in order.iter().enumerate() {
+        let target = &entries[i].body;
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        for &j in order[n.saturating_sub(options.window)..n].iter().rev() {
+            let base = &entries[j].body;
+            if depths.get(&j).copied().unwrap_or(0) >= options.max_depth || base.len() > target.len() * 2 || target.len() > base.len() * 2 {
+                continue
+            }
+            let delta = create_delta(base, target);
+            let limit = best.as_ref().map_or(target.len() / 2, |(_, d)| d.len());
+            if delta.len() < limit {
+                best = Some((j, delta));
+            }
+        }
+        if let Some((j, delta)) = best {
+            depths.insert(i, depths.get(&j).copied().unwrap_or(0) + 1);
+            deltas.insert(i, (j, delta));
+        }
+    }
+    deltas
+}
+
+// Writes the entries into a new pack with its index, returning the path of the index
+pub fn write_pack<D, P>(objects_dir: P, entries: &[PackEntry<D>], options: &RepackOptions) -> Result<PathBuf, ObjectError>
+where D: VcDigest, P: AsRef<Path>
+{
+    let pack_dir = objects_dir.as_ref().join(PACK_DIR_NAME);
+    std::fs::create_dir_all(&pack_dir)?;
+    let mut sorted_hashes: Vec<&VcHash<D>> = entries.iter().map(|e| &e.hash).collect();
+    sorted_hashes.sort();
+    let name_hash = hash::<D>(&sorted_hashes.iter().flat_map(|h| h.iter().copied()).collect::<Vec<u8>>());
+    let name = format!("pack-{}", hash_to_hex_string(&name_hash));
+    let pack_path = pack_dir.join(format!("{}.pack", name));
+    let index_path = pack_dir.join(format!("{}.idx", name));
+
+    let deltas = choose_deltas(entries, options);
+    let mut records: Vec<(VcHash<D>, u64)> = Vec::with_capacity(entries.len());
+    // the pack is complete before the index pointing into it appears
+    write_atomic_with(&pack_path, |f| {
+        let mut pack = BufWriter::new(f);
+        pack.write_all(PACK_MAGIC)?;
+        pack.write_all(&PACK_VERSION.to_be_bytes())?;
+        pack.write_all(&(entries.len() as u32).to_be_bytes())?;
+        let mut offset = 12u64;
+        for (i, entry) in entries.iter().enumerate() {
+            let (kind, base_hash, payload) = match deltas.get(&i) {
+                Some((j, delta)) => (ENTRY_DELTA, Some(&entries[*j].hash), &delta[..]),
+                None => (ENTRY_FULL, None, &entry.body[..])
+            };
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(payload)?;
+            let compressed = encoder.finish()?;
+
+            records.push((entry.hash.clone(), offset));
+            pack.write_all(&[kind, type_to_byte(entry.object_type)])?;
+            pack.write_all(&(entry.body.len() as u64).to_be_bytes())?;
+            pack.write_all(&(compressed.len() as u64).to_be_bytes())?;
+            if let Some(base_hash) = base_hash {
+                pack.write_all(base_hash)?;
+            }
+            pack.write_all(&compressed)?;
+            offset += 18 + base_hash.map_or(0, |h| h.len() as u64) + compressed.len() as u64;
+        }
+        pack.flush()
+    })?;
+
+    records.sort();
+    records.dedup_by(|a, b| a.0 == b.0);
+    let mut fanout = [0u32; 256];
+    for (hash, _) in &records {
+        fanout[hash[0] as usize] += 1;
+    }
+    for i in 1..256 {
+        fanout[i] += fanout[i - 1];
+    }
+    write_atomic_with(&index_path, |f| {
+        let mut index = BufWriter::new(f);
+        index.write_all(INDEX_MAGIC)?;
+        index.write_all(&PACK_VERSION.to_be_bytes())?;
+        for count in fanout {
+            index.write_all(&count.to_be_bytes())?;
+        }
+        for (hash, offset) in &records {
+            index.write_all(hash)?;
+            index.write_all(&offset.to_be_bytes())?;
+        }
+        index.flush()
+    })?;
+    Ok(index_path)
+}
+
+pub fn list_packs<P>(objects_dir: P) -> Result<Vec<Pack>, ObjectError>
+where P: AsRef<Path>
+{
+    let pack_dir = objects_dir.as_ref().join(PACK_DIR_NAME);
+    if !pack_dir.is_dir() {
+        return Ok(Vec::new())
+    }
+    let mut packs = Vec::new();
+    for entry in std::fs::read_dir(pack_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "idx") {
+            packs.push(Pack::open(path)?);
+        }
+    }
+    Ok(packs)
+}
+
+// Hashes of all packed objects, in no particular order
+pub fn list_packed_objects<D, P>(objects_dir: P) -> Result<Vec<VcHashString>, ObjectError>
+where D: VcDigest, P: AsRef<Path>
+{
+    let mut hashes = Vec::new();
+    for pack in list_packs(objects_dir)? {
+        hashes.extend(pack.hashes::<D>()?.iter().map(|h| hash_to_hex_string(h)));
+    }
+    Ok(hashes)
+}
+
+// Names blobs by the tree entries listing them, as hints for choosing delta bases
+fn path_hints<D: VcDigest>(entries: &[PackEntry<D>]) -> HashMap<VcHashString, String> {
+    let mut hints = HashMap::new();
+    for entry in entries.iter().filter(|e| e.object_type == ObjectType::Tree) {
+        let Ok(stub) = decode_stub::<D, TreeStub>(Path::new(""), &entry.body) else { continue };
+        for (name, (fs_object_type, hashstr)) in stub.listings {
+            if fs_object_type != FsObjectType::Tree {
+                hints.entry(hashstr).or_insert(name);
+            }
+        }
+    }
+    hints
+}
+
+// Packs all loose and packed objects of the store into a single new pack.
+// Returns the path of the new index, or None if the store holds no objects.
+pub fn repack<D, P>(objects_dir: P, options: &RepackOptions) -> Result<Option<PathBuf>, ObjectError>
+where D: VcDigest, P: AsRef<Path>
+{
+    let objects_dir = objects_dir.as_ref();
+    let _lock = LockFile::try_acquire(objects_dir.join(PACK_DIR_NAME))?;
+    let old_packs = list_packs(objects_dir)?;
+    let loose = list_objects(objects_dir)?;
+    let store = FsObjectStore::<D>::new(objects_dir);
+    let mut seen = HashSet::new();
+    let mut entries: Vec<PackEntry<D>> = Vec::new();
+    for hashstr in list_packed_objects::<D, _>(objects_dir)?.into_iter().chain(loose.iter().cloned()) {
+        // loose objects which aren't named by a hash can't be indexed
+        let Ok(hash) = hex_string_to_hash::<D>(&hashstr) else { continue };
+        if !seen.insert(hash.clone()) {
+            continue
+        }
+        let (object_type, body) = store.get(&hash)?.ok_or(ObjectError::NotFound(hashstr))?;
+        entries.push(PackEntry { hash, object_type, body, path_hint: None });
+    }
+    if entries.is_empty() {
+        return Ok(None)
+    }
+
+    let hints = path_hints(&entries);
+    for entry in &mut entries {
+        entry.path_hint = hints.get(&hash_to_hex_string(&entry.hash)).cloned();
+    }
+    let index_path = write_pack(objects_dir, &entries, options)?;
+
+    if options.prune {
+        for pack in old_packs.iter().filter(|p| p.get_index_path() != index_path) {
+            std::fs::remove_file(pack.get_pack_path())?;
+            std::fs::remove_file(pack.get_index_path())?;
+        }
+        for hashstr in loose.iter().filter(|h| hex_string_to_hash::<D>(h).is_ok()) {
+            let path = object_path(objects_dir, hashstr);
+            std::fs::remove_file(&path)?;
+            if let Some(shard_dir) = path.parent() {
+                // only succeeds once the shard is empty
+                let _ = std::fs::remove_dir(shard_dir);
+            }
+        }
+    }
+    Ok(Some(index_path))
+}
+
+#[test]
+fn test_delta_round_trip() {
+    let base = "line of text that repeats\n".repeat(20).into_bytes();
+    let mut target = base.clone();
+    target.splice(100..110, b"something new".iter().copied());
+    target.extend_from_slice(b"appended\n");
+
+    let delta = create_delta(&base, &target);
+    assert!(delta.len() < target.len() / 4);
+    assert_eq!(apply_delta(&base, &delta).unwrap(), target);
+    assert_eq!(apply_delta(&base[1..], &delta), None);
+    assert_eq!(apply_delta(b"", &create_delta(b"", b"abc")).unwrap(), b"abc");
+}
+
+#[cfg(test)]
+fn versions_tree(versions: usize) -> (Vec<Commit<'static>>, Vec<Tree>) {
+    let mut trees = Vec::new();
+    let mut commits = Vec::new();
+    let mut text: String = (0..300).map(|i| format!("line {} of the file, {}\n", i, i * 7919 % 1000)).collect();
+    for v in 0..versions {
+        text.push_str(&format!("version {} adds a line to the file\n", v));
+        let mut tree = Tree::new(HashMap::new());
+        tree.insert_path("src/file.txt", FsObject::Blob(Blob::new(text.as_bytes())));
+        tree.insert_path("other.txt", FsObject::Blob(Blob::new(format!("other {}\n", v).as_bytes())));
+        commits.push(Commit::new(tree.clone(), None, "alice".to_string(), format!("v{}", v)));
+        trees.push(tree);
+    }
+    (commits, trees)
+}
+
+#[test]
+fn test_repack_and_read() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let (commits, trees) = versions_tree(30);
+    for commit in &commits {
+        commit.to_file(tempdir.path()).unwrap();
+    }
+    let loose = list_objects(tempdir.path()).unwrap();
+    let loose_bytes: u64 = loose.iter().map(|h| std::fs::metadata(object_path(tempdir.path(), h)).unwrap().len()).sum();
+
+    let index_path = repack::<VcHasher, _>(tempdir.path(), &RepackOptions::default()).unwrap().unwrap();
+    assert!(list_objects(tempdir.path()).unwrap().is_empty());
+    let pack = Pack::open(&index_path).unwrap();
+    assert_eq!(pack.len(), loose.len());
+
+    // objects read back transparently from the pack, deltas included
+    for (commit, tree) in commits.iter().zip(&trees) {
+        let VcObject::Commit(loaded) = VcObject::<VcHasher>::load(tempdir.path(), &commit.get_hash_str()).unwrap() else {
+            panic!("commit not loaded")
+        };
+        assert_eq!(loaded.tree.hash, tree.hash);
+    }
+    let Some(FsObject::Blob(blob)) = trees[29].get_path("src/file.txt") else { panic!() };
+    assert_eq!(pack.read::<VcHasher>(&blob.hash).unwrap().unwrap().1, blob.data);
+    assert!(pack.read::<VcHasher>(&VcHash::<VcHasher>::default()).unwrap().is_none());
+
+    // similar versions of the file are stored as deltas, so the pack is smaller than the loose objects
+    let pack_bytes = std::fs::metadata(pack.get_pack_path()).unwrap().len();
+    assert!(pack_bytes < loose_bytes / 2);
+
+    // repacking again replaces the pack with one holding the same objects
+    let new_blob: Blob = Blob::new(b"new loose blob");
+    new_blob.to_file(tempdir.path()).unwrap();
+    let index_path = repack::<VcHasher, _>(tempdir.path(), &RepackOptions::default()).unwrap().unwrap();
+    assert_eq!(list_packs(tempdir.path()).unwrap().len(), 1);
+    assert_eq!(Pack::open(index_path).unwrap().len(), loose.len() + 1);
+}
+
+#[test]
+fn test_delta_chains_are_bounded() {
+    let versions: Vec<PackEntry> = (0..20).map(|v| {
+        let body = format!("{}{}", "shared content line\n".repeat(10), "x".repeat(v)).into_bytes();
+        PackEntry { hash: object_hash::<VcHasher>(ObjectType::Blob, &body), object_type: ObjectType::Blob, body: body.into(), path_hint: None }
+    }).collect();
+    let options = RepackOptions { max_depth: 3, ..Default::default() };
+    let deltas = choose_deltas(&versions, &options);
+    assert!(!deltas.is_empty());
+    for &i in deltas.keys() {
+        let mut depth = 0;
+        let mut current = i;
+        while let Some((base, _)) = deltas.get(&current) {
+            depth += 1;
+            current = *base;
+        }
+        assert!(depth <= 3);
+    }
+}
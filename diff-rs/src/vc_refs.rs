@@ -0,0 +1,417 @@
+use std::fs::{self, OpenOptions};
+use std::marker::PhantomData;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::*;
+use crate::util::*;
+use crate::vc::*;
+use crate::vc_lock::*;
+use crate::vc_object::*;
+use crate::vc_serialize::*;
+
+// Layout of the refs of a repository:
+//   `HEAD` holds a `HeadRefStub`,
+//   `refs/heads/<name>` and `refs/tags/<name>` hold the hash of a commit,
+//   `logs/HEAD` and `logs/refs/...` hold one `ReflogEntry` per line for every update of the ref.
+pub const HEAD_FILE_NAME: &str = "HEAD";
+pub const REFS_DIR_NAME: &str = "refs";
+pub const LOGS_DIR_NAME: &str = "logs";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RefKind {
+    Head,
+    Tag
+}
+
+impl RefKind {
+    pub fn dir_name(&self) -> &'static str {
+        match self {
+            RefKind::Head => "heads",
+            RefKind::Tag => "tags"
+        }
+    }
+}
+
+// An update of a ref; `old` is None when the ref was created
+#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct ReflogEntry {
+    pub old: Option<VcHashString>,
+    pub new: VcHashString,
+    pub timestamp: SystemTime,
+    pub message: String
+}
+impl SerializeDeserializeJson for ReflogEntry {}
+
+// Refs hold hashes under the digest `D`, which has to be the one of the repository's objects
+#[derive(Debug, Clone)]
+pub struct RefStore<D: VcDigest = VcHasher> {
+    root: PathBuf,
+    digest: PhantomData<D>
+}
+
+impl<D: VcDigest> RefStore<D> {
+    pub fn new<P>(root: P) -> Self
+    where P: AsRef<Path>
+    {
+        Self { root: root.as_ref().to_path_buf(), digest: PhantomData }
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    fn ref_path(&self, kind: RefKind, name: &str) -> PathBuf {
+        self.root.join(REFS_DIR_NAME).join(kind.dir_name()).join(name)
+    }
+
+    fn log_path(&self, kind: RefKind, name: &str) -> PathBuf {
+        self.root.join(LOGS_DIR_NAME).join(REFS_DIR_NAME).join(kind.dir_name()).join(name)
+    }
+
+    pub fn get_ref(&self, kind: RefKind, name: &str) -> Result<Option<VcHash<D>>, ObjectError> {
+        check_ref_name(name)?;
+        let path = self.ref_path(kind, name);
+        if !path.is_file() {
+            return Ok(None)
+        }
+        let hashstr = fs::read_to_string(&path)?;
+        hex_string_to_hash::<D>(hashstr.trim())
+            .map(Some)
+            .map_err(|e| ObjectError::InvalidBody { path, message: e.to_string() })
+    }
+
+    // Points a ref at a commit whatever it pointed at before, recording the update in the ref's log
+    pub fn set_ref(&self, kind: RefKind, name: &str, hash: &VcHash<D>, message: &str) -> Result<(), ObjectError> {
+        check_ref_name(name)?;
+        let _lock = LockFile::acquire(self.ref_path(kind, name), DEFAULT_LOCK_TIMEOUT)?;
+        let old = self.get_ref(kind, name)?;
+        self.write_ref(kind, name, old.as_ref(), hash, message)
+    }
+
+    // Points a ref at a commit only if it still points at `expected`, with None meaning it doesn't exist yet.
+    // Fails with `RefConflict` if another update got there first.
+    pub fn update_ref(&self, kind: RefKind, name: &str, expected: Option<&VcHash<D>>, hash: &VcHash<D>, message: &str) -> Result<(), ObjectError> {
+        check_ref_name(name)?;
+        let _lock = LockFile::acquire(self.ref_path(kind, name), DEFAULT_LOCK_TIMEOUT)?;
+        let old = self.get_ref(kind, name)?;
+        if old.as_ref() != expected {
+            return Err(ObjectError::RefConflict {
+                name: name.to_string(),
+                expected: expected.map(|h| hash_to_hex_string(h)),
+                actual: old.map(|h| hash_to_hex_string(&h))
+            })
+        }
+        self.write_ref(kind, name, old.as_ref(), hash, message)
+    }
+
+    fn write_ref(&self, kind: RefKind, name: &str, old: Option<&VcHash<D>>, hash: &VcHash<D>, message: &str) -> Result<(), ObjectError> {
+        let path = self.ref_path(kind, name);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomic(&path, format!("{}\n", hash_to_hex_string(hash)).as_bytes())?;
+        self.append_log(&self.log_path(kind, name), old, hash, message)
+    }
+
+    // Removes a ref along with its log, returning whether it existed
+    pub fn delete_ref(&self, kind: RefKind, name: &str) -> Result<bool, ObjectError> {
+        check_ref_name(name)?;
+        let path = self.ref_path(kind, name);
+        let _lock = LockFile::acquire(&path, DEFAULT_LOCK_TIMEOUT)?;
+        if !path.is_file() {
+            return Ok(false)
+        }
+        fs::remove_file(path)?;
+        let log_path = self.log_path(kind, name);
+        if log_path.is_file() {
+            fs::remove_file(log_path)?;
+        }
+        Ok(true)
+    }
+
+    // Names of all refs of a kind, with nested names joined by `/`, in sorted order
+    pub fn list_refs(&self, kind: RefKind) -> Result<Vec<(Name, VcHash<D>)>, ObjectError> {
+        let dir = self.root.join(REFS_DIR_NAME).join(kind.dir_name());
+        let mut refs = Vec::new();
+        if dir.is_dir() {
+            list_files(&dir, &dir, &mut refs)?;
+        }
+        refs.sort();
+        let mut listed = Vec::new();
+        for name in refs {
+            if let Some(hash) = self.get_ref(kind, &name)? {
+                listed.push((name, hash));
+            }
+        }
+        Ok(listed)
+    }
+
+    pub fn get_head(&self) -> Result<Option<HeadRefStub>, ObjectError> {
+        let path = self.root.join(HEAD_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None)
+        }
+        HeadRefStub::deserialize_json(&fs::read_to_string(&path)?)
+            .map(Some)
+            .map_err(|e| ObjectError::InvalidBody { path, message: e.to_string() })
+    }
+
+    // Points HEAD at a ref or a commit. Moves to another commit are recorded in the log of HEAD.
+    pub fn set_head(&self, head: &HeadRefStub, message: &str) -> Result<(), ObjectError> {
+        if let HeadRefStub::Head(name) | HeadRefStub::Tag(name) = head {
+            check_ref_name(name)?;
+        }
+        let path = self.root.join(HEAD_FILE_NAME);
+        let _lock = LockFile::acquire(&path, DEFAULT_LOCK_TIMEOUT)?;
+        let old = self.resolve_head()?;
+        let json = head.serialize_json().map_err(|e| ObjectError::InvalidBody { path: path.clone(), message: e.to_string() })?;
+        write_atomic(&path, json.as_bytes())?;
+        match self.resolve_head()? {
+            Some(new) if old.as_ref() != Some(&new) => self.append_log(&self.root.join(LOGS_DIR_NAME).join(HEAD_FILE_NAME), old.as_ref(), &new, message),
+            _ => Ok(())
+        }
+    }
+
+    // The commit HEAD points at, directly or through a ref
+    pub fn resolve_head(&self) -> Result<Option<VcHash<D>>, ObjectError> {
+        match self.get_head()? {
+            Some(HeadRefStub::Head(name)) => self.get_ref(RefKind::Head, &name),
+            Some(HeadRefStub::Tag(name)) => self.get_ref(RefKind::Tag, &name),
+            Some(HeadRefStub::Commit(hashstr)) => hex_string_to_hash::<D>(&hashstr)
+                .map(Some)
+                .map_err(|e| ObjectError::InvalidBody { path: self.root.join(HEAD_FILE_NAME), message: e.to_string() }),
+            None => Ok(None)
+        }
+    }
+
+    // The log of a ref, oldest update first
+    pub fn reflog(&self, kind: RefKind, name: &str) -> Result<Vec<ReflogEntry>, ObjectError> {
+        check_ref_name(name)?;
+        read_log(&self.log_path(kind, name))
+    }
+
+    pub fn head_reflog(&self) -> Result<Vec<ReflogEntry>, ObjectError> {
+        read_log(&self.root.join(LOGS_DIR_NAME).join(HEAD_FILE_NAME))
+    }
+
+    // Every commit a ref, HEAD or a reflog points at. These are what keeps objects alive.
+    pub fn all_referenced(&self) -> Result<Vec<VcHash<D>>, ObjectError> {
+        let mut hashes: Vec<VcHash<D>> = Vec::new();
+        let mut logs = self.head_reflog()?;
+        for kind in [RefKind::Head, RefKind::Tag] {
+            for (name, hash) in self.list_refs(kind)? {
+                hashes.push(hash);
+                logs.extend(self.reflog(kind, &name)?);
+            }
+        }
+        hashes.extend(self.resolve_head()?);
+        for entry in logs {
+            for hashstr in entry.old.iter().chain(std::iter::once(&entry.new)) {
+                if let Ok(hash) = hex_string_to_hash::<D>(hashstr) {
+                    hashes.push(hash);
+                }
+            }
+        }
+        hashes.sort();
+        hashes.dedup();
+        Ok(hashes)
+    }
+
+    fn append_log(&self, path: &Path, old: Option<&VcHash<D>>, new: &VcHash<D>, message: &str) -> Result<(), ObjectError> {
+        let entry = ReflogEntry {
+            old: old.map(|h| hash_to_hex_string(h)),
+            new: hash_to_hex_string(new),
+            timestamp: SystemTime::now(),
+            message: message.to_string()
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let line = serialize_json_compact(&entry).map_err(|e| ObjectError::InvalidBody { path: path.to_path_buf(), message: e.to_string() })?;
+        // appends can't be made atomic; a torn last line is skipped when the log is read,
+        // and dropped before the next entry is appended so that the two don't run together
+        if path.is_file() {
+            let log = fs::read(path)?;
+            if log.last().is_some_and(|b| *b != b'\n') {
+                let end = log.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+                write_atomic(path, &log[..end])?;
+            }
+        }
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(f, "{}", line)?;
+        f.sync_data()?;
+        Ok(())
+    }
+}
+
+// Ref names are `/`-separated paths below the refs directory of their kind. Empty, `.` and `..`
+// components and absolute names would lead outside it, and lock and temporary file names would
+// be mistaken for the files guarding ref updates.
+pub fn check_ref_name(name: &str) -> Result<(), ObjectError> {
+    let valid = !name.contains('\\') && name.split('/').all(|component| {
+        !matches!(component, "" | "." | "..") && !component.ends_with(LOCK_SUFFIX) && !is_temp_file_name(component)
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(ObjectError::InvalidRefName(name.to_string()))
+    }
+}
+
+fn read_log(path: &Path) -> Result<Vec<ReflogEntry>, ObjectError> {
+    if !path.is_file() {
+        return Ok(Vec::new())
+    }
+    let log = fs::read_to_string(path)?;
+    // a last line without its newline was cut short by a crash while appending
+    let complete = match log.rfind('\n') {
+        Some(end) => &log[..end],
+        None => ""
+    };
+    complete.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| ReflogEntry::deserialize_json(line).map_err(|e| ObjectError::InvalidBody { path: path.to_path_buf(), message: e.to_string() }))
+        .collect()
+}
+
+fn list_files(base: &Path, dir: &Path, names: &mut Vec<Name>) -> Result<(), ObjectError> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(base, &path, names)?;
+        } else if path.file_name().is_some_and(|n| is_temp_file_name(&n.to_string_lossy())) || path.to_string_lossy().ends_with(LOCK_SUFFIX) {
+            continue
+        } else if let Ok(relative) = path.strip_prefix(base) {
+            names.push(relative.to_string_lossy().replace('\\', "/"));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_refs_and_reflogs() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let refs: RefStore = RefStore::new(tempdir.path());
+    let first = Blob::<VcHasher>::new(b"first").hash;
+    let second = Blob::<VcHasher>::new(b"second").hash;
+    assert_eq!(refs.get_ref(RefKind::Head, "main").unwrap(), None);
+
+    refs.set_ref(RefKind::Head, "main", &first, "commit").unwrap();
+    refs.set_ref(RefKind::Head, "main", &second, "commit").unwrap();
+    refs.set_ref(RefKind::Tag, "release/v1", &first, "tag").unwrap();
+    refs.set_head(&HeadRefStub::Head("main".to_string()), "checkout").unwrap();
+
+    assert_eq!(refs.get_ref(RefKind::Head, "main").unwrap(), Some(second));
+    assert_eq!(refs.list_refs(RefKind::Tag).unwrap(), vec![("release/v1".to_string(), first)]);
+    assert_eq!(refs.resolve_head().unwrap(), Some(second));
+
+    let log = refs.reflog(RefKind::Head, "main").unwrap();
+    assert_eq!(log.len(), 2);
+    assert_eq!(log[1].old, Some(hash_to_hex_string(&first)));
+    assert_eq!(log[1].new, hash_to_hex_string(&second));
+    assert_eq!(refs.head_reflog().unwrap().len(), 1);
+    assert_eq!(refs.all_referenced().unwrap().len(), 2);
+
+    assert!(refs.delete_ref(RefKind::Head, "main").unwrap());
+    assert!(refs.reflog(RefKind::Head, "main").unwrap().is_empty());
+    assert_eq!(refs.resolve_head().unwrap(), None);
+}
+
+#[test]
+fn test_invalid_ref_names_are_rejected() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let refs: RefStore = RefStore::new(tempdir.path().join("repo"));
+    let hash = Blob::<VcHasher>::new(b"first").hash;
+    let outside = tempdir.path().join("outside").to_string_lossy().to_string();
+    for name in ["", "..", "../../objects/xx", "a/../../b", "./main", "a//b", "main/", &outside, "main.lock", "a\\..\\b", ".tmp-main"] {
+        assert!(matches!(refs.set_ref(RefKind::Head, name, &hash, "commit"), Err(ObjectError::InvalidRefName(_))), "{}", name);
+        assert!(matches!(refs.update_ref(RefKind::Head, name, None, &hash, "commit"), Err(ObjectError::InvalidRefName(_))), "{}", name);
+        assert!(matches!(refs.get_ref(RefKind::Head, name), Err(ObjectError::InvalidRefName(_))), "{}", name);
+        assert!(matches!(refs.delete_ref(RefKind::Head, name), Err(ObjectError::InvalidRefName(_))), "{}", name);
+        assert!(matches!(refs.reflog(RefKind::Head, name), Err(ObjectError::InvalidRefName(_))), "{}", name);
+        assert!(matches!(refs.set_head(&HeadRefStub::Head(name.to_string()), "checkout"), Err(ObjectError::InvalidRefName(_))), "{}", name);
+        assert!(matches!(refs.set_head(&HeadRefStub::Tag(name.to_string()), "checkout"), Err(ObjectError::InvalidRefName(_))), "{}", name);
+    }
+    // nothing was written, inside the repository or out
+    assert!(!tempdir.path().join("repo").exists());
+    assert!(!tempdir.path().join("outside").exists());
+
+    refs.set_ref(RefKind::Head, "feature/x.y", &hash, "commit").unwrap();
+    assert_eq!(refs.get_ref(RefKind::Head, "feature/x.y").unwrap(), Some(hash));
+}
+
+#[test]
+fn test_reflog_survives_torn_append() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let refs: RefStore = RefStore::new(tempdir.path());
+    let first = Blob::<VcHasher>::new(b"first").hash;
+    let second = Blob::<VcHasher>::new(b"second").hash;
+    refs.set_ref(RefKind::Head, "main", &first, "commit").unwrap();
+
+    // a crash part way through appending the next entry, and a temporary file left by a crashed ref write
+    let log_path = refs.log_path(RefKind::Head, "main");
+    let mut f = OpenOptions::new().append(true).open(&log_path).unwrap();
+    f.write_all(b"{\"old\":\"ab").unwrap();
+    fs::write(refs.ref_path(RefKind::Head, ".tmp-crashed"), "garbage").unwrap();
+    assert_eq!(refs.reflog(RefKind::Head, "main").unwrap().len(), 1);
+    assert_eq!(refs.list_refs(RefKind::Head).unwrap(), vec![("main".to_string(), first)]);
+
+    refs.set_ref(RefKind::Head, "main", &second, "commit").unwrap();
+    let log = refs.reflog(RefKind::Head, "main").unwrap();
+    assert_eq!(log.len(), 2);
+    assert_eq!(log[1].new, hash_to_hex_string(&second));
+}
+
+#[test]
+fn test_update_ref_compare_and_swap() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let refs: RefStore = RefStore::new(tempdir.path());
+    let first = Blob::<VcHasher>::new(b"first").hash;
+    let second = Blob::<VcHasher>::new(b"second").hash;
+    refs.update_ref(RefKind::Head, "main", None, &first, "create").unwrap();
+    let err = refs.update_ref(RefKind::Head, "main", None, &second, "create").unwrap_err();
+    assert!(matches!(err, ObjectError::RefConflict { actual: Some(_), expected: None, .. }));
+    refs.update_ref(RefKind::Head, "main", Some(&first), &second, "advance").unwrap();
+    assert_eq!(refs.get_ref(RefKind::Head, "main").unwrap(), Some(second));
+
+    // lock files aren't taken for refs
+    let _lock = LockFile::try_acquire(refs.ref_path(RefKind::Head, "main")).unwrap();
+    assert_eq!(refs.list_refs(RefKind::Head).unwrap().len(), 1);
+}
+
+#[test]
+fn test_concurrent_ref_updates_are_not_lost() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let refs: RefStore = RefStore::new(tempdir.path());
+    let threads: Vec<_> = (0..4).map(|t| {
+        let refs = refs.clone();
+        std::thread::spawn(move || {
+            for i in 0..10 {
+                let new = Blob::<VcHasher>::new(format!("{} {}", t, i).as_bytes()).hash;
+                // retry the read-modify-write until no other update slips in between
+                loop {
+                    let old = refs.get_ref(RefKind::Head, "main").unwrap();
+                    match refs.update_ref(RefKind::Head, "main", old.as_ref(), &new, "update") {
+                        Ok(()) => break,
+                        Err(ObjectError::RefConflict { .. }) => continue,
+                        Err(e) => panic!("{}", e)
+                    }
+                }
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    // every update is in the log, each starting from where the previous one left the ref
+    let log = refs.reflog(RefKind::Head, "main").unwrap();
+    assert_eq!(log.len(), 40);
+    for pair in log.windows(2) {
+        assert_eq!(pair[1].old.as_ref(), Some(&pair[0].new));
+    }
+}
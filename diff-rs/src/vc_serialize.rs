@@ -1,23 +1,29 @@
 use std::collections::HashMap;
-use std::fmt::Write;
-use std::io::{Read, Write as IoWrite, BufReader};
+use std::io::BufReader;
 use std::path::Path;
-use std::fs::{File, OpenOptions};
-use std::{fmt::Display, path::PathBuf, time::SystemTime, error::Error};
-use digest::{Digest, generic_array::GenericArray};
+use std::fs::File;
+use std::{time::SystemTime, error::Error};
 use serde::de::DeserializeOwned;
-use sha2::Sha256;
 use serde::{Deserialize, Serialize};
 
 use crate::hashing::*;
-use crate::merkle::*;
+use crate::util::*;
 use crate::vc::*;
+use crate::vc_binary::*;
+use crate::vc_object::*;
+use crate::vc_store::*;
 
 pub fn serialize_json<S>(obj: S) -> Result<String, serde_json::Error>
 where S: Serialize {
     serde_json::to_string_pretty(&obj)
 }
 
+// Without the whitespace of `serialize_json`, for objects which are only read back by the program
+pub fn serialize_json_compact<S>(obj: S) -> Result<String, serde_json::Error>
+where S: Serialize {
+    serde_json::to_string(&obj)
+}
+
 pub fn deserialize_json<D>(json: &str) -> Result<D, serde_json::Error>
 where D: DeserializeOwned {
     serde_json::from_str(json)
@@ -25,12 +31,8 @@ where D: DeserializeOwned {
 
 pub fn serialize_json_to_file<S, P>(obj: S, path: P) -> Result<(), Box<dyn Error>>
 where S: Serialize, P: AsRef<Path> {
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(path)?;
     let json = serialize_json(obj)?;
-    file.write_all(json.as_bytes())?;
+    write_atomic(path, json.as_bytes())?;
     Ok(())
 }
 
@@ -64,16 +66,30 @@ pub trait SerializeDeserializeJson : Serialize + DeserializeOwned {
     }
 }
 
-pub trait SaveLoadObjectJson : SerializeDeserializeJson + VcHashId {
+// The hash a stub names its object by. Unlike `VcHashId` the bytes are only parsed when asked for,
+// as a stub doesn't know the digest its hash was made with, and a loaded one may hold anything.
+pub trait StubHashId {
+    fn get_hash_str(&self) -> VcHashString;
+
+    fn get_hash_bytes<D: VcDigest>(&self) -> Result<VcHash<D>, hex::FromHexError> {
+        hex_string_to_hash::<D>(&self.get_hash_str())
+    }
+}
+
+pub trait SaveLoadObjectJson : SerializeDeserializeJson + StubHashId {
     fn save_object_json<P>(&self, parent_path: P) -> Result<(), Box<dyn Error>>
     where P: AsRef<Path> {
-        let path = PathBuf::from(parent_path.as_ref()).join(self.get_hash_str());
+        let path = object_path(parent_path, &self.get_hash_str());
+        if let Some(shard_dir) = path.parent() {
+            std::fs::create_dir_all(shard_dir)?;
+        }
         self.serialize_json_to_file(path)
     }
 
-    fn load_object_json<P>(parent_path: P, hashstr: &str) -> Result<Self, Box<dyn Error>>
-    where P: AsRef<Path> {
-        let path = PathBuf::from(parent_path.as_ref()).join(hashstr);
+    // Loads the stub of the object named `hashstr` in a store of objects named by `D`
+    fn load_object_json<D, P>(parent_path: P, hashstr: &str) -> Result<Self, Box<dyn Error>>
+    where D: VcDigest, P: AsRef<Path> {
+        let path = object_path(parent_path, hashstr);
         Self::deserialize_json_from_file(path)
     }
 }
@@ -84,11 +100,7 @@ pub struct BlobStub {
     pub hashstr: VcHashString
 }
 impl SerializeDeserializeJson for BlobStub {}
-impl VcHashId for BlobStub {
-    fn get_hash_bytes(&self) -> VcHash {
-        hex_string_to_hash::<VcHasher>(&self.hashstr).unwrap()
-    }
-
+impl StubHashId for BlobStub {
     fn get_hash_str(&self) -> VcHashString {
         self.hashstr.clone()
     }
@@ -99,14 +111,13 @@ impl SaveLoadObjectJson for BlobStub {}
 #[derive(Serialize, Deserialize)]
 pub struct TreeStub {
     pub listings: HashMap<Name, (FsObjectType, VcHashString)>,
-    pub hashstr: VcHashString
+    pub hashstr: VcHashString,
+    // stubs written before versioning have no version and load as version 0
+    #[serde(default)]
+    pub format_version: u32
 }
 impl SerializeDeserializeJson for TreeStub {}
-impl VcHashId for TreeStub {
-    fn get_hash_bytes(&self) -> VcHash {
-        hex_string_to_hash::<VcHasher>(&self.hashstr).unwrap()
-    }
-
+impl StubHashId for TreeStub {
     fn get_hash_str(&self) -> VcHashString {
         self.hashstr.clone()
     }
@@ -124,11 +135,7 @@ pub struct CommitStub {
     pub timestamp: SystemTime
 }
 impl SerializeDeserializeJson for CommitStub {}
-impl VcHashId for CommitStub {
-    fn get_hash_bytes(&self) -> VcHash {
-        hex_string_to_hash::<VcHasher>(&self.hashstr).unwrap()
-    }
-
+impl StubHashId for CommitStub {
     fn get_hash_str(&self) -> VcHashString {
         self.hashstr.clone()
     }
@@ -139,39 +146,22 @@ impl SaveLoadObjectJson for CommitStub {}
 #[derive(Serialize, Deserialize)]
 pub enum FsObjectType {
     Blob,
-    Tree
+    Tree,
+    // a blob listed with the executable bit set
+    Executable
 }
 impl SerializeDeserializeJson for FsObjectType {}
 
+// Tagged with the stub type, e.g. `{"type": "BlobStub", "hashstr": ...}`, so that either stub can be told apart when loading
 #[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum FsObjectStub {
     BlobStub(BlobStub),
     TreeStub(TreeStub)
 }
-impl Serialize for FsObjectStub {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where S: serde::Serializer {
-        match self {
-            FsObjectStub::BlobStub(blob_stub) => blob_stub.serialize(serializer),
-            FsObjectStub::TreeStub(tree_stub) => tree_stub.serialize(serializer)
-        }
-    }
-}
-impl<'a> Deserialize<'a> for FsObjectStub {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where D: serde::Deserializer<'a> {
-        Ok(FsObjectStub::TreeStub(TreeStub::deserialize(deserializer)?))
-    }
-}
 impl SerializeDeserializeJson for FsObjectStub {}
-impl VcHashId for FsObjectStub {
-    fn get_hash_bytes(&self) -> VcHash {
-        match self {
-            FsObjectStub::BlobStub(blob_stub) => blob_stub.get_hash_bytes(),
-            FsObjectStub::TreeStub(tree_stub) => tree_stub.get_hash_bytes()
-        }
-    }
-
+impl StubHashId for FsObjectStub {
     fn get_hash_str(&self) -> VcHashString {
         match self {
             FsObjectStub::BlobStub(blob_stub) => blob_stub.get_hash_str(),
@@ -181,41 +171,17 @@ impl VcHashId for FsObjectStub {
 }
 impl SaveLoadObjectJson for FsObjectStub {}
 
+// Tagged with the object kind on top of the fs object stub's own tag,
+// e.g. `{"kind": "FsObjectStub", "type": "TreeStub", ...}` or `{"kind": "CommitStub", ...}`
 #[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind")]
 pub enum VcObjectStub {
     FsObjectStub(FsObjectStub),
     CommitStub(CommitStub)
 }
-impl Serialize for VcObjectStub {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where S: serde::Serializer {
-        match self {
-            VcObjectStub::FsObjectStub(fs_object_stub) => fs_object_stub.serialize(serializer),
-            VcObjectStub::CommitStub(commit_stub) => commit_stub.serialize(serializer)
-        }
-    }
-}
-impl<'a> Deserialize<'a> for VcObjectStub {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where D: serde::Deserializer<'a> {
-        // CommitStub::deserialize(deserializer)
-        //     .map(|commit_stub| VcObjectStub::CommitStub(commit_stub))
-        //     .or_else(|_| {
-        //         let x = FsObjectStub::deserialize(deserializer)?;
-        //         Ok(VcObjectStub::FsObjectStub(x))
-        //     })
-        // try to parse as commit stub, if that fails, try to parse as fs object stub
-        
-    }
-}
-impl VcHashId for VcObjectStub {
-    fn get_hash_bytes(&self) -> VcHash {
-        match self {
-            VcObjectStub::FsObjectStub(fs_object_stub) => fs_object_stub.get_hash_bytes(),
-            VcObjectStub::CommitStub(commit_stub) => commit_stub.get_hash_bytes()
-        }
-    }
-
+impl SerializeDeserializeJson for VcObjectStub {}
+impl StubHashId for VcObjectStub {
     fn get_hash_str(&self) -> VcHashString {
         match self {
             VcObjectStub::FsObjectStub(fs_object_stub) => fs_object_stub.get_hash_str(),
@@ -223,16 +189,38 @@ impl VcHashId for VcObjectStub {
         }
     }
 }
+impl SaveLoadObjectJson for VcObjectStub {
+    // Loads the stub of any stored object, loose or packed, telling its type by the object header.
+    // Stubs saved by `save_object_json` have no header and are read back as they were saved.
+    fn load_object_json<D, P>(parent_path: P, hashstr: &str) -> Result<Self, Box<dyn Error>>
+    where D: VcDigest, P: AsRef<Path> {
+        let hash = hex_string_to_hash::<D>(hashstr)?;
+        let store: FsObjectStore<D> = FsObjectStore::new(parent_path.as_ref());
+        let path = store.location(&hash);
+        let stub = match store.get(&hash) {
+            Ok(Some((ObjectType::Blob, _))) => VcObjectStub::FsObjectStub(FsObjectStub::BlobStub(BlobStub { hashstr: hashstr.to_string() })),
+            Ok(Some((ObjectType::Tree, body))) => VcObjectStub::FsObjectStub(FsObjectStub::TreeStub(decode_stub::<D, _>(&path, &body)?)),
+            Ok(Some((ObjectType::Commit, body))) => VcObjectStub::CommitStub(decode_stub::<D, _>(&path, &body)?),
+            Ok(Some((ObjectType::Tag, _))) => return Err(ObjectError::UnsupportedType(ObjectType::Tag).into()),
+            Ok(None) => return Err(ObjectError::NotFound(hashstr.to_string()).into()),
+            Err(ObjectError::InvalidHeader(_)) => Self::deserialize_json_from_file(&path)?,
+            Err(e) => return Err(e.into())
+        };
+        Ok(stub)
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize)]
 pub enum HeadRefStub {
     Tag(Name),
     Head(Name),
     Commit(VcHashString)
 }
+impl SerializeDeserializeJson for HeadRefStub {}
 
 impl BlobStub {
-    pub fn from_blob(blob: &Blob) -> Self {
+    pub fn from_blob<D: VcDigest>(blob: &Blob<D>) -> Self {
         Self {
             hashstr: hash_to_hex_string(&blob.hash)
         }
@@ -240,9 +228,10 @@ impl BlobStub {
 }
 
 impl TreeStub {
-    pub fn from_tree(tree: &Tree) -> Self {
+    pub fn from_tree<D: VcDigest>(tree: &Tree<D>) -> Self {
         let listings = tree.listings.iter().map(|(name, fs_object)| {
             let (fs_object_type, hashstr) = match fs_object {
+                FsObject::Blob(blob) if blob.executable => (FsObjectType::Executable, hash_to_hex_string(&blob.hash)),
                 FsObject::Blob(blob) => (FsObjectType::Blob, hash_to_hex_string(&blob.hash)),
                 FsObject::Tree(tree) => (FsObjectType::Tree, hash_to_hex_string(&tree.hash))
             };
@@ -251,17 +240,25 @@ impl TreeStub {
 
         Self {
             listings,
-            hashstr: hash_to_hex_string(&tree.hash)
+            hashstr: hash_to_hex_string(&tree.hash),
+            format_version: REPOSITORY_FORMAT_VERSION
+        }
+    }
+}
+
+impl FsObjectType {
+    pub fn get_mode(&self) -> FileMode {
+        match self {
+            FsObjectType::Blob => FileMode::Regular,
+            FsObjectType::Executable => FileMode::Executable,
+            FsObjectType::Tree => FileMode::Directory
         }
     }
 }
 
 impl CommitStub {
-    pub fn from_commit(commit: &Commit) -> Self {
-        let parent_hashstr = match &commit.parent {
-            Some(parent) => Some(hash_to_hex_string(&parent.hash)),
-            None => None
-        };
+    pub fn from_commit<D: VcDigest>(commit: &Commit<D>) -> Self {
+        let parent_hashstr = commit.parent_hash.as_ref().map(|parent_hash| hash_to_hex_string(parent_hash));
         Self {
             tree_hashstr: hash_to_hex_string(&commit.tree.hash),
             hashstr: hash_to_hex_string(&commit.hash),
@@ -275,7 +272,7 @@ impl CommitStub {
 
 #[test]
 fn test_blob_stub_serialize_json() {
-    let blob = Blob::new(b"hello");
+    let blob: Blob = Blob::new(b"hello");
     let blob_stub = BlobStub::from_blob(&blob);
     let json = blob_stub.serialize_json().unwrap();
     let blob_stub2 = BlobStub::deserialize_json(&json).unwrap();
@@ -285,7 +282,7 @@ fn test_blob_stub_serialize_json() {
 
 #[test]
 fn test_tree_stub_serialize_json() {
-    let mut tree = Tree::new(HashMap::new());
+    let mut tree: Tree = Tree::new(HashMap::new());
     tree.listings.insert("hello".to_string(), FsObject::Blob(Blob::new(b"hello")));
     let tree_stub = TreeStub::from_tree(&tree);
     let json = tree_stub.serialize_json().unwrap();
@@ -293,3 +290,103 @@ fn test_tree_stub_serialize_json() {
     print!("{}", json);
     assert_eq!(tree_stub, tree_stub2);
 }
+
+#[test]
+fn test_stub_hash_of_other_digest() {
+    let mut tree: Tree<sha1::Sha1> = Tree::new(HashMap::new());
+    tree.listings.insert("hello".to_string(), FsObject::Blob(Blob::new(b"hello")));
+    let stub = VcObjectStub::FsObjectStub(FsObjectStub::TreeStub(TreeStub::from_tree(&tree)));
+    assert_eq!(stub.get_hash_bytes::<sha1::Sha1>().unwrap(), tree.hash);
+    assert!(stub.get_hash_bytes::<VcHasher>().is_err());
+    assert!(BlobStub { hashstr: "not a hash".to_string() }.get_hash_bytes::<VcHasher>().is_err());
+}
+
+#[test]
+fn test_commit_stub_serialize_json() {
+    let commit: Commit = Commit::new(Tree::new(HashMap::new()), None, "alice".to_string(), "init".to_string());
+    let commit_stub = CommitStub::from_commit(&commit);
+    let json = commit_stub.serialize_json().unwrap();
+    let commit_stub2 = CommitStub::deserialize_json(&json).unwrap();
+    assert_eq!(commit_stub, commit_stub2);
+}
+
+#[test]
+fn test_fs_object_stub_serialize_json() {
+    let mut tree: Tree = Tree::new(HashMap::new());
+    tree.insert_path("hello", FsObject::Blob(Blob::new(b"hello")));
+    let stubs = [
+        FsObjectStub::BlobStub(BlobStub::from_blob(&Blob::<VcHasher>::new(b"hello"))),
+        FsObjectStub::TreeStub(TreeStub::from_tree(&tree))
+    ];
+    for stub in stubs {
+        let json = stub.serialize_json().unwrap();
+        assert_eq!(FsObjectStub::deserialize_json(&json).unwrap(), stub);
+    }
+}
+
+#[test]
+fn test_vc_object_stub_serialize_json() {
+    let tree: Tree = Tree::new(HashMap::new());
+    let commit = Commit::new(tree.clone(), None, "alice".to_string(), "init".to_string());
+    let stubs = [
+        VcObjectStub::FsObjectStub(FsObjectStub::BlobStub(BlobStub::from_blob(&Blob::<VcHasher>::new(b"hello")))),
+        VcObjectStub::FsObjectStub(FsObjectStub::TreeStub(TreeStub::from_tree(&tree))),
+        VcObjectStub::CommitStub(CommitStub::from_commit(&commit))
+    ];
+    for stub in stubs {
+        let json = stub.serialize_json().unwrap();
+        assert_eq!(VcObjectStub::deserialize_json(&json).unwrap(), stub);
+    }
+}
+
+#[test]
+fn test_head_ref_stub_serialize_json() {
+    let stubs = [
+        HeadRefStub::Tag("v1".to_string()),
+        HeadRefStub::Head("main".to_string()),
+        HeadRefStub::Commit(hash_to_hex_string(&Blob::<VcHasher>::new(b"hello").hash))
+    ];
+    for stub in stubs {
+        let json = stub.serialize_json().unwrap();
+        assert_eq!(HeadRefStub::deserialize_json(&json).unwrap(), stub);
+    }
+}
+
+#[test]
+fn test_vc_object_stub_load_by_hash() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let blob: Blob = Blob::new(b"hello");
+    let mut tree: Tree = Tree::new(HashMap::new());
+    tree.insert_path("hello", FsObject::Blob(blob.clone()));
+    let commit = Commit::new(tree.clone(), None, "alice".to_string(), "init".to_string());
+    commit.to_file_with(tempdir.path(), Compression::Zlib).unwrap();
+
+    // objects as the repository stores them; the caller only needs the hash, not the type of the object
+    let stubs = [
+        VcObjectStub::FsObjectStub(FsObjectStub::BlobStub(BlobStub::from_blob(&blob))),
+        VcObjectStub::FsObjectStub(FsObjectStub::TreeStub(TreeStub::from_tree(&tree))),
+        VcObjectStub::CommitStub(CommitStub::from_commit(&commit))
+    ];
+    for stub in &stubs {
+        let loaded = VcObjectStub::load_object_json::<VcHasher, _>(tempdir.path(), &stub.get_hash_str()).unwrap();
+        assert_eq!(&loaded, stub);
+    }
+    let missing = hash_to_hex_string(&Blob::<VcHasher>::new(b"missing").hash);
+    assert!(VcObjectStub::load_object_json::<VcHasher, _>(tempdir.path(), &missing).is_err());
+
+    // and a stub saved on its own
+    let other: Tree = Tree::new(HashMap::from([("x".to_string(), FsObject::Blob(Blob::new(b"x")))]));
+    let stub = VcObjectStub::FsObjectStub(FsObjectStub::TreeStub(TreeStub::from_tree(&other)));
+    stub.save_object_json(tempdir.path()).unwrap();
+    assert_eq!(VcObjectStub::load_object_json::<VcHasher, _>(tempdir.path(), &stub.get_hash_str()).unwrap(), stub);
+
+    // in a store of another digest
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let mut tree: Tree<sha1::Sha1> = Tree::new(HashMap::new());
+    tree.insert_path("hello", FsObject::Blob(Blob::new(b"hello")));
+    let commit = Commit::new(tree, None, "alice".to_string(), "init".to_string());
+    commit.to_file(tempdir.path()).unwrap();
+    let stub = VcObjectStub::CommitStub(CommitStub::from_commit(&commit));
+    assert_eq!(VcObjectStub::load_object_json::<sha1::Sha1, _>(tempdir.path(), &stub.get_hash_str()).unwrap(), stub);
+    assert!(VcObjectStub::load_object_json::<VcHasher, _>(tempdir.path(), &stub.get_hash_str()).is_err());
+}
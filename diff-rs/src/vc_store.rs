@@ -0,0 +1,475 @@
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::hashing::*;
+use crate::vc::*;
+use crate::vc_object::*;
+use crate::vc_pack::*;
+
+// File of an objects directory listing the objects directories of other repositories to borrow objects from,
+// one per line. Relative paths are relative to the objects directory.
+pub const ALTERNATES_FILE_NAME: &str = "info/alternates";
+
+// How much space a stored object takes up and when it was written, if the store knows
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ObjectStat {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    // whether `delete` removes the object, freeing `size` bytes
+    pub deletable: bool
+}
+
+// Where objects are kept, keyed by their hash under the digest `D`. Objects are written as given;
+// checking that an object hashes to the hash it is stored under is up to whoever reads it.
+pub trait ObjectStore<D: VcDigest = VcHasher> {
+    // Stores an object, doing nothing if an object is already stored under its hash
+    fn put(&mut self, hash: &VcHash<D>, object_type: ObjectType, body: &[u8]) -> Result<(), ObjectError>;
+
+    fn get(&self, hash: &VcHash<D>) -> Result<Option<StoredObject>, ObjectError>;
+
+    fn contains(&self, hash: &VcHash<D>) -> Result<bool, ObjectError>;
+
+    // Hashes of all stored objects, in no particular order
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = VcHash<D>> + '_>, ObjectError>;
+
+    // Removes an object, returning whether it was stored
+    fn delete(&mut self, hash: &VcHash<D>) -> Result<bool, ObjectError>;
+
+    // Stores which know nothing better report the size of the body and no modification time
+    fn stat(&self, hash: &VcHash<D>) -> Result<Option<ObjectStat>, ObjectError> {
+        Ok(self.get(hash)?.map(|(_, body)| ObjectStat { size: body.len() as u64, modified: None, deletable: true }))
+    }
+
+    // Where an object is or would be stored, to point at it in errors
+    fn location(&self, hash: &VcHash<D>) -> PathBuf {
+        PathBuf::from(hash_to_hex_string(hash))
+    }
+
+    // Gets an object which has to be stored, and be of type `expected`
+    fn get_of_type(&self, hash: &VcHash<D>, expected: ObjectType) -> Result<Box<[u8]>, ObjectError> {
+        match self.get(hash)? {
+            Some((actual, body)) if actual == expected => Ok(body),
+            Some((actual, _)) => Err(ObjectError::UnexpectedType { path: self.location(hash), actual }),
+            None => Err(ObjectError::NotFound(hash_to_hex_string(hash)))
+        }
+    }
+}
+
+// The packs of an objects directory as last listed, along with when the pack directory was modified then
+type PackCache = (SystemTime, Arc<Vec<Pack>>);
+
+// Objects in an objects directory: loose objects fanned out into shard directories, and packs.
+// Reads check the packs first, writes always go to loose objects.
+#[derive(Debug, Clone)]
+pub struct FsObjectStore<D: VcDigest = VcHasher> {
+    objects_dir: PathBuf,
+    compression: Compression,
+    // shared by clones
+    packs: Arc<Mutex<Option<PackCache>>>,
+    digest: PhantomData<D>
+}
+
+impl<D: VcDigest> FsObjectStore<D> {
+    pub fn new<P>(objects_dir: P) -> Self
+    where P: AsRef<Path>
+    {
+        Self::with_compression(objects_dir, Compression::default())
+    }
+
+    pub fn with_compression<P>(objects_dir: P, compression: Compression) -> Self
+    where P: AsRef<Path>
+    {
+        Self {
+            objects_dir: objects_dir.as_ref().to_path_buf(),
+            compression,
+            packs: Arc::new(Mutex::new(None)),
+            digest: PhantomData
+        }
+    }
+
+    pub fn get_objects_dir(&self) -> &Path {
+        &self.objects_dir
+    }
+
+    // The packs of the objects directory, only listed and opened again once packs have been
+    // added or removed, e.g. by a repack, which changes the modification time of the pack directory
+    fn packs(&self) -> Result<Arc<Vec<Pack>>, ObjectError> {
+        let Ok(modified) = std::fs::metadata(self.objects_dir.join(PACK_DIR_NAME)).and_then(|m| m.modified()) else {
+            return Ok(Arc::new(list_packs(&self.objects_dir)?))
+        };
+        let mut cache = self.packs.lock().unwrap_or_else(|e| e.into_inner());
+        match cache.as_ref() {
+            Some((cached_modified, packs)) if *cached_modified == modified => Ok(packs.clone()),
+            _ => {
+                let packs = Arc::new(list_packs(&self.objects_dir)?);
+                *cache = Some((modified, packs.clone()));
+                Ok(packs)
+            }
+        }
+    }
+}
+
+impl<D: VcDigest> ObjectStore<D> for FsObjectStore<D> {
+    fn put(&mut self, hash: &VcHash<D>, object_type: ObjectType, body: &[u8]) -> Result<(), ObjectError> {
+        if self.contains(hash)? {
+            return Ok(())
+        }
+        write_object_with(&self.objects_dir, &hash_to_hex_string(hash), object_type, body, self.compression)?;
+        Ok(())
+    }
+
+    fn get(&self, hash: &VcHash<D>) -> Result<Option<StoredObject>, ObjectError> {
+        for pack in self.packs()?.iter() {
+            if let Some(obj) = pack.read::<D>(hash)? {
+                return Ok(Some(obj))
+            }
+        }
+        let path = self.location(hash);
+        if !path.is_file() {
+            return Ok(None)
+        }
+        read_object(path).map(Some)
+    }
+
+    fn contains(&self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        if self.location(hash).is_file() {
+            return Ok(true)
+        }
+        for pack in self.packs()?.iter() {
+            if pack.contains::<D>(hash)? {
+                return Ok(true)
+            }
+        }
+        Ok(false)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = VcHash<D>> + '_>, ObjectError> {
+        let mut hashes = HashSet::new();
+        for pack in self.packs()?.iter() {
+            hashes.extend(pack.hashes::<D>()?);
+        }
+        // loose objects which aren't named by a hash can't be looked up
+        hashes.extend(list_objects(&self.objects_dir)?.iter().filter_map(|h| hex_string_to_hash::<D>(h).ok()));
+        Ok(Box::new(hashes.into_iter()))
+    }
+
+    // Loose objects report their file; packed objects their entry in the pack, as stored,
+    // and the time the pack was written. Only loose objects can be deleted.
+    fn stat(&self, hash: &VcHash<D>) -> Result<Option<ObjectStat>, ObjectError> {
+        let path = self.location(hash);
+        if path.is_file() {
+            let metadata = std::fs::metadata(path)?;
+            return Ok(Some(ObjectStat { size: metadata.len(), modified: metadata.modified().ok(), deletable: true }))
+        }
+        for pack in self.packs()?.iter() {
+            if let Some(size) = pack.entry_size::<D>(hash)? {
+                let modified = std::fs::metadata(pack.get_pack_path())?.modified().ok();
+                return Ok(Some(ObjectStat { size, modified, deletable: false }))
+            }
+        }
+        Ok(None)
+    }
+
+    // Only loose objects are deleted; packed objects stay until the pack is rewritten
+    fn delete(&mut self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        let path = self.location(hash);
+        if !path.is_file() {
+            return Ok(false)
+        }
+        std::fs::remove_file(path)?;
+        Ok(true)
+    }
+
+    fn location(&self, hash: &VcHash<D>) -> PathBuf {
+        object_path(&self.objects_dir, &hash_to_hex_string(hash))
+    }
+}
+
+// Objects held in memory, for tests and for scratch work which shouldn't touch the disk
+#[derive(Debug, Clone)]
+pub struct MemoryObjectStore<D: VcDigest = VcHasher> {
+    objects: HashMap<VcHash<D>, StoredObject>
+}
+
+impl<D: VcDigest> Default for MemoryObjectStore<D> {
+    fn default() -> Self {
+        Self { objects: HashMap::new() }
+    }
+}
+
+impl<D: VcDigest> MemoryObjectStore<D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+}
+
+impl<D: VcDigest> ObjectStore<D> for MemoryObjectStore<D> {
+    fn put(&mut self, hash: &VcHash<D>, object_type: ObjectType, body: &[u8]) -> Result<(), ObjectError> {
+        self.objects.entry(hash.clone()).or_insert_with(|| (object_type, body.into()));
+        Ok(())
+    }
+
+    fn get(&self, hash: &VcHash<D>) -> Result<Option<StoredObject>, ObjectError> {
+        Ok(self.objects.get(hash).cloned())
+    }
+
+    fn contains(&self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        Ok(self.objects.contains_key(hash))
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = VcHash<D>> + '_>, ObjectError> {
+        Ok(Box::new(self.objects.keys().cloned()))
+    }
+
+    fn delete(&mut self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        Ok(self.objects.remove(hash).is_some())
+    }
+}
+
+// Wraps a store so that it can only be read from
+#[derive(Debug, Clone)]
+pub struct ReadOnlyObjectStore<S> {
+    inner: S
+}
+
+impl<S> ReadOnlyObjectStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<D: VcDigest, S: ObjectStore<D>> ObjectStore<D> for ReadOnlyObjectStore<S> {
+    fn put(&mut self, _hash: &VcHash<D>, _object_type: ObjectType, _body: &[u8]) -> Result<(), ObjectError> {
+        Err(ObjectError::ReadOnly)
+    }
+
+    fn get(&self, hash: &VcHash<D>) -> Result<Option<StoredObject>, ObjectError> {
+        self.inner.get(hash)
+    }
+
+    fn contains(&self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        self.inner.contains(hash)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = VcHash<D>> + '_>, ObjectError> {
+        self.inner.iter()
+    }
+
+    fn delete(&mut self, _hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        Err(ObjectError::ReadOnly)
+    }
+
+    fn stat(&self, hash: &VcHash<D>) -> Result<Option<ObjectStat>, ObjectError> {
+        Ok(self.inner.stat(hash)?.map(|stat| ObjectStat { deletable: false, ..stat }))
+    }
+
+    fn location(&self, hash: &VcHash<D>) -> PathBuf {
+        self.inner.location(hash)
+    }
+}
+
+// A store layered over other stores: objects are read from the primary store and then from each
+// alternate in turn, while writes and deletes only ever touch the primary store
+pub struct AlternatesObjectStore<D: VcDigest = VcHasher> {
+    primary: Box<dyn ObjectStore<D>>,
+    alternates: Vec<Box<dyn ObjectStore<D>>>
+}
+
+impl<D: VcDigest> AlternatesObjectStore<D> {
+    pub fn new(primary: Box<dyn ObjectStore<D>>, alternates: Vec<Box<dyn ObjectStore<D>>>) -> Self {
+        Self { primary, alternates }
+    }
+
+    // The store of an objects directory along with the alternates listed in its `ALTERNATES_FILE_NAME`
+    pub fn open<P>(objects_dir: P) -> Result<Self, ObjectError>
+    where P: AsRef<Path>
+    {
+        let objects_dir = objects_dir.as_ref();
+        let alternates_path = objects_dir.join(ALTERNATES_FILE_NAME);
+        let mut alternates: Vec<Box<dyn ObjectStore<D>>> = Vec::new();
+        if alternates_path.is_file() {
+            for line in std::fs::read_to_string(alternates_path)?.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue
+                }
+                alternates.push(Box::new(ReadOnlyObjectStore::new(FsObjectStore::new(objects_dir.join(line)))));
+            }
+        }
+        Ok(Self::new(Box::new(FsObjectStore::new(objects_dir)), alternates))
+    }
+
+    pub fn get_alternates(&self) -> &[Box<dyn ObjectStore<D>>] {
+        &self.alternates
+    }
+
+    fn stores(&self) -> impl Iterator<Item = &Box<dyn ObjectStore<D>>> {
+        std::iter::once(&self.primary).chain(self.alternates.iter())
+    }
+}
+
+impl<D: VcDigest> ObjectStore<D> for AlternatesObjectStore<D> {
+    fn put(&mut self, hash: &VcHash<D>, object_type: ObjectType, body: &[u8]) -> Result<(), ObjectError> {
+        // an object borrowed from an alternate doesn't need a copy of its own
+        if self.contains(hash)? {
+            return Ok(())
+        }
+        self.primary.put(hash, object_type, body)
+    }
+
+    fn get(&self, hash: &VcHash<D>) -> Result<Option<StoredObject>, ObjectError> {
+        for store in self.stores() {
+            if let Some(obj) = store.get(hash)? {
+                return Ok(Some(obj))
+            }
+        }
+        Ok(None)
+    }
+
+    fn contains(&self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        for store in self.stores() {
+            if store.contains(hash)? {
+                return Ok(true)
+            }
+        }
+        Ok(false)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = VcHash<D>> + '_>, ObjectError> {
+        let mut hashes = HashSet::new();
+        for store in self.stores() {
+            hashes.extend(store.iter()?);
+        }
+        Ok(Box::new(hashes.into_iter()))
+    }
+
+    fn delete(&mut self, hash: &VcHash<D>) -> Result<bool, ObjectError> {
+        self.primary.delete(hash)
+    }
+
+    // objects found in an alternate can't be deleted through this store
+    fn stat(&self, hash: &VcHash<D>) -> Result<Option<ObjectStat>, ObjectError> {
+        if let Some(stat) = self.primary.stat(hash)? {
+            return Ok(Some(stat))
+        }
+        for store in &self.alternates {
+            if let Some(stat) = store.stat(hash)? {
+                return Ok(Some(ObjectStat { deletable: false, ..stat }))
+            }
+        }
+        Ok(None)
+    }
+
+    fn location(&self, hash: &VcHash<D>) -> PathBuf {
+        self.stores().find(|store| store.contains(hash).unwrap_or(false))
+            .unwrap_or(&self.primary)
+            .location(hash)
+    }
+}
+
+#[test]
+fn test_memory_store() {
+    let mut store: MemoryObjectStore = MemoryObjectStore::new();
+    let blob: Blob = Blob::new(b"hello");
+    store.put(&blob.hash, ObjectType::Blob, blob.get_data()).unwrap();
+    assert!(store.contains(&blob.hash).unwrap());
+    assert_eq!(store.get_of_type(&blob.hash, ObjectType::Blob).unwrap(), blob.data);
+    assert!(matches!(store.get_of_type(&blob.hash, ObjectType::Tree), Err(ObjectError::UnexpectedType { .. })));
+    assert_eq!(store.iter().unwrap().collect::<Vec<_>>(), vec![blob.hash]);
+    assert!(store.delete(&blob.hash).unwrap());
+    assert!(!store.delete(&blob.hash).unwrap());
+    assert!(matches!(store.get_of_type(&blob.hash, ObjectType::Blob), Err(ObjectError::NotFound(_))));
+}
+
+#[test]
+fn test_fs_store_reads_packed_and_loose() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let mut store: FsObjectStore = FsObjectStore::new(tempdir.path());
+    let packed: Blob = Blob::new(b"packed");
+    store.put(&packed.hash, ObjectType::Blob, packed.get_data()).unwrap();
+    repack::<VcHasher, _>(tempdir.path(), &RepackOptions::default()).unwrap();
+    let loose: Blob = Blob::new(b"loose");
+    store.put(&loose.hash, ObjectType::Blob, loose.get_data()).unwrap();
+
+    assert!(store.contains(&packed.hash).unwrap());
+    assert_eq!(store.get_of_type(&packed.hash, ObjectType::Blob).unwrap(), packed.data);
+    assert_eq!(store.get_of_type(&loose.hash, ObjectType::Blob).unwrap(), loose.data);
+    assert_eq!(store.iter().unwrap().collect::<HashSet<_>>(), HashSet::from([packed.hash, loose.hash]));
+    assert!(store.get(&Blob::<VcHasher>::new(b"missing").hash).unwrap().is_none());
+
+    assert!(store.delete(&loose.hash).unwrap());
+    assert!(!store.contains(&loose.hash).unwrap());
+
+    // a repack replaces the pack the store has loaded
+    let repacked: Blob = Blob::new(b"repacked");
+    store.put(&repacked.hash, ObjectType::Blob, repacked.get_data()).unwrap();
+    repack::<VcHasher, _>(tempdir.path(), &RepackOptions::default()).unwrap();
+    assert!(!store.location(&repacked.hash).exists());
+    assert_eq!(store.get_of_type(&repacked.hash, ObjectType::Blob).unwrap(), repacked.data);
+    assert_eq!(store.get_of_type(&packed.hash, ObjectType::Blob).unwrap(), packed.data);
+}
+
+#[test]
+fn test_alternates_store() {
+    let tempdir = tempfile::tempdir().expect("Failed to create temp dir");
+    let shared_dir = tempdir.path().join("shared");
+    let own_dir = tempdir.path().join("own");
+    let shared: Blob = Blob::new(b"shared");
+    FsObjectStore::<VcHasher>::new(&shared_dir).put(&shared.hash, ObjectType::Blob, shared.get_data()).unwrap();
+    std::fs::create_dir_all(own_dir.join("info")).unwrap();
+    std::fs::write(own_dir.join(ALTERNATES_FILE_NAME), "../shared\n").unwrap();
+
+    let mut store: AlternatesObjectStore = AlternatesObjectStore::open(&own_dir).unwrap();
+    assert_eq!(store.get_alternates().len(), 1);
+    assert_eq!(store.get_of_type(&shared.hash, ObjectType::Blob).unwrap(), shared.data);
+
+    // borrowed objects are neither copied nor deleted
+    store.put(&shared.hash, ObjectType::Blob, shared.get_data()).unwrap();
+    assert!(!FsObjectStore::<VcHasher>::new(&own_dir).contains(&shared.hash).unwrap());
+    assert!(!store.delete(&shared.hash).unwrap());
+    assert!(store.contains(&shared.hash).unwrap());
+
+    let own: Blob = Blob::new(b"own");
+    store.put(&own.hash, ObjectType::Blob, own.get_data()).unwrap();
+    assert!(FsObjectStore::<VcHasher>::new(&own_dir).contains(&own.hash).unwrap());
+    assert!(!FsObjectStore::<VcHasher>::new(&shared_dir).contains(&own.hash).unwrap());
+    assert_eq!(store.iter().unwrap().count(), 2);
+
+    let mut read_only = ReadOnlyObjectStore::new(FsObjectStore::<VcHasher>::new(&shared_dir));
+    assert!(matches!(read_only.put(&own.hash, ObjectType::Blob, own.get_data()), Err(ObjectError::ReadOnly)));
+    assert!(matches!(read_only.delete(&shared.hash), Err(ObjectError::ReadOnly)));
+}
+
+#[test]
+fn test_objects_round_trip_through_store() {
+    let mut store: MemoryObjectStore = MemoryObjectStore::new();
+    let commit = sample_commit();
+    let tree = commit.tree.clone();
+    commit.to_store(&mut store).unwrap();
+    // the commit, three trees and three blobs
+    assert_eq!(store.len(), 7);
+
+    let loaded = Commit::from_store(&store, &commit.hash).unwrap();
+    assert_eq!(loaded.tree.hash, tree.hash);
+    let Some(FsObject::Blob(blob)) = loaded.tree.get_path("src/main.rs") else { panic!("blob not loaded") };
+    assert_eq!(blob.get_data(), b"fn main() {}");
+    assert!(matches!(VcObject::from_store(&store, &tree.hash).unwrap(), VcObject::FsObject(FsObject::Tree(_))));
+
+    let Some(FsObject::Blob(readme)) = tree.get_path("README") else { panic!() };
+    store.delete(&readme.hash).unwrap();
+    assert!(matches!(Tree::from_store(&store, &tree.hash), Err(ObjectError::NotFound(_))));
+}